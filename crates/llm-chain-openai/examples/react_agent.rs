@@ -0,0 +1,31 @@
+use llm_chain::{
+    agents::{react::Agent, self_ask_with_search::EarlyStoppingConfig},
+    executor,
+    tools::{tools::BashTool, ToolCollection},
+};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let executor = executor!().unwrap();
+    let mut tools = ToolCollection::new();
+    tools.add_tool(BashTool::new());
+    let agent = Agent::new(
+        executor,
+        tools,
+        EarlyStoppingConfig {
+            max_iterations: Some(10),
+            max_time_elapsed_seconds: Some(60.0),
+        },
+    );
+    let (res, intermediate_steps) = agent
+        .run("Which files are in the current directory?")
+        .await
+        .unwrap();
+    for step in &intermediate_steps {
+        println!("{}\nObservation: {:?}", step.action.log, step.observation);
+    }
+    println!(
+        "Agent final answer: {}",
+        res.return_values.get("output").unwrap()
+    );
+}
@@ -1,5 +1,6 @@
 use super::error::OpenAIInnerError;
 use super::prompt::completion_to_output;
+use super::prompt::completion_to_outputs;
 use super::prompt::stream_to_output;
 use async_openai::config::OpenAIConfig;
 use async_openai::types::ChatCompletionRequestMessage;
@@ -28,6 +29,9 @@ use llm_chain::tokens::TokenCount;
 
 use std::sync::Arc;
 use tokio::time::{sleep, Duration};
+/// The largest number of choices the API returns for a single request.
+const MAX_CHOICES_PER_REQUEST: usize = 128;
+
 /// The `Executor` struct for the ChatGPT model. This executor uses the `async_openai` crate to communicate with the OpenAI API.
 #[derive(Clone)]
 pub struct Executor {
@@ -136,6 +140,57 @@ impl traits::Executor for Executor {
         }
     }
 
+    /// Asks for all `n` answers in a single request, using the `n` parameter of the API. Requests are split
+    /// when asking for more than the API allows in one request.
+    ///
+    /// Streaming doesn't support several choices, so streamed prompts are executed `n` times instead.
+    async fn execute_n(
+        &self,
+        options: &Options,
+        prompt: &Prompt,
+        n: usize,
+    ) -> Result<Vec<Output>, ExecutorError> {
+        let opts = self.cascade(Some(options));
+        if opts.is_streaming() {
+            return futures::future::try_join_all((0..n).map(|_| self.execute(options, prompt)))
+                .await;
+        }
+        let model = self.get_model_from_invocation_options(&opts);
+        let mut input = create_chat_completion_request(model, prompt, false)
+            .map_err(|e| ExecutorError::InnerError(e.into()))?;
+        if let Some(Opt::Temperature(temperature)) =
+            opts.get(llm_chain::options::OptDiscriminants::Temperature)
+        {
+            input.temperature = Some(*temperature);
+        }
+        let mut outputs = Vec::with_capacity(n);
+        while outputs.len() < n {
+            let count = (n - outputs.len()).min(MAX_CHOICES_PER_REQUEST);
+            input.n = Some(count as u8);
+            sleep(Duration::from_millis(1000)).await;
+            let response = match self.client.chat().create(input.clone()).await {
+                Ok(response) => response,
+                Err(err) => {
+                    log::error!("llm-chain execute_n create error = {},retry ", err);
+                    sleep(Duration::from_millis(1000)).await;
+                    self.client
+                        .chat()
+                        .create(input.clone())
+                        .await
+                        .map_err(|e| ExecutorError::InnerError(e.into()))?
+                }
+            };
+            let choices = completion_to_outputs(response);
+            if choices.is_empty() {
+                return Err(ExecutorError::InnerError(
+                    "the response has no choices".into(),
+                ));
+            }
+            outputs.extend(choices);
+        }
+        Ok(outputs)
+    }
+
     fn tokens_used(
         &self,
         opts: &Options,
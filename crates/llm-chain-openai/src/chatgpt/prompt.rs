@@ -2,8 +2,8 @@ use async_openai::types::{
     ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestFunctionMessageArgs,
     ChatCompletionRequestMessage, ChatCompletionRequestSystemMessageArgs,
     ChatCompletionRequestToolMessageArgs, ChatCompletionRequestUserMessageArgs,
-    ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionRequestArgs,
-    CreateChatCompletionResponse, Role,
+    ChatCompletionResponseMessage, ChatCompletionResponseStream, CreateChatCompletionRequest,
+    CreateChatCompletionRequestArgs, CreateChatCompletionResponse, Role,
 };
 use futures::StreamExt;
 use llm_chain::prompt::{self, Prompt};
@@ -89,6 +89,20 @@ pub fn create_chat_completion_request(
 
 pub fn completion_to_output(resp: CreateChatCompletionResponse) -> Output {
     let msg = resp.choices.first().unwrap().message.clone();
+    message_to_output(msg)
+}
+
+/// Converts every choice of the response to its own output, in the order of their indexes.
+pub fn completion_to_outputs(resp: CreateChatCompletionResponse) -> Vec<Output> {
+    let mut choices = resp.choices;
+    choices.sort_by_key(|choice| choice.index);
+    choices
+        .into_iter()
+        .map(|choice| message_to_output(choice.message))
+        .collect()
+}
+
+fn message_to_output(msg: ChatCompletionResponseMessage) -> Output {
     let mut col = ChatMessageCollection::new();
     col.add_message(ChatMessage::new(
         convert_openai_role(&msg.role),
@@ -0,0 +1,114 @@
+//! The building blocks shared by all agents.
+//!
+//! An agent is a model that decides, step by step, which tool to use until it is able to answer. The
+//! `Agent` trait captures the decision making, while the `AgentExecutor` runs the decisions.
+use crate::tools::{Tool, ToolCollection};
+use crate::Parameters;
+use async_trait::async_trait;
+use serde::Serialize;
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct AgentAction {
+    pub tool: String,
+    pub tool_input: serde_yaml::Value,
+    pub log: String,
+}
+#[derive(Debug, PartialEq)]
+pub struct AgentFinish {
+    pub return_values: Parameters,
+    pub log: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AgentIntermediateStep {
+    pub action: AgentAction,
+    pub observation: serde_yaml::Value,
+}
+
+pub enum AgentIntermediateStepOutput {
+    Step(AgentIntermediateStep),
+    Finish(AgentFinish),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AgentDecision {
+    Action(AgentAction),
+    Finish(AgentFinish),
+}
+pub trait AgentOutputParser {
+    type Error;
+    fn parse(&self, text: String) -> Result<AgentDecision, Self::Error>;
+}
+
+#[derive(Default)]
+pub struct EarlyStoppingConfig {
+    /// Stops the agent once more than `max_iterations` iterations have elapsed. The limit is checked before every
+    /// iteration, so up to `max_iterations + 1` iterations run.
+    pub max_iterations: Option<u32>,
+    pub max_time_elapsed_seconds: Option<f64>,
+}
+
+impl EarlyStoppingConfig {
+    /// Returns true as long as neither the iteration nor the time limit has been exceeded.
+    pub(crate) fn should_continue(
+        &self,
+        iterations_elapsed: u32,
+        time_elapsed_seconds: f64,
+    ) -> bool {
+        match (self.max_iterations, self.max_time_elapsed_seconds) {
+            (None, None) => true,
+            (None, Some(max_time_elapsed_seconds)) => {
+                max_time_elapsed_seconds >= time_elapsed_seconds
+            }
+            (Some(max_iterations), None) => max_iterations >= iterations_elapsed,
+            (Some(max_iterations), Some(max_time_elapsed_seconds)) => {
+                max_iterations >= iterations_elapsed
+                    && max_time_elapsed_seconds >= time_elapsed_seconds
+            }
+        }
+    }
+}
+
+/// The `Agent` trait is implemented by every style of agent, e.x. self-ask or ReAct.
+///
+/// An agent only decides on what to do next, running the tools and looping until the agent is done is
+/// left to the `AgentExecutor`.
+#[async_trait]
+pub trait Agent: Sync {
+    type OutputParser: AgentOutputParser + Sync;
+    type Error: std::fmt::Debug
+        + std::error::Error
+        + From<<Self::OutputParser as AgentOutputParser>::Error>;
+
+    /// Returns the parser used to turn the output of the model into an `AgentDecision`.
+    fn output_parser(&self) -> &Self::OutputParser;
+
+    /// Fills in the prompt with the input and the steps taken so far, then calls the model to complete it.
+    async fn generate(
+        &self,
+        intermediate_steps: &[AgentIntermediateStep],
+        input: &str,
+    ) -> Result<String, Self::Error>;
+
+    /// Ask a model for a decision on what to do next, e.x. which tool to use
+    ///
+    /// Agents that don't need to parse free-form text, such as function calling agents, can override this.
+    async fn plan(
+        &self,
+        intermediate_steps: &[AgentIntermediateStep],
+        input: &str,
+    ) -> Result<AgentDecision, Self::Error> {
+        let output = self.generate(intermediate_steps, input).await?;
+        Ok(self.output_parser().parse(output)?)
+    }
+}
+
+/// An agent choosing among the tools of a `ToolCollection` it owns, e.x. a ReAct agent.
+///
+/// The actions of such an agent are run with the tools it describes to the model, so the two can't drift apart.
+pub trait ToolAgent: Agent {
+    type Tool: Tool + Send + Sync;
+
+    /// Returns the tools the agent has access to.
+    fn tools(&self) -> &ToolCollection<Self::Tool>;
+}
@@ -0,0 +1,342 @@
+//! The `AgentExecutor` runs any `Agent` until it reaches a final answer.
+//!
+//! It asks the agent for a decision, dispatches actions to the tools of a `ToolCollection`, records the
+//! observations and stops early once the `EarlyStoppingConfig` limits are reached.
+use super::agent::{
+    Agent, AgentDecision, AgentFinish, AgentIntermediateStep, AgentIntermediateStepOutput,
+    EarlyStoppingConfig,
+};
+use crate::callbacks::{Callbacks, Event};
+use crate::tools::{Tool, ToolCollection, ToolError, ToolUseError};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum AgentExecutorError<A, T>
+where
+    A: std::fmt::Debug + std::error::Error,
+    T: std::fmt::Debug + std::error::Error + ToolError,
+{
+    #[error(transparent)]
+    AgentError(A),
+    #[error(transparent)]
+    ToolUseError(#[from] ToolUseError<T>),
+    #[error("Max number of iterations or timeout exceeded. Elapsed: {time_elapsed_seconds}s, {iterations_elapsed} iterations")]
+    RuntimeExceeded {
+        time_elapsed_seconds: f64,
+        iterations_elapsed: u32,
+    },
+}
+
+/// Runs an agent, executing the actions it decides on with the tools it has access to.
+pub struct AgentExecutor<'a, A, T>
+where
+    A: Agent,
+    T: Tool + Send + Sync,
+{
+    agent: &'a A,
+    tools: &'a ToolCollection<T>,
+    early_stopping_config: &'a EarlyStoppingConfig,
+    callbacks: Callbacks,
+}
+
+impl<'a, A, T> AgentExecutor<'a, A, T>
+where
+    A: Agent,
+    T: Tool + Send + Sync,
+{
+    pub fn new(
+        agent: &'a A,
+        tools: &'a ToolCollection<T>,
+        early_stopping_config: &'a EarlyStoppingConfig,
+    ) -> Self {
+        Self {
+            agent,
+            tools,
+            early_stopping_config,
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Attaches callbacks to the executor, which are notified of every step and tool invocation.
+    ///
+    /// The steps are reported as `agent step 1`, `agent step 2` and so on.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Self {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Ask the agent for a decision on what to do next and perform the action.
+    ///
+    /// Choosing a tool that doesn't exist is not an error, the agent is told about its mistake in the observation instead.
+    async fn take_next_step(
+        &self,
+        intermediate_steps: &[AgentIntermediateStep],
+        input: &str,
+    ) -> Result<AgentIntermediateStepOutput, AgentExecutorError<A::Error, <T as Tool>::Error>> {
+        let decision = self
+            .agent
+            .plan(intermediate_steps, input)
+            .await
+            .map_err(AgentExecutorError::AgentError)?;
+        match decision {
+            AgentDecision::Action(action) => {
+                self.callbacks.emit(Event::ToolInvoked {
+                    tool: action.tool.clone(),
+                    input: action.tool_input.clone(),
+                });
+                let observation = match self.tools.invoke(&action.tool, &action.tool_input).await {
+                    Ok(observation) => observation,
+                    Err(ToolUseError::ToolNotFound) => format!(
+                        "{} is not a valid tool, try one of [{}].",
+                        action.tool,
+                        self.tools.tool_names().join(", ")
+                    )
+                    .into(),
+                    Err(e) => return Err(e.into()),
+                };
+                self.callbacks.emit(Event::ToolReturned {
+                    tool: action.tool.clone(),
+                    output: observation.clone(),
+                });
+                Ok(AgentIntermediateStepOutput::Step(AgentIntermediateStep {
+                    action,
+                    observation,
+                }))
+            }
+            AgentDecision::Finish(finish) => Ok(AgentIntermediateStepOutput::Finish(finish)),
+        }
+    }
+
+    /// Runs the agent until it returns a final answer or the early stopping limits are exceeded.
+    ///
+    /// Returns the final answer together with every step taken to reach it.
+    pub async fn run(
+        &self,
+        input: &str,
+    ) -> Result<
+        (AgentFinish, Vec<AgentIntermediateStep>),
+        AgentExecutorError<A::Error, <T as Tool>::Error>,
+    > {
+        let output = self.try_run(input).await;
+        if let Err(err) = &output {
+            self.callbacks.emit(Event::Error {
+                message: err.to_string(),
+            });
+        }
+        output
+    }
+
+    async fn try_run(
+        &self,
+        input: &str,
+    ) -> Result<
+        (AgentFinish, Vec<AgentIntermediateStep>),
+        AgentExecutorError<A::Error, <T as Tool>::Error>,
+    > {
+        let mut intermediate_steps = vec![];
+
+        let mut iterations = 0;
+        let start = Instant::now();
+        let mut full_duration = Duration::from_nanos(0);
+        while self
+            .early_stopping_config
+            .should_continue(iterations, full_duration.as_secs_f64())
+        {
+            let name = format!("agent step {}", iterations + 1);
+            self.callbacks
+                .emit(Event::StepStarted { name: name.clone() });
+            let decision = self.take_next_step(&intermediate_steps, input).await?;
+            full_duration = start.elapsed();
+            iterations += 1;
+            match decision {
+                AgentIntermediateStepOutput::Step(step) => {
+                    self.callbacks.emit(Event::StepFinished {
+                        name,
+                        output: step.action.log.clone(),
+                    });
+                    intermediate_steps.push(step)
+                }
+                AgentIntermediateStepOutput::Finish(finish) => {
+                    self.callbacks.emit(Event::StepFinished {
+                        name,
+                        output: finish.log.clone(),
+                    });
+                    return Ok((finish, intermediate_steps));
+                }
+            }
+        }
+        Err(AgentExecutorError::RuntimeExceeded {
+            time_elapsed_seconds: full_duration.as_secs_f64(),
+            iterations_elapsed: iterations,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use thiserror::Error;
+
+    use super::{AgentExecutor, AgentExecutorError};
+    use crate::{
+        agents::{
+            react::{ParserError, ReActAgentOutputParser},
+            Agent, AgentIntermediateStep, EarlyStoppingConfig,
+        },
+        callbacks::{Callbacks, Event},
+        tools::{Format, Tool, ToolCollection, ToolDescription, ToolError},
+    };
+
+    /// An agent replaying a fixed list of model outputs, one per step.
+    struct ScriptedAgent {
+        outputs: Vec<&'static str>,
+        output_parser: ReActAgentOutputParser,
+    }
+
+    #[derive(Debug, Error)]
+    #[error(transparent)]
+    struct ScriptedAgentError(#[from] ParserError);
+
+    #[async_trait]
+    impl Agent for ScriptedAgent {
+        type OutputParser = ReActAgentOutputParser;
+        type Error = ScriptedAgentError;
+
+        fn output_parser(&self) -> &Self::OutputParser {
+            &self.output_parser
+        }
+
+        async fn generate(
+            &self,
+            intermediate_steps: &[AgentIntermediateStep],
+            _: &str,
+        ) -> Result<String, Self::Error> {
+            Ok(self.outputs[intermediate_steps.len()].to_string())
+        }
+    }
+
+    #[derive(Debug, Error)]
+    #[error(transparent)]
+    struct EchoError(#[from] serde_yaml::Error);
+
+    impl ToolError for EchoError {}
+
+    struct Echo;
+
+    #[async_trait]
+    impl Tool for Echo {
+        type Input = String;
+        type Output = String;
+        type Error = EchoError;
+
+        async fn invoke_typed(&self, input: &Self::Input) -> Result<Self::Output, Self::Error> {
+            Ok(input.clone())
+        }
+
+        fn description(&self) -> ToolDescription {
+            ToolDescription::new(
+                "Echo",
+                "Echoes its input",
+                "",
+                Format::new(vec![]),
+                Format::new(vec![]),
+            )
+        }
+    }
+
+    fn agent(outputs: Vec<&'static str>) -> ScriptedAgent {
+        ScriptedAgent {
+            outputs,
+            output_parser: ReActAgentOutputParser::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_runs_until_final_answer() {
+        let agent = agent(vec![
+            "Action: Search\nAction Input: hello",
+            "Action: Echo\nAction Input: hello",
+            "Final Answer: hello",
+        ]);
+        let mut tools = ToolCollection::new();
+        tools.add_tool(Echo);
+        let config = EarlyStoppingConfig::default();
+
+        let (finish, steps) = AgentExecutor::new(&agent, &tools, &config)
+            .run("Say hello")
+            .await
+            .unwrap();
+
+        assert_eq!(finish.return_values.get("output").unwrap(), "hello");
+        assert_eq!(steps.len(), 2);
+        assert_eq!(
+            steps[0].observation,
+            "Search is not a valid tool, try one of [Echo]."
+        );
+        assert_eq!(steps[1].observation, "hello");
+    }
+
+    #[tokio::test]
+    async fn test_stops_after_max_iterations() {
+        let agent = agent(vec!["Action: Echo\nAction Input: hello"; 5]);
+        let mut tools = ToolCollection::new();
+        tools.add_tool(Echo);
+        let config = EarlyStoppingConfig {
+            max_iterations: Some(1),
+            max_time_elapsed_seconds: None,
+        };
+
+        let result = AgentExecutor::new(&agent, &tools, &config)
+            .run("Say hello")
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(AgentExecutorError::RuntimeExceeded {
+                iterations_elapsed: 2,
+                ..
+            })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_reports_events_to_callbacks() {
+        let agent = agent(vec![
+            "Action: Echo\nAction Input: hello",
+            "Final Answer: hello",
+        ]);
+        let mut tools = ToolCollection::new();
+        tools.add_tool(Echo);
+        let config = EarlyStoppingConfig::default();
+        let (callbacks, mut events) = Callbacks::channel();
+
+        AgentExecutor::new(&agent, &tools, &config)
+            .with_callbacks(callbacks)
+            .run("Say hello")
+            .await
+            .unwrap();
+
+        let mut names = vec![];
+        while let Ok(event) = events.try_recv() {
+            names.push(match event {
+                Event::StepStarted { name } => format!("started {}", name),
+                Event::ToolInvoked { tool, .. } => format!("invoked {}", tool),
+                Event::ToolReturned { tool, .. } => format!("returned {}", tool),
+                Event::StepFinished { name, .. } => format!("finished {}", name),
+                event => panic!("unexpected event {:?}", event),
+            });
+        }
+        assert_eq!(
+            names,
+            vec![
+                "started agent step 1",
+                "invoked Echo",
+                "returned Echo",
+                "finished agent step 1",
+                "started agent step 2",
+                "finished agent step 2",
+            ]
+        );
+    }
+}
@@ -1 +1,11 @@
+mod agent;
+mod executor;
+pub mod plan_and_execute;
+pub mod react;
 pub mod self_ask_with_search;
+
+pub use agent::{
+    Agent, AgentAction, AgentDecision, AgentFinish, AgentIntermediateStep,
+    AgentIntermediateStepOutput, AgentOutputParser, EarlyStoppingConfig, ToolAgent,
+};
+pub use executor::{AgentExecutor, AgentExecutorError};
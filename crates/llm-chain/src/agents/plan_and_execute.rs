@@ -0,0 +1,467 @@
+//! A plan-and-execute agent for long, multi-step tasks.
+//!
+//! Instead of deciding on one action at a time, the agent first asks the model for a numbered plan.
+//! Each step of the plan is then carried out by a sub-agent, any `ToolAgent` run by an `AgentExecutor` with
+//! its own tools.
+//! After every step a re-planner may revise the remaining steps, and once the plan is done the final
+//! answer is synthesized from the results of all steps.
+//!
+//! Every run returns a `PlanAndExecuteTrace` with all plan revisions and step outcomes, which can be
+//! serialized for logging and inspection.
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::agent::{AgentFinish, AgentIntermediateStep, EarlyStoppingConfig, ToolAgent};
+use super::executor::{AgentExecutor, AgentExecutorError};
+use crate::{
+    callbacks::{Callbacks, Event},
+    frame::{FormatAndExecuteError, Frame},
+    parameters,
+    parsing::{find_yaml, ExtractionError},
+    prompt,
+    step::Step,
+    tools::{Tool, ToolError},
+    traits::Executor,
+    Parameters,
+};
+
+#[derive(Debug, Error)]
+pub enum PlanAndExecuteAgentError<A, T>
+where
+    A: std::fmt::Debug + std::error::Error,
+    T: std::fmt::Debug + std::error::Error + ToolError,
+{
+    #[error("Step agent failed: {0}")]
+    StepAgentError(AgentExecutorError<A, T>),
+    #[error(transparent)]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    #[error("Could not parse a plan from the model output: {0}")]
+    PlanParseError(#[from] ExtractionError),
+    #[error("Model response was empty or contained no choices")]
+    NoChoicesReturned,
+    #[error("Max number of iterations or timeout exceeded. Elapsed: {time_elapsed_seconds}s, {iterations_elapsed} iterations")]
+    RuntimeExceeded {
+        time_elapsed_seconds: f64,
+        iterations_elapsed: u32,
+    },
+}
+
+/// The outcome of executing a single step of the plan.
+#[derive(Debug, Serialize)]
+pub struct StepOutcome {
+    /// The step of the plan, as written by the planner.
+    pub step: String,
+    /// The final answer of the sub-agent for this step.
+    pub answer: String,
+    /// The actions taken by the sub-agent to reach its answer.
+    pub intermediate_steps: Vec<AgentIntermediateStep>,
+}
+
+/// A structured record of a plan-and-execute run.
+#[derive(Debug, Default, Serialize)]
+pub struct PlanAndExecuteTrace {
+    /// Every version of the remaining plan, starting with the initial plan.
+    pub plan_revisions: Vec<Vec<String>>,
+    /// The outcome of every executed step, in order of execution.
+    pub step_outcomes: Vec<StepOutcome>,
+}
+
+/// The formats a plan is accepted in: either a YAML list or a YAML mapping of step numbers to steps.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PlanFormat {
+    List(Vec<String>),
+    Numbered(BTreeMap<u32, String>),
+}
+
+/// Parses a plan written by the model.
+///
+/// The plan is expected as YAML, either as a list of steps or as a mapping of step numbers to steps.
+/// An empty list means that no (further) steps are needed.
+pub fn parse_plan(text: &str) -> Result<Vec<String>, ExtractionError> {
+    let plan = find_yaml::<PlanFormat>(text)?
+        .into_iter()
+        .next()
+        .map(|plan| match plan {
+            PlanFormat::List(steps) => steps,
+            PlanFormat::Numbered(steps) => steps.into_values().collect(),
+        })
+        .unwrap_or_default();
+    Ok(plan
+        .into_iter()
+        .map(|step| step.trim().to_string())
+        .filter(|step| !step.is_empty())
+        .collect())
+}
+
+fn format_plan<'a>(steps: impl IntoIterator<Item = &'a String>) -> String {
+    steps
+        .into_iter()
+        .enumerate()
+        .map(|(idx, step)| format!("{}. {}", idx + 1, step))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_step_outcomes(outcomes: &[StepOutcome]) -> String {
+    outcomes
+        .iter()
+        .enumerate()
+        .map(|(idx, outcome)| format!("{}. {}\nResult: {}", idx + 1, outcome.step, outcome.answer))
+        .collect::<Vec<_>>()
+        .join("\n\n")
+}
+
+/// An agent that plans before it acts, executing every step of the plan with a sub-agent.
+pub struct Agent<E, A>
+where
+    E: Executor,
+    A: ToolAgent,
+{
+    executor: E,
+    step_agent: A,
+    early_stopping_config: EarlyStoppingConfig,
+    step_early_stopping_config: EarlyStoppingConfig,
+    planner: Step,
+    replanner: Option<Step>,
+    synthesizer: Step,
+    callbacks: Callbacks,
+}
+
+impl<E, A> Agent<E, A>
+where
+    E: Executor,
+    A: ToolAgent,
+{
+    /// Creates a new plan-and-execute agent.
+    ///
+    /// # Arguments
+    /// * `executor` - The executor used to plan, re-plan and synthesize the final answer.
+    /// * `step_agent` - The sub-agent executing each step of the plan with its tools, e.x. a ReAct agent.
+    /// * `early_stopping_config` - Limits the number of plan steps executed and the total time spent.
+    pub fn new(executor: E, step_agent: A, early_stopping_config: EarlyStoppingConfig) -> Self {
+        let planner = Step::for_prompt_template(prompt!(
+            "You are a planner. You will be given an objective and you will have to devise a step by step plan to achieve it.",
+            "Objective: {{input}}\n\nWrite a simple step by step plan to achieve the objective. Every step should be a self-contained task that can be carried out on its own, do not add any superfluous steps. The result of the final step should be the final answer.\n\nRespond only with the plan as a numbered YAML list, like this:\n```yaml\n1: first step\n2: second step\n```"
+        ));
+        let replanner = Step::for_prompt_template(prompt!(
+            "You are a planner. You will be given an objective, a plan and the steps that have been carried out so far, and you will have to update the plan.",
+            "Objective: {{input}}\n\nPlan:\n{{plan}}\n\nCompleted steps:\n{{past_steps}}\n\nUpdate the plan with the steps that still need to be carried out. Do not repeat completed steps. If no more steps are needed, respond with an empty list `[]`.\n\nRespond only with the remaining plan as a numbered YAML list, like this:\n```yaml\n1: next step\n2: step after that\n```"
+        ));
+        let synthesizer = Step::for_prompt_template(prompt!(
+            "You are a helpful assistant. You will be given an objective and the results of the steps taken to achieve it.",
+            "Objective: {{input}}\n\nCompleted steps:\n{{past_steps}}\n\nUsing the results above, write the final answer to the objective. Respond only with the answer."
+        ));
+        Self {
+            executor,
+            step_agent,
+            early_stopping_config,
+            step_early_stopping_config: EarlyStoppingConfig::default(),
+            planner,
+            replanner: Some(replanner),
+            synthesizer,
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Attaches callbacks to the agent and the executor running its step agent.
+    ///
+    /// The planning steps are reported as `plan`, `replan` and `synthesize`, the steps of the plan as
+    /// `plan step 1`, `plan step 2` and so on, each followed by the `agent step`s of the sub-agent.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Self {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Limits the iterations and time the sub-agent may spend on a single step of the plan. Unlimited by default.
+    pub fn with_step_early_stopping_config(
+        mut self,
+        step_early_stopping_config: EarlyStoppingConfig,
+    ) -> Self {
+        self.step_early_stopping_config = step_early_stopping_config;
+        self
+    }
+
+    /// Replaces the step used to write the initial plan.
+    ///
+    /// The prompt is formatted with the `input` parameter and must make the model respond with a YAML plan.
+    pub fn with_planner(mut self, planner: Step) -> Self {
+        self.planner = planner;
+        self
+    }
+
+    /// Replaces the step used to revise the plan after each step, or disables re-planning with `None`.
+    ///
+    /// The prompt is formatted with the `input`, `plan` and `past_steps` parameters and must make the model
+    /// respond with the remaining plan as YAML.
+    pub fn with_replanner(mut self, replanner: Option<Step>) -> Self {
+        self.replanner = replanner;
+        self
+    }
+
+    /// Replaces the step used to write the final answer.
+    ///
+    /// The prompt is formatted with the `input` and `past_steps` parameters.
+    pub fn with_synthesizer(mut self, synthesizer: Step) -> Self {
+        self.synthesizer = synthesizer;
+        self
+    }
+
+    async fn execute_step(
+        &self,
+        step: &Step,
+        name: &str,
+        parameters: &Parameters,
+    ) -> Result<String, PlanAndExecuteAgentError<A::Error, <A::Tool as Tool>::Error>> {
+        Frame::new(&self.executor, step)
+            .with_callbacks(self.callbacks.clone())
+            .with_name(name)
+            .format_and_execute(parameters)
+            .await?
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .primary_textual_output()
+            .ok_or(PlanAndExecuteAgentError::NoChoicesReturned)
+    }
+
+    /// Plans how to achieve the objective, executes the plan and returns the final answer.
+    ///
+    /// Returns the final answer together with a trace of all plan revisions and step outcomes.
+    pub async fn run(
+        &self,
+        query: &str,
+    ) -> Result<
+        (AgentFinish, PlanAndExecuteTrace),
+        PlanAndExecuteAgentError<A::Error, <A::Tool as Tool>::Error>,
+    > {
+        let output = self.try_run(query).await;
+        // Errors of the planning steps and the step agent have already been reported where they occurred.
+        if let Err(
+            err @ (PlanAndExecuteAgentError::PlanParseError(_)
+            | PlanAndExecuteAgentError::NoChoicesReturned
+            | PlanAndExecuteAgentError::RuntimeExceeded { .. }),
+        ) = &output
+        {
+            self.callbacks.emit(Event::Error {
+                message: err.to_string(),
+            });
+        }
+        output
+    }
+
+    async fn try_run(
+        &self,
+        query: &str,
+    ) -> Result<
+        (AgentFinish, PlanAndExecuteTrace),
+        PlanAndExecuteAgentError<A::Error, <A::Tool as Tool>::Error>,
+    > {
+        let mut trace = PlanAndExecuteTrace::default();
+        let plan = parse_plan(
+            &self
+                .execute_step(&self.planner, "plan", &parameters!("input" => query))
+                .await?,
+        )?;
+        trace.plan_revisions.push(plan.clone());
+        let mut remaining: VecDeque<String> = plan.into();
+
+        let mut iterations = 0;
+        let start = Instant::now();
+        while let Some(step) = remaining.pop_front() {
+            let time_elapsed_seconds = start.elapsed().as_secs_f64();
+            if !self
+                .early_stopping_config
+                .should_continue(iterations, time_elapsed_seconds)
+            {
+                return Err(PlanAndExecuteAgentError::RuntimeExceeded {
+                    time_elapsed_seconds,
+                    iterations_elapsed: iterations,
+                });
+            }
+            iterations += 1;
+            let name = format!("plan step {}", iterations);
+            self.callbacks
+                .emit(Event::StepStarted { name: name.clone() });
+
+            let step_input = format!(
+                "Objective: {}\n\nCompleted steps:\n{}\n\nYour task is to carry out the next step: {}",
+                query,
+                format_step_outcomes(&trace.step_outcomes),
+                step
+            );
+            let (finish, intermediate_steps) = AgentExecutor::new(
+                &self.step_agent,
+                self.step_agent.tools(),
+                &self.step_early_stopping_config,
+            )
+            .with_callbacks(self.callbacks.clone())
+            .run(&step_input)
+            .await
+            .map_err(PlanAndExecuteAgentError::StepAgentError)?;
+            let answer = finish.return_values.get("output").unwrap_or_default();
+            self.callbacks.emit(Event::StepFinished {
+                name,
+                output: answer.clone(),
+            });
+            trace.step_outcomes.push(StepOutcome {
+                step,
+                answer,
+                intermediate_steps,
+            });
+
+            if let Some(replanner) = &self.replanner {
+                let plan = format_plan(
+                    trace
+                        .step_outcomes
+                        .iter()
+                        .map(|outcome| &outcome.step)
+                        .chain(remaining.iter()),
+                );
+                let parameters = parameters!(
+                    "input" => query,
+                    "plan" => plan,
+                    "past_steps" => format_step_outcomes(&trace.step_outcomes)
+                );
+                let revised =
+                    parse_plan(&self.execute_step(replanner, "replan", &parameters).await?)?;
+                trace.plan_revisions.push(revised.clone());
+                remaining = revised.into();
+            }
+        }
+
+        let answer = self
+            .execute_step(
+                &self.synthesizer,
+                "synthesize",
+                &parameters!(
+                    "input" => query,
+                    "past_steps" => format_step_outcomes(&trace.step_outcomes)
+                ),
+            )
+            .await?;
+        let finish = AgentFinish {
+            return_values: parameters!("output" => answer.trim()),
+            log: answer,
+        };
+        Ok((finish, trace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_plan, Agent};
+    use crate::agents::{react, EarlyStoppingConfig};
+    use crate::testing::ScriptedExecutor;
+    use crate::tools::tools::ExitTool;
+    use crate::tools::ToolCollection;
+
+    fn tools() -> ToolCollection<ExitTool> {
+        let mut tools = ToolCollection::new();
+        tools.add_tool(ExitTool::new());
+        tools
+    }
+
+    #[test]
+    fn test_parses_numbered_plan() {
+        let text = "Here is the plan:
+```yaml
+1: Find out who founded craigslist
+2: Find out when the founder was born
+```";
+        assert_eq!(
+            parse_plan(text).unwrap(),
+            vec![
+                "Find out who founded craigslist".to_string(),
+                "Find out when the founder was born".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parses_list_plan() {
+        let text = "- Find out who founded craigslist\n- Find out when the founder was born";
+        assert_eq!(parse_plan(text).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_parses_empty_plan() {
+        assert!(parse_plan("[]").unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_replans_after_each_step_and_records_the_trace() {
+        let planner = ScriptedExecutor::new(|prompt| {
+            if prompt.contains("devise a step by step plan") {
+                "1: look up A\n2: look up B\n3: look up C".to_string()
+            } else if prompt.contains("write the final answer") {
+                "A and C".to_string()
+            } else if prompt.contains("Result: done look up C") {
+                "[]".to_string()
+            } else {
+                "1: look up C".to_string()
+            }
+        });
+        let step_executor = ScriptedExecutor::new(|prompt| {
+            let step = prompt
+                .rsplit("carry out the next step: ")
+                .next()
+                .and_then(|rest| rest.lines().next())
+                .unwrap_or_default();
+            format!("Final Answer: done {}", step)
+        });
+        let step_agent = react::Agent::new(step_executor, tools(), EarlyStoppingConfig::default());
+        let agent = Agent::new(planner, step_agent, EarlyStoppingConfig::default());
+
+        let (finish, trace) = agent.run("Find A and C").await.unwrap();
+
+        assert_eq!(finish.return_values.get("output").unwrap(), "A and C");
+        assert_eq!(
+            trace.plan_revisions,
+            vec![
+                vec!["look up A", "look up B", "look up C"],
+                vec!["look up C"],
+                vec![],
+            ]
+        );
+        let answers: Vec<_> = trace
+            .step_outcomes
+            .iter()
+            .map(|outcome| (outcome.step.as_str(), outcome.answer.as_str()))
+            .collect();
+        assert_eq!(
+            answers,
+            vec![
+                ("look up A", "done look up A"),
+                ("look up C", "done look up C")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stops_after_max_plan_steps() {
+        let planner =
+            ScriptedExecutor::new(|_| "1: look up A\n2: look up B\n3: look up C".to_string());
+        let step_executor = ScriptedExecutor::new(|_| "Final Answer: done".to_string());
+        let step_agent = react::Agent::new(step_executor, tools(), EarlyStoppingConfig::default());
+        let config = EarlyStoppingConfig {
+            max_iterations: Some(1),
+            max_time_elapsed_seconds: None,
+        };
+        let agent = Agent::new(planner, step_agent, config).with_replanner(None);
+
+        let result = agent.run("Find A, B and C").await;
+
+        // Like the `AgentExecutor`, the limit is checked before every step, so one step more than the limit runs.
+        assert!(matches!(
+            result,
+            Err(super::PlanAndExecuteAgentError::RuntimeExceeded {
+                iterations_elapsed: 2,
+                ..
+            })
+        ));
+    }
+}
@@ -0,0 +1,388 @@
+//! A general purpose ReAct agent that can use any tool in a `ToolCollection`.
+//!
+//! The agent alternates between reasoning and acting: at every step the model writes down a
+//! thought, picks a tool by name and provides the tool input as YAML. The tool output is fed back to
+//! the model as an observation until the model decides that it knows the final answer.
+//!
+//! See [ReAct: Synergizing Reasoning and Acting in Language Models](https://arxiv.org/abs/2210.03629).
+use super::agent::{
+    AgentAction, AgentDecision, AgentFinish, AgentIntermediateStep, AgentOutputParser,
+    EarlyStoppingConfig,
+};
+use super::executor::{AgentExecutor, AgentExecutorError};
+use crate::{
+    callbacks::{Callbacks, Event},
+    options::{Opt, Options},
+    parameters,
+    parsing::find_yaml,
+    prompt::{PromptTemplate, StringTemplateError},
+    tools::{Tool, ToolCollection, ToolError, ToolUseError},
+    traits::{Executor, ExecutorError},
+};
+use async_trait::async_trait;
+use thiserror::Error;
+
+const PROMPT: &str =
+    "Answer the following question as best you can. You have access to the following tools:
+
+{{tools}}
+
+Use the following format:
+
+Question: the input question you must answer
+Thought: you should always think about what to do
+Action: the action to take, should be one of [{{tool_names}}]
+Action Input: the input to the action, written as YAML matching the input format of the tool
+Observation: the result of the action
+... (this Thought/Action/Action Input/Observation can repeat N times)
+Thought: I now know the final answer
+Final Answer: the final answer to the original input question
+
+Begin!
+
+Question: {{input}}
+Thought:{{agent_scratchpad}}";
+
+#[derive(Debug, Error)]
+pub enum ReActAgentError<T>
+where
+    T: std::fmt::Debug + std::error::Error + ToolError,
+{
+    #[error(transparent)]
+    ToolUseError(#[from] ToolUseError<T>),
+    #[error(transparent)]
+    ExecutorError(ExecutorError),
+    #[error(transparent)]
+    ParserError(#[from] ParserError),
+    #[error(transparent)]
+    YamlError(#[from] serde_yaml::Error),
+    #[error(transparent)]
+    StringTemplateError(#[from] StringTemplateError),
+    #[error("Model response was empty or contained no choices")]
+    NoChoicesReturned,
+    #[error("Max number of iterations or timeout exceeded. Elapsed: {time_elapsed_seconds}s, {iterations_elapsed} iterations")]
+    RuntimeExceeded {
+        time_elapsed_seconds: f64,
+        iterations_elapsed: u32,
+    },
+}
+
+#[derive(Debug, Error)]
+#[error("No action or final answer was returned by the model: {0}")]
+pub struct ParserError(String);
+
+/// Parses the `Action:`/`Action Input:` and `Final Answer:` lines written by a ReAct model.
+pub struct ReActAgentOutputParser {
+    action_prefix: String,
+    action_input_prefix: String,
+    observation_prefix: String,
+    final_answer_prefix: String,
+}
+
+impl ReActAgentOutputParser {
+    pub fn new(
+        action_prefix: &str,
+        action_input_prefix: &str,
+        observation_prefix: &str,
+        final_answer_prefix: &str,
+    ) -> Self {
+        Self {
+            action_prefix: action_prefix.into(),
+            action_input_prefix: action_input_prefix.into(),
+            observation_prefix: observation_prefix.into(),
+            final_answer_prefix: final_answer_prefix.into(),
+        }
+    }
+
+    /// Parses the tool input, falling back to a plain string when it isn't valid YAML.
+    fn parse_tool_input(raw: &str) -> serde_yaml::Value {
+        find_yaml::<serde_yaml::Value>(raw)
+            .ok()
+            .and_then(|values| values.into_iter().next())
+            .unwrap_or_else(|| raw.into())
+    }
+}
+
+impl Default for ReActAgentOutputParser {
+    fn default() -> Self {
+        Self::new("Action:", "Action Input:", "Observation:", "Final Answer:")
+    }
+}
+
+impl AgentOutputParser for ReActAgentOutputParser {
+    type Error = ParserError;
+    fn parse(&self, text: String) -> Result<AgentDecision, Self::Error> {
+        let action_idx = text.find(&self.action_prefix);
+        let final_answer_idx = text.find(&self.final_answer_prefix);
+
+        if let Some(idx) = final_answer_idx {
+            let is_before_action = match action_idx {
+                Some(action_idx) => idx < action_idx,
+                None => true,
+            };
+            if is_before_action {
+                let final_answer = &text[idx + self.final_answer_prefix.len()..];
+                return Ok(AgentDecision::Finish(AgentFinish {
+                    return_values: parameters!("output" => final_answer.trim()),
+                    log: text,
+                }));
+            }
+        }
+
+        let (Some(action_idx), Some(input_idx)) =
+            (action_idx, text.find(&self.action_input_prefix))
+        else {
+            return Err(ParserError(text));
+        };
+        if input_idx < action_idx {
+            return Err(ParserError(text));
+        }
+
+        let tool = text[action_idx + self.action_prefix.len()..input_idx]
+            .trim()
+            .to_string();
+        // Models sometimes hallucinate the observation, everything from there on is discarded.
+        let input_end = text[input_idx..]
+            .find(&self.observation_prefix)
+            .map(|idx| input_idx + idx)
+            .unwrap_or(text.len());
+        let raw_input = text[input_idx + self.action_input_prefix.len()..input_end].trim();
+        if tool.is_empty() {
+            return Err(ParserError(text));
+        }
+
+        Ok(AgentDecision::Action(AgentAction {
+            tool,
+            tool_input: Self::parse_tool_input(raw_input),
+            log: text[..input_end].trim_end().to_string(),
+        }))
+    }
+}
+
+/// A ReAct agent choosing between all the tools of a `ToolCollection`.
+pub struct Agent<E, T>
+where
+    E: Executor,
+    T: Tool + Send + Sync,
+{
+    executor: E,
+    tools: ToolCollection<T>,
+    early_stopping_config: EarlyStoppingConfig,
+    options: Options,
+    observation_prefix: String,
+    llm_prefix: String,
+    output_parser: ReActAgentOutputParser,
+    callbacks: Callbacks,
+}
+
+impl<E, T> Agent<E, T>
+where
+    E: Executor,
+    T: Tool + Send + Sync,
+{
+    pub fn new(
+        executor: E,
+        tools: ToolCollection<T>,
+        early_stopping_config: EarlyStoppingConfig,
+    ) -> Self {
+        let mut options = Options::builder();
+        options.add_option(Opt::StopSequence(vec!["\nObservation:".to_string()]));
+        Self {
+            executor,
+            tools,
+            early_stopping_config,
+            options: options.build(),
+            observation_prefix: "Observation: ".to_string(),
+            llm_prefix: "Thought:".to_string(),
+            output_parser: ReActAgentOutputParser::default(),
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Attaches callbacks to the agent, which are notified of every step, prompt and tool invocation.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Self {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Convert the intermediate steps into a single text to pass to the agent so he can continue his thought process
+    pub fn build_agent_scratchpad(
+        &self,
+        intermediate_steps: &[AgentIntermediateStep],
+    ) -> Result<String, serde_yaml::Error> {
+        let mut scratchpad = "".to_string();
+        for intermediate_step in intermediate_steps {
+            let observation = match intermediate_step.observation.as_str() {
+                Some(s) => s.to_string(),
+                None => serde_yaml::to_string(&intermediate_step.observation)?,
+            };
+            scratchpad += &format!(
+                " {}\n{}{}\n{}",
+                intermediate_step.action.log.trim_start(),
+                self.observation_prefix,
+                observation.trim_end(),
+                self.llm_prefix
+            );
+        }
+        Ok(scratchpad)
+    }
+
+    pub async fn run(
+        &self,
+        query: &str,
+    ) -> Result<(AgentFinish, Vec<AgentIntermediateStep>), ReActAgentError<<T as Tool>::Error>>
+    where
+        E: Sync,
+    {
+        AgentExecutor::new(self, &self.tools, &self.early_stopping_config)
+            .with_callbacks(self.callbacks.clone())
+            .run(query)
+            .await
+            .map_err(|e| match e {
+                AgentExecutorError::AgentError(e) => e,
+                AgentExecutorError::ToolUseError(e) => ReActAgentError::ToolUseError(e),
+                AgentExecutorError::RuntimeExceeded {
+                    time_elapsed_seconds,
+                    iterations_elapsed,
+                } => ReActAgentError::RuntimeExceeded {
+                    time_elapsed_seconds,
+                    iterations_elapsed,
+                },
+            })
+    }
+}
+
+impl<E, T> super::agent::ToolAgent for Agent<E, T>
+where
+    E: Executor + Sync,
+    T: Tool + Send + Sync,
+{
+    type Tool = T;
+
+    fn tools(&self) -> &ToolCollection<T> {
+        &self.tools
+    }
+}
+
+#[async_trait]
+impl<E, T> super::agent::Agent for Agent<E, T>
+where
+    E: Executor + Sync,
+    T: Tool + Send + Sync,
+{
+    type OutputParser = ReActAgentOutputParser;
+    type Error = ReActAgentError<<T as Tool>::Error>;
+
+    fn output_parser(&self) -> &Self::OutputParser {
+        &self.output_parser
+    }
+
+    /// Fills in the prompt template then calls the model to complete it
+    async fn generate(
+        &self,
+        intermediate_steps: &[AgentIntermediateStep],
+        query: &str,
+    ) -> Result<String, Self::Error> {
+        let scratchpad = self.build_agent_scratchpad(intermediate_steps)?;
+        let template_parameters = parameters!(
+            "input" => query,
+            "agent_scratchpad" => scratchpad,
+            "tools" => self.tools.describe()?,
+            "tool_names" => self.tools.tool_names().join(", ")
+        );
+        let prompt = PromptTemplate::Text(PROMPT.into()).format(&template_parameters)?;
+        self.callbacks.emit(Event::PromptFormatted {
+            prompt: prompt.clone(),
+        });
+        let plan = self
+            .executor
+            .execute(&self.options, &prompt)
+            .await
+            .map_err(ReActAgentError::ExecutorError)?;
+        self.callbacks
+            .observe_output(plan, None)
+            .to_immediate()
+            .await
+            .map_err(ReActAgentError::ExecutorError)?
+            .as_content()
+            .extract_last_body()
+            .cloned()
+            .ok_or(ReActAgentError::NoChoicesReturned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        agents::self_ask_with_search::{
+            AgentAction, AgentDecision, AgentFinish, AgentOutputParser,
+        },
+        parameters,
+    };
+
+    use super::ReActAgentOutputParser;
+
+    #[test]
+    fn test_parses_action_with_yaml_input() {
+        let parser = ReActAgentOutputParser::default();
+        let text = " I should list the files first.
+Action: BashTool
+Action Input:
+  cmd: ls
+Observation: GOAL.txt";
+        let decision = parser.parse(text.into()).unwrap();
+        let mut expected_input = serde_yaml::Mapping::new();
+        expected_input.insert("cmd".into(), "ls".into());
+        assert_eq!(
+            decision,
+            AgentDecision::Action(AgentAction {
+                tool: "BashTool".into(),
+                tool_input: expected_input.into(),
+                log: " I should list the files first.
+Action: BashTool
+Action Input:
+  cmd: ls"
+                    .into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parses_action_with_plain_input() {
+        let parser = ReActAgentOutputParser::default();
+        let text = " I need to search for this.
+Action: GoogleSerper
+Action Input: capital of Zambia";
+        let decision = parser.parse(text.into()).unwrap();
+        assert_eq!(
+            decision,
+            AgentDecision::Action(AgentAction {
+                tool: "GoogleSerper".into(),
+                tool_input: "capital of Zambia".into(),
+                log: text.into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parses_final_answer() {
+        let parser = ReActAgentOutputParser::default();
+        let text = " I now know the final answer
+Final Answer: Lusaka: the capital of Zambia
+";
+        let decision = parser.parse(text.into()).unwrap();
+        assert_eq!(
+            decision,
+            AgentDecision::Finish(AgentFinish {
+                return_values: parameters!("output" => "Lusaka: the capital of Zambia"),
+                log: text.into()
+            })
+        );
+    }
+
+    #[test]
+    fn test_fails_without_action_or_final_answer() {
+        let parser = ReActAgentOutputParser::default();
+        assert!(parser.parse(" I am not sure what to do.".into()).is_err());
+    }
+}
@@ -1,12 +1,17 @@
+pub use super::agent::{
+    AgentAction, AgentDecision, AgentFinish, AgentIntermediateStep, AgentIntermediateStepOutput,
+    AgentOutputParser, EarlyStoppingConfig,
+};
+use super::executor::{AgentExecutor, AgentExecutorError};
 use crate::{
+    callbacks::{Callbacks, Event},
     options::Options,
     parameters,
     prompt::{PromptTemplate, StringTemplateError},
-    tools::{Tool, ToolError},
+    tools::{Tool, ToolCollection, ToolDescription, ToolError, ToolUseError},
     traits::{Executor, ExecutorError},
-    Parameters,
 };
-use std::time::{Duration, Instant};
+use async_trait::async_trait;
 use thiserror::Error;
 
 /// TODO: This prompt has some issues:
@@ -54,38 +59,8 @@ So the final answer is: No
 Question: {{input}}
 Are followup questions needed here:{{agent_scratchpad}}";
 
-#[derive(Debug, PartialEq, Eq)]
-pub struct AgentAction {
-    pub tool: String,
-    pub tool_input: serde_yaml::Value,
-    pub log: String,
-}
-#[derive(Debug, PartialEq)]
-pub struct AgentFinish {
-    pub return_values: Parameters,
-    pub log: String,
-}
-
-#[derive(Debug)]
-pub struct AgentIntermediateStep {
-    pub action: AgentAction,
-    pub observation: serde_yaml::Value,
-}
-
-pub enum AgentIntermediateStepOutput {
-    Step(AgentIntermediateStep),
-    Finish(AgentFinish),
-}
-
-#[derive(Debug, PartialEq)]
-pub enum AgentDecision {
-    Action(AgentAction),
-    Finish(AgentFinish),
-}
-pub trait AgentOutputParser {
-    type Error;
-    fn parse(&self, text: String) -> Result<AgentDecision, Self::Error>;
-}
+/// The name of the action the self-ask output parser decides on when asking a follow up question.
+const INTERMEDIATE_ANSWER_TOOL: &str = "Intermediate Answer";
 
 #[derive(Debug, Error)]
 pub enum SelfAskWithSearchAgentError<T>
@@ -97,6 +72,8 @@ where
     #[error(transparent)]
     SearchToolError(T),
     #[error(transparent)]
+    ToolUseError(ToolUseError<T>),
+    #[error(transparent)]
     ExecutorError(ExecutorError),
     #[error(transparent)]
     ParserError(#[from] ParserError),
@@ -193,7 +170,7 @@ impl AgentOutputParser for SelfAskWithSearchAgentOutputParser {
                 (followup_question, log)
             };
             Ok(AgentDecision::Action(AgentAction {
-                tool: "Intermediate Answer".into(),
+                tool: INTERMEDIATE_ANSWER_TOOL.into(),
                 tool_input: followup_question.into(),
                 log,
             }))
@@ -213,10 +190,30 @@ impl AgentOutputParser for SelfAskWithSearchAgentOutputParser {
     }
 }
 
-#[derive(Default)]
-pub struct EarlyStoppingConfig {
-    pub max_iterations: Option<u32>,
-    pub max_time_elapsed_seconds: Option<f64>,
+/// Exposes the search tool to the `AgentExecutor` under the name used by the output parser.
+struct IntermediateAnswerTool<T>(T);
+
+#[async_trait]
+impl<T> Tool for IntermediateAnswerTool<T>
+where
+    T: Tool + Send + Sync,
+    T::Input: From<String>,
+    T::Output: Into<String>,
+{
+    type Input = String;
+    type Output = String;
+    type Error = T::Error;
+
+    async fn invoke_typed(&self, input: &Self::Input) -> Result<Self::Output, Self::Error> {
+        let input = input.clone().into();
+        self.0.invoke_typed(&input).await.map(Into::into)
+    }
+
+    fn description(&self) -> ToolDescription {
+        let mut description = self.0.description();
+        description.name = INTERMEDIATE_ANSWER_TOOL.to_string();
+        description
+    }
 }
 
 pub struct Agent<E, T>
@@ -227,90 +224,43 @@ where
     T::Output: Into<String>,
 {
     executor: E,
-    search_tool: T,
+    search_tool: ToolCollection<IntermediateAnswerTool<T>>,
     early_stopping_config: EarlyStoppingConfig,
     observation_prefix: String,
     llm_prefix: String,
     output_parser: SelfAskWithSearchAgentOutputParser,
+    callbacks: Callbacks,
 }
 
 impl<E, T> Agent<E, T>
 where
     E: Executor,
-    T: Tool,
+    T: Tool + Send + Sync,
     T::Input: From<String>,
     T::Output: Into<String>,
 {
     pub fn new(executor: E, search_tool: T, early_stopping_config: EarlyStoppingConfig) -> Self {
+        let mut tools = ToolCollection::new();
+        tools.add_tool(IntermediateAnswerTool(search_tool));
         Self {
             executor,
-            search_tool,
+            search_tool: tools,
             early_stopping_config,
             observation_prefix: "Intermediate answer: ".to_string(),
             llm_prefix: "".to_string(),
             output_parser: SelfAskWithSearchAgentOutputParser::default(),
+            callbacks: Callbacks::default(),
         }
     }
 
-    fn should_continue(&self, iterations_elapsed: u32, time_elapsed_seconds: f64) -> bool {
-        match (
-            self.early_stopping_config.max_iterations,
-            self.early_stopping_config.max_time_elapsed_seconds,
-        ) {
-            (None, None) => true,
-            (None, Some(max_time_elapsed_seconds)) => {
-                max_time_elapsed_seconds >= time_elapsed_seconds
-            }
-            (Some(max_iterations), None) => max_iterations >= iterations_elapsed,
-            (Some(max_iterations), Some(max_time_elapsed_seconds)) => {
-                max_iterations >= iterations_elapsed
-                    && max_time_elapsed_seconds >= time_elapsed_seconds
-            }
-        }
-    }
-
-    /// Ask a model for a decision on what to do next, e.x. which tool to use
-    ///
-    /// Perform the action
-    async fn take_next_step(
-        &self,
-        intermediate_steps: &Vec<AgentIntermediateStep>,
-        query: &str,
-    ) -> Result<AgentIntermediateStepOutput, SelfAskWithSearchAgentError<<T as Tool>::Error>> {
-        let output = self.plan(intermediate_steps, query).await?;
-
-        let decision = self.output_parser.parse(output)?;
-        match decision {
-            AgentDecision::Action(action) => {
-                let observation = self
-                    .search_tool
-                    .invoke_typed(
-                        &action
-                            .tool_input
-                            .as_str()
-                            .ok_or(SelfAskWithSearchAgentError::ToolInputNotString(
-                                action.tool_input.clone(),
-                            ))?
-                            .to_string()
-                            .into(),
-                    )
-                    .await
-                    .map_err(SelfAskWithSearchAgentError::SearchToolError)?;
-
-                Ok(AgentIntermediateStepOutput::Step(AgentIntermediateStep {
-                    action,
-                    observation: serde_yaml::to_value(Into::<String>::into(observation))?,
-                }))
-            }
-            AgentDecision::Finish(finish) => Ok(AgentIntermediateStepOutput::Finish(finish)),
-        }
+    /// Attaches callbacks to the agent, which are notified of every step, prompt and tool invocation.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Self {
+        self.callbacks = callbacks;
+        self
     }
 
     /// Convert the intermediate steps into a single text to pass to the agent so he can continue his thought process
-    pub fn build_agent_scratchpad(
-        &self,
-        intermediate_steps: &Vec<AgentIntermediateStep>,
-    ) -> String {
+    pub fn build_agent_scratchpad(&self, intermediate_steps: &[AgentIntermediateStep]) -> String {
         let mut scratchpad = "".to_string();
         for intermediate_step in intermediate_steps {
             scratchpad += &intermediate_step.action.log;
@@ -324,23 +274,72 @@ where
         scratchpad
     }
 
-    /// Ask a model for a decision on what to do next, e.x. which tool to use
-    ///
+    pub async fn run(
+        &self,
+        query: &str,
+    ) -> Result<
+        (AgentFinish, Vec<AgentIntermediateStep>),
+        SelfAskWithSearchAgentError<<T as Tool>::Error>,
+    >
+    where
+        E: Sync,
+    {
+        AgentExecutor::new(self, &self.search_tool, &self.early_stopping_config)
+            .with_callbacks(self.callbacks.clone())
+            .run(query)
+            .await
+            .map_err(|e| match e {
+                AgentExecutorError::AgentError(e) => e,
+                AgentExecutorError::ToolUseError(ToolUseError::ToolError(e)) => {
+                    SelfAskWithSearchAgentError::SearchToolError(e)
+                }
+                AgentExecutorError::ToolUseError(e) => SelfAskWithSearchAgentError::ToolUseError(e),
+                AgentExecutorError::RuntimeExceeded {
+                    time_elapsed_seconds,
+                    iterations_elapsed,
+                } => SelfAskWithSearchAgentError::RuntimeExceeded {
+                    time_elapsed_seconds,
+                    iterations_elapsed,
+                },
+            })
+    }
+}
+
+#[async_trait]
+impl<E, T> super::agent::Agent for Agent<E, T>
+where
+    E: Executor + Sync,
+    T: Tool + Send + Sync,
+    T::Input: From<String>,
+    T::Output: Into<String>,
+{
+    type OutputParser = SelfAskWithSearchAgentOutputParser;
+    type Error = SelfAskWithSearchAgentError<<T as Tool>::Error>;
+
+    fn output_parser(&self) -> &Self::OutputParser {
+        &self.output_parser
+    }
+
     /// Fills in the prompt template then calls the model to complete it
-    async fn plan(
+    async fn generate(
         &self,
-        intermediate_steps: &Vec<AgentIntermediateStep>,
+        intermediate_steps: &[AgentIntermediateStep],
         query: &str,
-    ) -> Result<String, SelfAskWithSearchAgentError<<T as Tool>::Error>> {
+    ) -> Result<String, Self::Error> {
         let scratchpad = self.build_agent_scratchpad(intermediate_steps);
         let template_parameters = parameters!("input" => query, "agent_scratchpad" => scratchpad);
         let prompt = PromptTemplate::Text(PROMPT.into()).format(&template_parameters)?;
+        self.callbacks.emit(Event::PromptFormatted {
+            prompt: prompt.clone(),
+        });
         let plan = self
             .executor
             .execute(Options::empty(), &prompt)
             .await
             .map_err(SelfAskWithSearchAgentError::ExecutorError)?;
-        plan.to_immediate()
+        self.callbacks
+            .observe_output(plan, None)
+            .to_immediate()
             .await
             .map_err(SelfAskWithSearchAgentError::ExecutorError)?
             .as_content()
@@ -349,33 +348,19 @@ where
             .ok_or(SelfAskWithSearchAgentError::NoChoicesReturned)
     }
 
-    pub async fn run(
+    /// Asks the model for the next follow-up question, which the search tool only accepts as a string.
+    async fn plan(
         &self,
+        intermediate_steps: &[AgentIntermediateStep],
         query: &str,
-    ) -> Result<
-        (AgentFinish, Vec<AgentIntermediateStep>),
-        SelfAskWithSearchAgentError<<T as Tool>::Error>,
-    > {
-        let mut intermediate_steps = vec![];
-
-        let mut iterations = 0;
-        let start = Instant::now();
-        let mut full_duration = Duration::from_nanos(0);
-        while self.should_continue(iterations, full_duration.as_secs_f64()) {
-            let decision = self.take_next_step(&intermediate_steps, query).await?;
-            full_duration = start.elapsed();
-            iterations += 1;
-            match decision {
-                AgentIntermediateStepOutput::Step(step) => intermediate_steps.push(step),
-                AgentIntermediateStepOutput::Finish(finish) => {
-                    return Ok((finish, intermediate_steps))
-                }
-            }
+    ) -> Result<AgentDecision, Self::Error> {
+        let output = self.generate(intermediate_steps, query).await?;
+        match self.output_parser.parse(output)? {
+            AgentDecision::Action(action) if !action.tool_input.is_string() => Err(
+                SelfAskWithSearchAgentError::ToolInputNotString(action.tool_input),
+            ),
+            decision => Ok(decision),
         }
-        Err(SelfAskWithSearchAgentError::RuntimeExceeded {
-            time_elapsed_seconds: full_duration.as_secs_f64(),
-            iterations_elapsed: iterations,
-        })
     }
 }
 
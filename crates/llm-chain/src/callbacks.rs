@@ -0,0 +1,231 @@
+//! Callbacks make the progress of frames, chains and agents observable while they are running.
+//!
+//! Every component that supports callbacks has a `with_callbacks` method taking a `Callbacks` set. During a
+//! run the component emits `Event`s to every handler in the set, so a single handler can drive logging, UI
+//! updates and metrics alike.
+//!
+//! A handler is anything implementing `CallbackHandler`: closures taking an `&Event` work out of the box,
+//! and `Callbacks::channel` creates a set forwarding all events to an unbounded channel.
+//!
+//! # Example
+//!
+//! ```rust
+//! use llm_chain::callbacks::{Callbacks, Event};
+//!
+//! let callbacks = Callbacks::new().with_handler(|event: &Event| {
+//!     if let Event::StepFinished { name, output } = event {
+//!         println!("{} finished: {}", name, output);
+//!     }
+//! });
+//! ```
+use std::fmt;
+use std::sync::Arc;
+
+use tokio::sync::mpsc;
+
+use crate::output::{Output, StreamExt, StreamSegment};
+use crate::prompt::Prompt;
+
+/// An event emitted while a frame, chain or agent is running.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Event {
+    /// A step started, e.x. the `map` step of a map-reduce chain or an iteration of an agent.
+    StepStarted { name: String },
+    /// A prompt was formatted and is about to be sent to the model.
+    PromptFormatted { prompt: Prompt },
+    /// The model streamed a token.
+    Token { content: String },
+    /// An agent invoked a tool.
+    ToolInvoked {
+        tool: String,
+        input: serde_yaml::Value,
+    },
+    /// A tool returned its output to an agent.
+    ToolReturned {
+        tool: String,
+        output: serde_yaml::Value,
+    },
+    /// A step finished, with the text it produced.
+    StepFinished { name: String, output: String },
+    /// An error occurred. Every error is reported once, by the component it originated in.
+    Error { message: String },
+    /// A stage of a chain made progress, e.x. `completed` of the `total` chunks of the `map` stage of a map-reduce
+    /// chain are done.
+    Progress {
+        stage: String,
+        completed: usize,
+        total: usize,
+    },
+}
+
+/// A handler receiving the events emitted during a run.
+///
+/// Handlers are called synchronously from the running task, so they should return quickly.
+pub trait CallbackHandler: Send + Sync {
+    fn on_event(&self, event: &Event);
+}
+
+impl<F> CallbackHandler for F
+where
+    F: Fn(&Event) + Send + Sync,
+{
+    fn on_event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+impl CallbackHandler for mpsc::UnboundedSender<Event> {
+    fn on_event(&self, event: &Event) {
+        // A receiver that went away must not interrupt the run.
+        let _ = self.send(event.clone());
+    }
+}
+
+/// A set of callback handlers. Cloning it is cheap, the handlers are shared between the clones.
+#[derive(Clone, Default)]
+pub struct Callbacks {
+    handlers: Vec<Arc<dyn CallbackHandler>>,
+}
+
+impl Callbacks {
+    /// Creates an empty set of callbacks.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a set of callbacks forwarding every event to the returned receiver.
+    pub fn channel() -> (Self, mpsc::UnboundedReceiver<Event>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (Self::new().with_handler(sender), receiver)
+    }
+
+    /// Adds a handler to the set.
+    pub fn add_handler<H: CallbackHandler + 'static>(&mut self, handler: H) {
+        self.handlers.push(Arc::new(handler));
+    }
+
+    /// Adds a handler to the set, returning the set.
+    pub fn with_handler<H: CallbackHandler + 'static>(mut self, handler: H) -> Self {
+        self.add_handler(handler);
+        self
+    }
+
+    /// Returns true if the set contains no handlers.
+    pub fn is_empty(&self) -> bool {
+        self.handlers.is_empty()
+    }
+
+    /// Sends an event to every handler in the set.
+    pub fn emit(&self, event: Event) {
+        for handler in &self.handlers {
+            handler.on_event(&event);
+        }
+    }
+
+    /// Reports the tokens of a streamed output as they arrive, and the step as finished or failed when `step_name`
+    /// is given.
+    ///
+    /// Immediate outputs are finished right away, streams are finished once the whole stream has been produced.
+    pub(crate) fn observe_output(&self, output: Output, step_name: Option<&str>) -> Output {
+        if self.is_empty() {
+            return output;
+        }
+        match output {
+            Output::Immediate(immediate) => {
+                if let Some(name) = step_name {
+                    self.emit(Event::StepFinished {
+                        name: name.to_string(),
+                        output: immediate.primary_textual_output().unwrap_or_default(),
+                    });
+                }
+                Output::Immediate(immediate)
+            }
+            Output::Stream(mut stream) => {
+                let (sender, observed) = Output::new_stream();
+                let callbacks = self.clone();
+                let step_name = step_name.map(str::to_string);
+                tokio::spawn(async move {
+                    let mut text = String::new();
+                    let mut failed = false;
+                    while let Some(segment) = stream.next().await {
+                        match &segment {
+                            StreamSegment::Content(content) => {
+                                text.push_str(content);
+                                callbacks.emit(Event::Token {
+                                    content: content.clone(),
+                                });
+                            }
+                            StreamSegment::Err(err) => {
+                                failed = true;
+                                // Without a step the error is left to whoever owns the output.
+                                if step_name.is_some() {
+                                    callbacks.emit(Event::Error {
+                                        message: err.to_string(),
+                                    });
+                                }
+                            }
+                            StreamSegment::Role(_) => {}
+                        }
+                        if sender.send(segment).is_err() {
+                            return;
+                        }
+                    }
+                    if let (Some(name), false) = (step_name, failed) {
+                        callbacks.emit(Event::StepFinished { name, output: text });
+                    }
+                });
+                observed
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Callbacks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Callbacks")
+            .field("handlers", &self.handlers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Callbacks, Event};
+    use crate::output::{Output, StreamSegment};
+
+    #[tokio::test]
+    async fn test_observes_streamed_output() {
+        let (callbacks, mut events) = Callbacks::channel();
+        let (sender, output) = Output::new_stream();
+        let output = callbacks.observe_output(output, Some("answer"));
+        sender
+            .send(StreamSegment::Content("Hello, ".to_string()))
+            .unwrap();
+        sender
+            .send(StreamSegment::Content("world".to_string()))
+            .unwrap();
+        drop(sender);
+
+        let text = output
+            .to_immediate()
+            .await
+            .unwrap()
+            .primary_textual_output();
+        assert_eq!(text.as_deref(), Some("Hello, world"));
+        drop(callbacks);
+
+        let mut tokens = vec![];
+        while let Some(event) = events.recv().await {
+            match event {
+                Event::Token { content } => tokens.push(content),
+                Event::StepFinished { name, output } => {
+                    assert_eq!(name, "answer");
+                    assert_eq!(output, "Hello, world");
+                }
+                event => panic!("unexpected event {:?}", event),
+            }
+        }
+        assert_eq!(tokens, vec!["Hello, ", "world"]);
+    }
+}
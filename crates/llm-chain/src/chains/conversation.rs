@@ -2,10 +2,36 @@
 //! It manages the conversation state and provides methods for sending messages and receiving responses.
 //!
 //! It relies on the `traits::Executor` trait to execute prompts and handle LLM interactions.
+//!
+//! Answers can also be streamed with `stream_message`, which returns a `ConversationStream` yielding the answer as
+//! it's generated and recording it in the conversation once the stream ends or is dropped.
+//!
+//! Conversations can be changed after the fact, like chat interfaces allow: `regenerate` replaces the last answer,
+//! `edit_message` rewrites an earlier message and drops everything after it, and `fork` starts an independent
+//! conversation from a prefix of another. A `ConversationTree` keeps all those branches together, storing the
+//! messages they share once, and can be serialized.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut tree = ConversationTree::new();
+//! tree.insert(chain.history());
+//! // The user edits their second message and wants a new answer to it.
+//! chain.edit_message(3, "What about Norway?".to_string())?;
+//! chain.regenerate(&options, &executor).await?;
+//! let leaf = tree.insert(chain.history());
+//! ```
+
+use crate::memory::{ConversationMemory, MemoryError, TokenWindowMemory};
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 use crate::options::Options;
-use crate::output::Output;
-use crate::prompt::{ChatMessageCollection, Prompt, PromptTemplate, StringTemplateError};
+use crate::output::{Output, OutputStream, Stream, StreamExt, StreamSegment};
+use crate::prompt::{
+    ChatMessage, ChatMessageCollection, ChatRole, Data, Prompt, PromptTemplate, StringTemplateError,
+};
+use crate::serialization::StorableEntity;
 use crate::step::Step;
 use crate::tokens::{PromptTokensError, TokenizerError};
 use crate::traits::{Executor, ExecutorError};
@@ -15,9 +41,24 @@ use serde::{Deserialize, Serialize};
 /// `Chain` represents a conversation between an entity and an LLM.
 ///
 /// It holds the conversation state and provides methods for sending messages and receiving responses.
+/// The whole history is kept, and its `ConversationMemory` chooses which part of it is sent with every message.
 #[derive(Serialize, Deserialize, Default)]
 pub struct Chain {
     state: ChatMessageCollection<String>,
+    #[serde(skip)]
+    memory: Memory,
+    /// Streamed exchanges not given to `ConversationMemory::save_exchange` yet, which can't be awaited on drop.
+    #[serde(skip)]
+    unsaved_exchanges: Vec<(ChatMessageCollection<String>, ChatMessageCollection<String>)>,
+}
+
+/// The memory of a conversation, which defaults to a `TokenWindowMemory`.
+struct Memory(Box<dyn ConversationMemory>);
+
+impl Default for Memory {
+    fn default() -> Self {
+        Memory(Box::new(TokenWindowMemory::new()))
+    }
 }
 
 impl Chain {
@@ -29,7 +70,10 @@ impl Chain {
         state
             .format(&parameters!())
             .map(|state| state.to_chat())
-            .map(|state| Self { state })
+            .map(|state| Self {
+                state,
+                ..Default::default()
+            })
     }
 
     /// Constructs a new `Chain` with the given conversation state by passing a ChatMessageCollection<String> (clone).
@@ -39,7 +83,115 @@ impl Chain {
     pub fn new_with_message_collection(state: &ChatMessageCollection<String>) -> Chain {
         Self {
             state: state.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// Sets the memory choosing which messages of the history are sent with every new message. Defaults to a
+    /// `TokenWindowMemory`, sending as many of the newest messages as fit in the context window.
+    pub fn with_memory<M: ConversationMemory + 'static>(mut self, memory: M) -> Chain {
+        self.memory = Memory(Box::new(memory));
+        self
+    }
+
+    /// Replaces the messages of the conversation, keeping its memory. This is how stored conversations are restored.
+    pub fn with_history(mut self, history: ChatMessageCollection<String>) -> Chain {
+        self.state = history;
+        self
+    }
+
+    /// Returns the messages of the conversation so far.
+    pub fn history(&self) -> &ChatMessageCollection<String> {
+        &self.state
+    }
+
+    /// Returns the state of the memory to store along with the history, see `ConversationMemory::state`.
+    pub fn memory_state(&self) -> Option<serde_json::Value> {
+        self.memory.0.state()
+    }
+
+    /// Restores the state of the memory returned by `memory_state`, for a conversation restored with
+    /// `with_history`.
+    pub fn restore_memory_state(&mut self, state: serde_json::Value) -> Result<(), MemoryError> {
+        self.memory.0.restore_state(state)
+    }
+
+    /// Adds a message to the conversation without sending anything to the LLM.
+    ///
+    /// This is useful to record exchanges that were handled outside of the chain.
+    pub fn add_message(&mut self, message: ChatMessage<String>) {
+        self.state.add_message(message);
+    }
+
+    /// Replaces the message at `index` with one of the same role with the given body, and removes every message
+    /// after it. Call `regenerate` afterwards to get a new answer to an edited user message.
+    pub fn edit_message(&mut self, index: usize, body: String) -> Result<(), Error> {
+        let role = match self.state.get_message(index) {
+            Some(message) => message.role().clone(),
+            None => {
+                return Err(Error::MessageIndexOutOfRange {
+                    index,
+                    len: self.state.len(),
+                })
+            }
+        };
+        self.cut_history(index);
+        self.state.add_message(ChatMessage::new(role, body));
+        Ok(())
+    }
+
+    /// Keeps the first `len` messages of the conversation and removes the rest.
+    pub fn truncate(&mut self, len: usize) {
+        self.cut_history(len);
+    }
+
+    /// Removes the messages after the first `len`, and tells the memory, so it drops what it kept of them.
+    fn cut_history(&mut self, len: usize) {
+        self.state.truncate(len);
+        self.memory.0.history_truncated(&self.state);
+    }
+
+    /// Starts an independent conversation with the first `len` messages of this one.
+    ///
+    /// Memories can't be copied, so the new conversation has the default memory. Use `with_memory` to set another.
+    pub fn fork(&self, len: usize) -> Chain {
+        let mut history = self.state.clone();
+        history.truncate(len);
+        Chain::new_with_message_collection(&history)
+    }
+
+    /// Sends the last user message again and returns the new answer, replacing everything that followed it.
+    ///
+    /// If sending fails, the conversation is left as it was.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Error::NoUserMessage` if the conversation has no user message to answer.
+    pub async fn regenerate<E: Executor>(
+        &mut self,
+        options: &Options,
+        exec: &E,
+    ) -> Result<Output, Error> {
+        let index = self
+            .state
+            .iter()
+            .rposition(|message| message.role() == &ChatRole::User)
+            .ok_or(Error::NoUserMessage)?;
+        let prompt = self
+            .state
+            .get_message(index)
+            .cloned()
+            .ok_or(Error::NoUserMessage)?;
+        let replaced: Vec<_> = self.state.iter().skip(index).cloned().collect();
+        self.cut_history(index);
+        let prompt = Prompt::Chat(ChatMessageCollection::for_vector(vec![prompt]));
+        let output = self.send_message_raw(options, &prompt, exec).await;
+        if output.is_err() {
+            self.state.truncate(index);
+            self.state
+                .append(ChatMessageCollection::for_vector(replaced));
         }
+        output
     }
 
     /// Sends a message to the LLM and returns the response.
@@ -80,23 +232,253 @@ impl Chain {
         prompt: &Prompt,
         exec: &E,
     ) -> Result<Output, Error> {
-        let tok = exec.tokens_used(options, prompt)?;
-        let tokens_remaining = tok.tokens_remaining();
-        let tokenizer = exec.get_tokenizer(options)?;
-        self.state.trim_context(&tokenizer, tokens_remaining)?;
-
-        // Combine the conversation history with the new prompt.
-        let prompt_with_history = Prompt::Chat(self.state.clone()).combine(prompt);
+        let prompt_with_history = self.prompt_with_history(options, prompt, exec).await?;
 
         // Execute the prompt and retrieve the LLM's response.
         let res = exec.execute(options, &prompt_with_history).await?;
-        let content = res.to_immediate().await?.as_content().to_chat();
+        // Text completions are answers too, so they're recorded as assistant messages.
+        let content = match res.to_immediate().await?.as_content() {
+            Data::Text(text) => ChatMessageCollection::new().with_assistant(text),
+            Data::Chat(chat) => chat,
+        };
 
-        self.state = prompt_with_history.to_chat();
+        self.state.append(prompt.to_chat());
         self.state.append(content.clone());
+        self.memory
+            .0
+            .save_exchange(&prompt.to_chat(), &content)
+            .await?;
 
         Ok(Output::new_immediate(content.into()))
     }
+
+    /// Sends a message to the LLM and streams the response.
+    ///
+    /// The prompt and the answer are added to the conversation once the returned stream ends, or when it's dropped
+    /// with whatever part of the answer was received. Should the stream fail, neither is added. Set the `Stream`
+    /// option of the step to get the answer token by token from executors supporting it; other executors stream
+    /// the whole answer at once.
+    pub async fn stream_message<E: Executor>(
+        &mut self,
+        step: Step,
+        parameters: &Parameters,
+        exec: &E,
+    ) -> Result<ConversationStream<'_>, Error> {
+        let fmt = step.format(parameters)?;
+        self.stream_message_raw(step.options(), &fmt, exec).await
+    }
+
+    /// Sends a ready prompt to the LLM and streams the response, like `stream_message`.
+    pub async fn stream_message_raw<E: Executor>(
+        &mut self,
+        options: &Options,
+        prompt: &Prompt,
+        exec: &E,
+    ) -> Result<ConversationStream<'_>, Error> {
+        let prompt_with_history = self.prompt_with_history(options, prompt, exec).await?;
+        let stream = match exec.execute(options, &prompt_with_history).await? {
+            Output::Stream(stream) => stream,
+            Output::Immediate(immediate) => {
+                let segments = match immediate.as_content() {
+                    Data::Text(text) => vec![StreamSegment::Content(text)],
+                    Data::Chat(chat) => chat
+                        .iter()
+                        .flat_map(|message| {
+                            [
+                                StreamSegment::Role(message.role().clone()),
+                                StreamSegment::Content(message.body().clone()),
+                            ]
+                        })
+                        .collect(),
+                };
+                OutputStream::from_stream(futures::stream::iter(segments))
+            }
+        };
+        Ok(ConversationStream {
+            chain: self,
+            stream,
+            input: prompt.to_chat(),
+            answer: vec![],
+            role: None,
+            error: None,
+            recorded: false,
+        })
+    }
+
+    /// Hands the streamed exchanges the memory hasn't seen yet to it.
+    ///
+    /// This happens when the next message is sent, but a chain used for a single streamed message, as in a
+    /// request handler, has to be flushed before it's dropped for the memory to see the exchange.
+    /// `Session::save` does this.
+    pub async fn flush_memory(&mut self) -> Result<(), MemoryError> {
+        for (input, output) in std::mem::take(&mut self.unsaved_exchanges) {
+            self.memory.0.save_exchange(&input, &output).await?;
+        }
+        Ok(())
+    }
+
+    /// Prepares the memory for the prompt, and returns the prompt preceded by the messages the memory chooses.
+    async fn prompt_with_history<E: Executor>(
+        &mut self,
+        options: &Options,
+        prompt: &Prompt,
+        exec: &E,
+    ) -> Result<Prompt, Error> {
+        self.flush_memory().await?;
+        let tok = exec.tokens_used(options, prompt)?;
+        let tokens_remaining = tok.tokens_remaining().max(0) as usize;
+        self.memory.0.prepare(&self.state, prompt).await?;
+        self.update_summary(options, exec).await?;
+        // The tokenizer isn't necessarily `Send`, so it's dropped before anything is awaited.
+        let context = {
+            let tokenizer = exec.get_tokenizer(options)?;
+            self.memory
+                .0
+                .context(&self.state, &tokenizer, tokens_remaining)?
+        };
+
+        // Combine the messages chosen by the memory with the new prompt.
+        Ok(Prompt::Chat(context).combine(prompt))
+    }
+
+    /// Brings the summary of the memory up to date, if it keeps one.
+    async fn update_summary<E: Executor>(
+        &mut self,
+        options: &Options,
+        exec: &E,
+    ) -> Result<(), Error> {
+        let request = {
+            let tokenizer = exec.get_tokenizer(options)?;
+            self.memory.0.summary_request(&self.state, &tokenizer)?
+        };
+        let Some(request) = request else {
+            return Ok(());
+        };
+        let prompt = request.step.format(&request.parameters)?;
+        let summary = exec
+            .execute(request.step.options(), &prompt)
+            .await?
+            .to_immediate()
+            .await?
+            .primary_textual_output()
+            .ok_or(Error::NoModelOutput)?;
+        self.memory.0.update_summary(summary, request.summarized);
+        Ok(())
+    }
+}
+
+/// The streamed answer of a conversation, as returned by `Chain::stream_message`.
+///
+/// It yields the segments of the answer as they arrive. Once it ends, or when it's dropped before that, the prompt
+/// and the part of the answer received so far are added to the conversation, unless the stream yielded an error.
+/// The memory of the conversation sees the exchange when the next message is sent or the memory is flushed, see
+/// `Chain::flush_memory`.
+pub struct ConversationStream<'a> {
+    chain: &'a mut Chain,
+    stream: OutputStream,
+    input: ChatMessageCollection<String>,
+    /// The messages received so far, as roles and bodies, since bodies arrive in pieces.
+    answer: Vec<(ChatRole, String)>,
+    /// The role of the next message, if the stream announced one.
+    role: Option<ChatRole>,
+    /// The error the stream failed with, if it did.
+    error: Option<String>,
+    recorded: bool,
+}
+
+impl ConversationStream<'_> {
+    /// Returns the part of the answer received so far.
+    pub fn answer(&self) -> ChatMessageCollection<String> {
+        ChatMessageCollection::for_vector(
+            self.answer
+                .iter()
+                .map(|(role, body)| ChatMessage::new(role.clone(), body.clone()))
+                .collect(),
+        )
+    }
+
+    /// Returns the error the stream failed with, if it did, in which case nothing is added to the conversation.
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    /// Receives the rest of the answer and adds the exchange to the conversation, returning the whole answer.
+    ///
+    /// Returns `Error::Stream` if the stream failed, now or before.
+    pub async fn finish(mut self) -> Result<ChatMessageCollection<String>, Error> {
+        while let Some(segment) = self.stream.next().await {
+            self.receive(&segment);
+        }
+        if let Some(error) = &self.error {
+            return Err(Error::Stream(error.clone()));
+        }
+        self.record();
+        Ok(self.answer())
+    }
+
+    fn receive(&mut self, segment: &StreamSegment) {
+        match segment {
+            StreamSegment::Role(role) => self.role = Some(role.clone()),
+            StreamSegment::Content(content) => match (self.role.take(), self.answer.last_mut()) {
+                (None, Some((_, body))) => body.push_str(content),
+                (role, _) => self
+                    .answer
+                    .push((role.unwrap_or(ChatRole::Assistant), content.clone())),
+            },
+            StreamSegment::Err(err) => {
+                self.error.get_or_insert_with(|| err.to_string());
+            }
+        }
+    }
+
+    /// Adds the prompt and the answer received so far to the conversation, once.
+    fn record(&mut self) {
+        if std::mem::replace(&mut self.recorded, true)
+            || self.answer.is_empty()
+            || self.error.is_some()
+        {
+            return;
+        }
+        let answer = self.answer();
+        self.chain.state.append(self.input.clone());
+        self.chain.state.append(answer.clone());
+        self.chain
+            .unsaved_exchanges
+            .push((self.input.clone(), answer));
+    }
+}
+
+impl Stream for ConversationStream<'_> {
+    type Item = StreamSegment;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.stream).poll_next(cx) {
+            Poll::Ready(Some(segment)) => {
+                self.receive(&segment);
+                Poll::Ready(Some(segment))
+            }
+            Poll::Ready(None) => {
+                self.record();
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for ConversationStream<'_> {
+    fn drop(&mut self) {
+        self.record();
+    }
+}
+
+impl StorableEntity for Chain {
+    fn get_metadata() -> Vec<(String, String)> {
+        vec![(
+            "chain-type".to_string(),
+            "llm-chain::chains::conversation::Chain".to_string(),
+        )]
+    }
 }
 
 /// An error type representing various errors that can occur while interacting with the `Chain`.
@@ -112,4 +494,353 @@ pub enum Error {
     NoModelOutput,
     #[error("StringTemplateError: {0}")]
     StringTemplate(#[from] crate::prompt::StringTemplateError),
+    #[error("MemoryError: {0}")]
+    Memory(#[from] MemoryError),
+    #[error("The streamed answer failed: {0}")]
+    Stream(String),
+    #[error("The conversation has no user message")]
+    NoUserMessage,
+    #[error("No message at index {index}, the conversation has {len} messages")]
+    MessageIndexOutOfRange { index: usize, len: usize },
+}
+
+/// The id of a message in a `ConversationTree`.
+pub type MessageId = usize;
+
+/// A message of a `ConversationTree`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreeNode {
+    pub message: ChatMessage<String>,
+    /// The message this one answers or follows, or `None` for the first messages of conversations.
+    pub parent: Option<MessageId>,
+}
+
+/// The branches of a conversation, as a tree of messages where branches sharing a prefix share its messages.
+///
+/// Every message is identified by a `MessageId`, and the conversation ending at a message is the path from the
+/// root of the tree to it, which `checkout` turns back into a `Chain`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConversationTree {
+    nodes: Vec<TreeNode>,
+}
+
+impl ConversationTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a conversation to the tree, reusing the messages it shares with the branches already in it, and
+    /// returns the id of its last message, or `None` if it's empty.
+    pub fn insert(&mut self, history: &ChatMessageCollection<String>) -> Option<MessageId> {
+        let mut parent = None;
+        for message in history.iter() {
+            let existing = self.children(parent).into_iter().find(|&id| {
+                let node = &self.nodes[id].message;
+                node.role() == message.role() && node.body() == message.body()
+            });
+            parent = Some(existing.unwrap_or_else(|| {
+                self.nodes.push(TreeNode {
+                    message: message.clone(),
+                    parent,
+                });
+                self.nodes.len() - 1
+            }));
+        }
+        parent
+    }
+
+    /// Returns the message with the given id.
+    pub fn get(&self, id: MessageId) -> Option<&TreeNode> {
+        self.nodes.get(id)
+    }
+
+    /// Returns the messages following `parent`, in the order they were added, or the first messages of the
+    /// conversations if `parent` is `None`.
+    pub fn children(&self, parent: Option<MessageId>) -> Vec<MessageId> {
+        (0..self.nodes.len())
+            .filter(|&id| self.nodes[id].parent == parent)
+            .collect()
+    }
+
+    /// Returns the last messages of every branch.
+    pub fn leaves(&self) -> Vec<MessageId> {
+        (0..self.nodes.len())
+            .filter(|&id| !self.nodes.iter().any(|node| node.parent == Some(id)))
+            .collect()
+    }
+
+    /// Returns the messages of the branch ending at `id`, from the first one to `id` itself.
+    pub fn history(&self, id: MessageId) -> ChatMessageCollection<String> {
+        let mut messages = vec![];
+        let mut current = self.nodes.get(id);
+        while let Some(node) = current {
+            messages.push(node.message.clone());
+            current = node.parent.and_then(|parent| self.nodes.get(parent));
+        }
+        messages.reverse();
+        ChatMessageCollection::for_vector(messages)
+    }
+
+    /// Creates a conversation continuing the branch ending at `id`, with the default memory.
+    pub fn checkout(&self, id: MessageId) -> Chain {
+        Chain::new_with_message_collection(&self.history(id))
+    }
+
+    /// Returns the number of messages in the tree.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chain, ConversationStream, ConversationTree, Error};
+    use crate::memory::SummaryMemory;
+    use crate::options::Options;
+    use crate::prompt::ChatMessageCollection;
+    use crate::testing::{RecordingMemory, ScriptedExecutor};
+    use crate::{options, prompt, step::Step, Parameters};
+
+    fn bodies(history: &ChatMessageCollection<String>) -> Vec<&str> {
+        history.iter().map(|m| m.body().as_str()).collect()
+    }
+
+    async fn say(chain: &mut Chain, text: &str, executor: &ScriptedExecutor) {
+        let step = Step::for_prompt_template(prompt!(user: "{{text}}"));
+        chain
+            .send_message(step, &Parameters::new_with_text(text), executor)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sends_the_summary_instead_of_the_history() {
+        let executor = ScriptedExecutor::new(|prompt| {
+            if prompt.contains("New summary:") {
+                "The user greeted".to_string()
+            } else {
+                prompt.to_string()
+            }
+        });
+        let mut chain = Chain::default().with_memory(SummaryMemory::new());
+        let step = || Step::for_prompt_template(prompt!(user: "{{text}}"));
+        chain
+            .send_message(step(), &Parameters::new_with_text("Hello"), &executor)
+            .await
+            .unwrap();
+        let sent = chain
+            .send_message(step(), &Parameters::new_with_text("Bye"), &executor)
+            .await
+            .unwrap()
+            .to_immediate()
+            .await
+            .unwrap()
+            .primary_textual_output()
+            .unwrap();
+
+        assert!(sent.contains("The user greeted"));
+        assert!(!sent.contains("Hello"));
+        // The whole conversation is still kept.
+        assert_eq!(chain.history().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_regenerates_and_edits_messages() {
+        let answers = std::sync::atomic::AtomicUsize::new(0);
+        let executor = ScriptedExecutor::new(move |_| {
+            format!(
+                "answer {}",
+                answers.fetch_add(1, std::sync::atomic::Ordering::SeqCst)
+            )
+        });
+        let mut chain = Chain::new(prompt!(system: "Be brief")).unwrap();
+        assert!(matches!(
+            chain.regenerate(Options::empty(), &executor).await,
+            Err(Error::NoUserMessage)
+        ));
+        say(&mut chain, "Hi", &executor).await;
+        say(&mut chain, "Capital of Sweden?", &executor).await;
+
+        chain.regenerate(Options::empty(), &executor).await.unwrap();
+        assert_eq!(
+            bodies(chain.history()),
+            vec![
+                "Be brief",
+                "Hi",
+                "answer 0",
+                "Capital of Sweden?",
+                "answer 2"
+            ]
+        );
+
+        chain.edit_message(1, "Hello".to_string()).unwrap();
+        assert_eq!(bodies(chain.history()), vec!["Be brief", "Hello"]);
+        chain.regenerate(Options::empty(), &executor).await.unwrap();
+        assert_eq!(
+            bodies(chain.history()),
+            vec!["Be brief", "Hello", "answer 3"]
+        );
+        assert!(matches!(
+            chain.edit_message(9, String::new()),
+            Err(Error::MessageIndexOutOfRange { index: 9, len: 3 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_failed_regenerations_keep_the_history() {
+        let calls = std::sync::atomic::AtomicUsize::new(0);
+        let executor = ScriptedExecutor::fallible(move |_| {
+            match calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => Ok("Stockholm".to_string()),
+                _ => Err("the model is down".to_string()),
+            }
+        });
+        let mut chain = Chain::new(prompt!(system: "Be brief")).unwrap();
+        say(&mut chain, "Capital of Sweden?", &executor).await;
+
+        assert!(chain.regenerate(Options::empty(), &executor).await.is_err());
+        assert_eq!(
+            bodies(chain.history()),
+            vec!["Be brief", "Capital of Sweden?", "Stockholm"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_edits_drop_the_summary_of_removed_messages() {
+        let summaries = std::sync::atomic::AtomicUsize::new(0);
+        let prompts = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = prompts.clone();
+        let executor = ScriptedExecutor::new(move |prompt| {
+            if prompt.contains("New summary:") {
+                let n = summaries.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                return format!("Summary {}", n + 1);
+            }
+            recorded.lock().unwrap().push(prompt.to_string());
+            "ok".to_string()
+        });
+        let mut chain = Chain::new(prompt!(system: "Be brief"))
+            .unwrap()
+            .with_memory(SummaryMemory::new());
+        say(&mut chain, "My dog is Rex", &executor).await;
+        say(&mut chain, "What is his name?", &executor).await;
+        assert!(prompts.lock().unwrap()[1].contains("Summary 1"));
+
+        // The summary covered two messages, which the edited conversation has again by its next message.
+        chain.edit_message(1, "My cat is Tom".to_string()).unwrap();
+        chain.regenerate(Options::empty(), &executor).await.unwrap();
+        say(&mut chain, "What is her name?", &executor).await;
+
+        let prompts = prompts.lock().unwrap();
+        let last = prompts.last().unwrap();
+        assert!(!last.contains("Summary 1"));
+        assert!(last.contains("Summary 2"));
+    }
+
+    #[tokio::test]
+    async fn test_tree_shares_the_prefix_of_branches() {
+        let executor = ScriptedExecutor::new(|prompt| format!("re: {}", prompt.lines().count()));
+        let mut chain = Chain::new(prompt!(system: "Be brief")).unwrap();
+        say(&mut chain, "Hi", &executor).await;
+        let mut branch = chain.fork(3);
+        say(&mut chain, "Weather?", &executor).await;
+        say(&mut branch, "News?", &executor).await;
+
+        let mut tree = ConversationTree::new();
+        let first = tree.insert(chain.history()).unwrap();
+        let second = tree.insert(branch.history()).unwrap();
+        // The system prompt, "Hi" and its answer are shared.
+        assert_eq!(tree.len(), 7);
+        assert_eq!(tree.leaves(), vec![first, second]);
+
+        let tree: ConversationTree =
+            serde_json::from_str(&serde_json::to_string(&tree).unwrap()).unwrap();
+        assert_eq!(
+            bodies(tree.checkout(second).history()),
+            vec!["Be brief", "Hi", "re: 2", "News?", "re: 4"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_streams_and_records_the_answer() {
+        use crate::output::{StreamExt, StreamSegment};
+
+        let executor = ScriptedExecutor::new(|_| "one two three".to_string());
+        let step =
+            || Step::for_prompt_and_options(prompt!(user: "{{text}}"), options!(Stream: true));
+        let mut chain = Chain::new(prompt!(system: "Be brief")).unwrap();
+        let stream = chain
+            .stream_message(step(), &Parameters::new_with_text("Count"), &executor)
+            .await
+            .unwrap();
+        let pieces: Vec<_> = stream
+            .map(|segment| match segment {
+                StreamSegment::Content(content) => content,
+                other => panic!("unexpected segment {}", other),
+            })
+            .collect()
+            .await;
+        assert_eq!(pieces, vec!["one ", "two ", "three"]);
+        assert_eq!(
+            bodies(chain.history()),
+            vec!["Be brief", "Count", "one two three"]
+        );
+
+        // Dropping the stream early keeps the part of the answer received so far.
+        let mut stream = chain
+            .stream_message(step(), &Parameters::new_with_text("Again"), &executor)
+            .await
+            .unwrap();
+        stream.next().await.unwrap();
+        drop(stream);
+        assert_eq!(bodies(chain.history())[3..], ["Again", "one "]);
+    }
+
+    #[tokio::test]
+    async fn test_finishes_streams_and_flushes_the_exchange_to_memory() {
+        let executor = ScriptedExecutor::new(|_| "one two three".to_string());
+        let memory = RecordingMemory::default();
+        let mut chain = Chain::new(prompt!(system: "Be brief"))
+            .unwrap()
+            .with_memory(memory.clone());
+        let step = Step::for_prompt_and_options(prompt!(user: "{{text}}"), options!(Stream: true));
+        let answer = chain
+            .stream_message(step, &Parameters::new_with_text("Count"), &executor)
+            .await
+            .unwrap()
+            .finish()
+            .await
+            .unwrap();
+        assert_eq!(bodies(&answer), vec!["one two three"]);
+        assert!(memory.exchanges().is_empty());
+
+        chain.flush_memory().await.unwrap();
+        assert_eq!(memory.exchanges(), vec!["Count -> one two three"]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_streams_are_not_recorded() {
+        use crate::output::{OutputStream, StreamSegment};
+        use crate::traits::ExecutorError;
+
+        let mut chain = Chain::new(prompt!(system: "Be brief")).unwrap();
+        let segments = vec![
+            StreamSegment::Content("one ".to_string()),
+            StreamSegment::Err(ExecutorError::ContextTooSmall),
+        ];
+        let stream = ConversationStream {
+            chain: &mut chain,
+            stream: OutputStream::from_stream(futures::stream::iter(segments)),
+            input: ChatMessageCollection::new().with_user("Count".to_string()),
+            answer: vec![],
+            role: None,
+            error: None,
+            recorded: false,
+        };
+        assert!(matches!(stream.finish().await, Err(Error::Stream(_))));
+        assert_eq!(bodies(chain.history()), vec!["Be brief"]);
+    }
 }
@@ -0,0 +1,301 @@
+//! The `conversational_retrieval` module contains the `Chain` struct, which chats over the documents of a vector
+//! store.
+//!
+//! Follow-up messages often only make sense in the context of the conversation, like "and what about the second
+//! one?", which makes them poor search queries. So every message after the first is rewritten into a standalone
+//! question using the chat history before it is answered by a retrieval QA chain. The conversation itself keeps
+//! the messages as the user wrote them, together with the answers. Only the newest messages that fit in the
+//! context window of the condense step are used, keeping room for the standalone question.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut chain = Chain::new(retrieval_qa::Chain::new(vector_store));
+//! let first = chain.send_message("Which cities host the largest ports in Europe?", &executor).await?;
+//! let second = chain.send_message("And what about the second one?", &executor).await?;
+//! println!("{} -> {}", second.standalone_question, second.answer);
+//! ```
+
+use thiserror::Error;
+
+use super::conversation;
+use super::retrieval_qa::{self, RetrievalQaChainError};
+use crate::callbacks::Callbacks;
+use crate::estimate::assumed_output_tokens;
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::memory::{format_chat_history, split_system_prompt};
+use crate::prompt::{ChatMessage, ChatMessageCollection};
+use crate::schema::{Document, EmptyMetadata};
+use crate::tokens::{PromptTokensError, Tokenizer};
+use crate::traits::{Embeddings, Executor, VectorStore};
+use crate::{prompt, step::Step, Parameters};
+
+const CONDENSE_QUESTION_PROMPT: &str = "Given the following conversation and a follow up question, rephrase the follow up question to be a standalone question, in its original language. Respond only with the standalone question.
+
+Chat History:
+{{chat_history}}
+
+Follow Up Input: {{question}}
+Standalone question:";
+
+/// The `ConversationalRetrievalChainError` enum represents errors that can occur when chatting over documents.
+#[derive(Error, Debug)]
+pub enum ConversationalRetrievalChainError<V>
+where
+    V: std::fmt::Debug + std::error::Error,
+{
+    #[error("RetrievalQaChainError: {0}")]
+    RetrievalQaChainError(#[from] RetrievalQaChainError<V>),
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    #[error("TokenizeError: {0}")]
+    TokenizeError(#[from] PromptTokensError),
+}
+
+/// The answer to a message of the conversation.
+pub struct ConversationalRetrievalOutput<M>
+where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// The question the documents were retrieved for, which is the message itself for the first message.
+    pub standalone_question: String,
+    /// The answer of the model, which was added to the conversation.
+    pub answer: String,
+    /// The documents the model was given.
+    pub source_documents: Vec<Document<M>>,
+    /// The cited documents, if the retrieval QA chain is in citation mode.
+    pub citations: Vec<usize>,
+}
+
+/// A chain answering the messages of a conversation from the documents of a vector store.
+pub struct Chain<E, V, M = EmptyMetadata>
+where
+    E: Embeddings,
+    V: VectorStore<E, M>,
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    retrieval: retrieval_qa::Chain<E, V, M>,
+    conversation: conversation::Chain,
+    condense: Step,
+    callbacks: Callbacks,
+}
+
+impl<E, V, M> Chain<E, V, M>
+where
+    E: Embeddings,
+    V: VectorStore<E, M>,
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Creates a new conversation answered by the given retrieval QA chain, with the default condense prompt.
+    pub fn new(retrieval: retrieval_qa::Chain<E, V, M>) -> Self {
+        Chain {
+            retrieval,
+            conversation: conversation::Chain::default(),
+            condense: Step::for_prompt_template(prompt!(CONDENSE_QUESTION_PROMPT)),
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Replaces the step rewriting follow-up messages into standalone questions.
+    ///
+    /// The step is formatted with the previous messages as `chat_history` and the new message as `question`.
+    pub fn with_condense_step(mut self, condense: Step) -> Self {
+        self.condense = condense;
+        self
+    }
+
+    /// Continues an existing conversation.
+    pub fn with_conversation(mut self, conversation: conversation::Chain) -> Self {
+        self.conversation = conversation;
+        self
+    }
+
+    /// Attaches callbacks to the chain. The condense step is reported as `condense_question`, and the callbacks are
+    /// passed on to the retrieval QA chain.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Self {
+        self.retrieval = self.retrieval.with_callbacks(callbacks.clone());
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// The conversation so far.
+    pub fn conversation(&self) -> &conversation::Chain {
+        &self.conversation
+    }
+
+    /// Answers a message of the conversation using the documents of the vector store, and records the message and
+    /// the answer in the conversation.
+    ///
+    /// Nothing is recorded if answering fails.
+    pub async fn send_message<X: Executor>(
+        &mut self,
+        message: &str,
+        executor: &X,
+    ) -> Result<ConversationalRetrievalOutput<M>, ConversationalRetrievalChainError<V::Error>> {
+        let standalone_question = self.condense_question(message, executor).await?;
+        let answered = self.retrieval.run(&standalone_question, executor).await?;
+
+        self.conversation
+            .add_message(ChatMessage::user(message.to_string()));
+        self.conversation
+            .add_message(ChatMessage::assistant(answered.answer.clone()));
+        Ok(ConversationalRetrievalOutput {
+            standalone_question,
+            answer: answered.answer,
+            source_documents: answered.source_documents,
+            citations: answered.citations,
+        })
+    }
+
+    async fn condense_question<X: Executor>(
+        &self,
+        message: &str,
+        executor: &X,
+    ) -> Result<String, ConversationalRetrievalChainError<V::Error>> {
+        let chat_history = self.chat_history(message, executor)?;
+        if chat_history.is_empty() {
+            return Ok(message.to_string());
+        }
+        let parameters = Parameters::new()
+            .with("chat_history", chat_history)
+            .with("question", message);
+        let question = Frame::new(executor, &self.condense)
+            .with_callbacks(self.callbacks.clone())
+            .with_name("condense_question")
+            .format_and_execute(&parameters)
+            .await?
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .primary_textual_output()
+            .unwrap_or_default();
+        let question = question.trim();
+        // Fall back to the message itself rather than searching for nothing.
+        Ok(if question.is_empty() {
+            message.to_string()
+        } else {
+            question.to_string()
+        })
+    }
+
+    /// Formats the newest messages of the conversation that fit in the context window of the condense step, along
+    /// with the message and the answer.
+    fn chat_history<X: Executor>(
+        &self,
+        message: &str,
+        executor: &X,
+    ) -> Result<String, PromptTokensError> {
+        let (_, rest) = split_system_prompt(self.conversation.history());
+        if rest.is_empty() {
+            return Ok(String::new());
+        }
+        let options = self.condense.options();
+        let prompt = self.condense.format(
+            &Parameters::new()
+                .with("chat_history", "")
+                .with("question", message),
+        )?;
+        let mut remaining = executor
+            .tokens_used(options, &prompt)?
+            .tokens_remaining()
+            .max(0) as usize;
+        remaining = remaining.saturating_sub(assumed_output_tokens(options));
+
+        let tokenizer = executor.get_tokenizer(options)?;
+        let mut start = rest.len();
+        for (index, message) in rest.iter().enumerate().rev() {
+            // Every message takes a line of its own, prefixed with its role.
+            let line = format!("{}: {}\n", message.role(), message.body());
+            let tokens = tokenizer.tokenize_str(&line)?.len();
+            if tokens > remaining {
+                break;
+            }
+            remaining -= tokens;
+            start = index;
+        }
+        Ok(format_chat_history(&ChatMessageCollection::for_vector(
+            rest[start..].to_vec(),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::Chain;
+    use crate::chains::retrieval_qa;
+    use crate::testing::{ScriptedExecutor, WordStore};
+    use crate::traits::VectorStore;
+
+    const FIRST_QUESTION: &str =
+        "Which are the largest ports in Europe, by the tonnage of the cargo they handle?";
+
+    async fn chain() -> Chain<crate::testing::NoEmbeddings, WordStore> {
+        let store = WordStore::default();
+        store
+            .add_texts(vec![
+                "Rotterdam is the largest port in Europe".to_string(),
+                "Antwerp is the second largest port in Europe".to_string(),
+            ])
+            .await
+            .unwrap();
+        Chain::new(retrieval_qa::Chain::new(store))
+    }
+
+    /// Rewrites follow-ups into a fixed standalone question, answers everything else, and records the prompts.
+    fn executor(prompts: Arc<Mutex<Vec<String>>>) -> ScriptedExecutor {
+        ScriptedExecutor::new(move |prompt| {
+            prompts.lock().unwrap().push(prompt.to_string());
+            if prompt.contains("Standalone question:") {
+                "Which is the second largest port in Europe?".to_string()
+            } else {
+                "Rotterdam, then Antwerp.".to_string()
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_searches_for_the_standalone_question() {
+        let prompts = Arc::new(Mutex::new(vec![]));
+        let executor = executor(prompts.clone());
+        let mut chain = chain().await;
+        chain.send_message(FIRST_QUESTION, &executor).await.unwrap();
+        let output = chain
+            .send_message("And the second one?", &executor)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            output.standalone_question,
+            "Which is the second largest port in Europe?"
+        );
+        assert_eq!(output.source_documents.len(), 2);
+        let prompts = prompts.lock().unwrap();
+        assert_eq!(prompts.len(), 3);
+        assert!(prompts[1].contains(&format!(
+            "User: {}\nAssistant: Rotterdam, then Antwerp.",
+            FIRST_QUESTION
+        )));
+        assert!(prompts[1].contains("Follow Up Input: And the second one?"));
+        assert!(prompts[2].contains("Question: Which is the second largest port in Europe?"));
+        assert_eq!(chain.conversation().history().len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_condenses_with_the_newest_messages_that_fit() {
+        let prompts = Arc::new(Mutex::new(vec![]));
+        // Enough for the condense prompt, the answer and the last message, but not the first question as well.
+        let executor = executor(prompts.clone()).with_max_tokens(600);
+        let mut chain = chain().await;
+        chain.send_message(FIRST_QUESTION, &executor).await.unwrap();
+        chain
+            .send_message("And the second one?", &executor)
+            .await
+            .unwrap();
+
+        let prompts = prompts.lock().unwrap();
+        assert!(!prompts[1].contains(FIRST_QUESTION));
+        assert!(prompts[1].contains("Chat History:\nAssistant: Rotterdam, then Antwerp.\n"));
+    }
+}
@@ -0,0 +1,437 @@
+//! A graph chain runs steps as a directed acyclic graph, running independent branches concurrently.
+//!
+//! Every node of the graph is a `Step` or a sequential sub-chain, identified by a unique name. A node declares the
+//! nodes it depends on, and its output is stored in the parameters under its name, so dependent nodes can refer
+//! to it in their prompts. A node starts as soon as all of its dependencies have finished, so nodes that don't
+//! depend on each other run in parallel.
+//!
+//! The graph is validated when it is built: unknown dependencies, duplicate names and cycles are rejected.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let chain = Chain::new(vec![
+//!     Node::step("entities", Step::for_prompt_template(prompt!("List the entities in: {{text}}"))),
+//!     Node::step("summary", Step::for_prompt_template(prompt!("Summarize: {{text}}"))),
+//!     Node::step(
+//!         "report",
+//!         Step::for_prompt_template(prompt!("Write a report on {{summary}} mentioning {{entities}}")),
+//!     )
+//!     .with_dependency("entities")
+//!     .with_dependency("summary"),
+//! ])?;
+//!
+//! let outputs = chain.run(parameters!("your input text here"), &executor).await?;
+//! println!("{}", outputs.get("report").unwrap());
+//! ```
+//!
+//! Like the other chains, graphs can be stored with `StorableEntity`, and definitions can be written by hand in YAML.
+
+use std::collections::{HashMap, HashSet};
+
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+
+use super::sequential::{self, SequentialChainError};
+use crate::callbacks::Callbacks;
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::{serialization::StorableEntity, step::Step, traits::Executor, Parameters};
+
+/// The `GraphChainError` enum represents errors that can occur when building or executing a graph chain.
+#[derive(thiserror::Error, Debug)]
+pub enum GraphChainError {
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    #[error("SequentialChainError: {0}")]
+    SequentialChainError(#[from] SequentialChainError),
+    #[error("The graph has no nodes")]
+    NoNodes,
+    #[error("There is more than one node named `{0}`")]
+    DuplicateNode(String),
+    #[error("Node `{node}` depends on `{dependency}`, which is not a node of the graph")]
+    UnknownDependency { node: String, dependency: String },
+    #[error("The graph contains a cycle between the nodes {0:?}")]
+    Cycle(Vec<String>),
+}
+
+/// What a node of the graph runs.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Step(Step),
+    Sequential(sequential::Chain),
+}
+
+/// A node of the graph, whose output is stored under its name.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Node {
+    name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    depends_on: Vec<String>,
+    #[serde(flatten)]
+    kind: NodeKind,
+}
+
+impl Node {
+    /// Creates a node running a single step.
+    pub fn step<S: Into<String>>(name: S, step: Step) -> Node {
+        Node {
+            name: name.into(),
+            depends_on: vec![],
+            kind: NodeKind::Step(step),
+        }
+    }
+
+    /// Creates a node running a sequential chain, whose output is the output of its last step.
+    pub fn chain<S: Into<String>>(name: S, chain: sequential::Chain) -> Node {
+        Node {
+            name: name.into(),
+            depends_on: vec![],
+            kind: NodeKind::Sequential(chain),
+        }
+    }
+
+    /// Makes the node wait for the output of the node named `dependency`.
+    pub fn with_dependency<S: Into<String>>(mut self, dependency: S) -> Node {
+        self.depends_on.push(dependency.into());
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn dependencies(&self) -> &[String] {
+        &self.depends_on
+    }
+
+    pub fn kind(&self) -> &NodeKind {
+        &self.kind
+    }
+}
+
+/// The serialized form of a graph, which is validated when it is turned into a `Chain`.
+#[derive(Serialize, Deserialize)]
+struct GraphDefinition {
+    nodes: Vec<Node>,
+}
+
+/// A chain running its nodes in dependency order, with independent nodes running concurrently.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "GraphDefinition", into = "GraphDefinition")]
+pub struct Chain {
+    nodes: Vec<Node>,
+    callbacks: Callbacks,
+}
+
+impl Chain {
+    /// Creates a new graph chain from its nodes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the graph is empty, two nodes share a name, a node depends on a node that doesn't exist
+    /// or the dependencies form a cycle.
+    pub fn new(nodes: Vec<Node>) -> Result<Chain, GraphChainError> {
+        validate(&nodes)?;
+        Ok(Chain {
+            nodes,
+            callbacks: Callbacks::default(),
+        })
+    }
+
+    /// Attaches callbacks to the chain, which are notified as every node runs. Nodes are reported under their name.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Executes the graph with the given parameters and executor.
+    ///
+    /// Every node is formatted with the input parameters and the outputs of all nodes finished so far. Execution
+    /// stops at the first node that fails.
+    ///
+    /// # Returns
+    ///
+    /// The input parameters with the output of every node added under its name.
+    pub async fn run<E: Executor>(
+        &self,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<Parameters, GraphChainError> {
+        let mut outputs = parameters;
+        let mut finished: HashSet<&str> = HashSet::new();
+        let mut pending: Vec<&Node> = self.nodes.iter().collect();
+        let mut running = FuturesUnordered::new();
+        loop {
+            let (ready, waiting): (Vec<_>, Vec<_>) = pending.into_iter().partition(|node| {
+                node.depends_on
+                    .iter()
+                    .all(|dependency| finished.contains(dependency.as_str()))
+            });
+            pending = waiting;
+            for node in ready {
+                let parameters = outputs.clone();
+                running.push(async move {
+                    let output = self.run_node(node, &parameters, executor).await;
+                    (node, output)
+                });
+            }
+            let Some((node, output)) = running.next().await else {
+                break;
+            };
+            outputs = outputs.with(node.name.clone(), output?);
+            finished.insert(&node.name);
+        }
+        Ok(outputs)
+    }
+
+    async fn run_node<E: Executor>(
+        &self,
+        node: &Node,
+        parameters: &Parameters,
+        executor: &E,
+    ) -> Result<String, GraphChainError> {
+        let output = match &node.kind {
+            NodeKind::Step(step) => {
+                Frame::new(executor, step)
+                    .with_callbacks(self.callbacks.clone())
+                    .with_name(node.name.clone())
+                    .format_and_execute(parameters)
+                    .await?
+            }
+            NodeKind::Sequential(chain) => {
+                chain
+                    .clone()
+                    .with_callbacks(self.callbacks.clone())
+                    .run(parameters.clone(), executor)
+                    .await?
+            }
+        };
+        Ok(output
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .primary_textual_output()
+            .unwrap_or_default())
+    }
+}
+
+/// Checks that the nodes form a valid graph, using Kahn's algorithm to find cycles.
+fn validate(nodes: &[Node]) -> Result<(), GraphChainError> {
+    if nodes.is_empty() {
+        return Err(GraphChainError::NoNodes);
+    }
+    let mut in_degree: HashMap<&str, usize> = HashMap::new();
+    for node in nodes {
+        if in_degree
+            .insert(&node.name, node.depends_on.len())
+            .is_some()
+        {
+            return Err(GraphChainError::DuplicateNode(node.name.clone()));
+        }
+    }
+    for node in nodes {
+        if let Some(dependency) = node
+            .depends_on
+            .iter()
+            .find(|dependency| !in_degree.contains_key(dependency.as_str()))
+        {
+            return Err(GraphChainError::UnknownDependency {
+                node: node.name.clone(),
+                dependency: dependency.clone(),
+            });
+        }
+    }
+
+    let mut ready: Vec<&str> = nodes
+        .iter()
+        .filter(|node| node.depends_on.is_empty())
+        .map(|node| node.name.as_str())
+        .collect();
+    let mut visited = 0;
+    while let Some(name) = ready.pop() {
+        visited += 1;
+        for node in nodes {
+            for _ in node
+                .depends_on
+                .iter()
+                .filter(|dependency| *dependency == name)
+            {
+                let degree = in_degree.get_mut(node.name.as_str()).unwrap();
+                *degree -= 1;
+                if *degree == 0 {
+                    ready.push(&node.name);
+                }
+            }
+        }
+    }
+    if visited < nodes.len() {
+        let cycle = nodes
+            .iter()
+            .filter(|node| in_degree[node.name.as_str()] > 0)
+            .map(|node| node.name.clone())
+            .collect();
+        return Err(GraphChainError::Cycle(cycle));
+    }
+    Ok(())
+}
+
+impl TryFrom<GraphDefinition> for Chain {
+    type Error = GraphChainError;
+
+    fn try_from(definition: GraphDefinition) -> Result<Self, Self::Error> {
+        Chain::new(definition.nodes)
+    }
+}
+
+impl From<Chain> for GraphDefinition {
+    fn from(chain: Chain) -> Self {
+        GraphDefinition { nodes: chain.nodes }
+    }
+}
+
+impl StorableEntity for Chain {
+    fn get_metadata() -> Vec<(String, String)> {
+        let base = vec![(
+            "chain-type".to_string(),
+            "llm-chain::chains::graph::Chain".to_string(),
+        )];
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chain, GraphChainError, Node};
+    use crate::{
+        chains::sequential, frame::FormatAndExecuteError, prompt, step::Step,
+        testing::ScriptedExecutor, Parameters,
+    };
+
+    fn step(template: &str) -> Step {
+        Step::for_prompt_template(prompt!(template))
+    }
+
+    #[test]
+    fn test_rejects_invalid_graphs() {
+        assert!(matches!(Chain::new(vec![]), Err(GraphChainError::NoNodes)));
+        assert!(matches!(
+            Chain::new(vec![
+                Node::step("a", step("{{text}}")),
+                Node::step("a", step("{{text}}"))
+            ]),
+            Err(GraphChainError::DuplicateNode(name)) if name == "a"
+        ));
+        assert!(matches!(
+            Chain::new(vec![Node::step("a", step("{{b}}")).with_dependency("b")]),
+            Err(GraphChainError::UnknownDependency { dependency, .. }) if dependency == "b"
+        ));
+        assert!(matches!(
+            Chain::new(vec![
+                Node::step("start", step("{{text}}")),
+                Node::step("a", step("{{b}}"))
+                    .with_dependency("start")
+                    .with_dependency("b"),
+                Node::step("b", step("{{a}}")).with_dependency("a"),
+            ]),
+            Err(GraphChainError::Cycle(nodes)) if nodes == vec!["a", "b"]
+        ));
+    }
+
+    fn diamond() -> Chain {
+        Chain::new(vec![
+            Node::step("root", step("root {{text}}")),
+            Node::step("left", step("left {{root}}")).with_dependency("root"),
+            Node::step("right", step("right {{root}}")).with_dependency("root"),
+            Node::step("join", step("join {{left}} / {{right}}"))
+                .with_dependency("left")
+                .with_dependency("right"),
+        ])
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_runs_a_diamond_graph() {
+        let executor = ScriptedExecutor::new(|prompt| prompt.to_uppercase());
+        let outputs = diamond()
+            .run(Parameters::new_with_text("input"), &executor)
+            .await
+            .unwrap();
+
+        assert_eq!(outputs.get("root").unwrap(), "ROOT INPUT");
+        assert_eq!(outputs.get("left").unwrap(), "LEFT ROOT INPUT");
+        assert_eq!(outputs.get("right").unwrap(), "RIGHT ROOT INPUT");
+        assert_eq!(
+            outputs.get("join").unwrap(),
+            "JOIN LEFT ROOT INPUT / RIGHT ROOT INPUT"
+        );
+        assert_eq!(executor.calls(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_stops_at_a_failing_node() {
+        let executor = ScriptedExecutor::fallible(|prompt| {
+            if prompt.starts_with("right") {
+                Err("right failed".to_string())
+            } else {
+                Ok(prompt.to_uppercase())
+            }
+        });
+        let result = diamond()
+            .run(Parameters::new_with_text("input"), &executor)
+            .await;
+
+        assert!(matches!(
+            result,
+            Err(GraphChainError::FormatAndExecuteError(
+                FormatAndExecuteError::Execute(_)
+            ))
+        ));
+        assert!(executor.calls() < 4);
+    }
+
+    #[test]
+    fn test_roundtrips_through_json() {
+        let chain = Chain::new(vec![
+            Node::step("entities", step("List the entities in: {{text}}")),
+            Node::chain(
+                "summary",
+                sequential::Chain::new(vec![step("Summarize: {{text}}")]),
+            ),
+            Node::step("report", step("{{summary}} {{entities}}"))
+                .with_dependency("entities")
+                .with_dependency("summary"),
+        ])
+        .unwrap();
+
+        let json = serde_json::to_string(&chain).unwrap();
+        let chain: Chain = serde_json::from_str(&json).unwrap();
+        assert_eq!(chain.nodes().len(), 3);
+        assert_eq!(chain.nodes()[2].dependencies(), ["entities", "summary"]);
+
+        let cyclic = json.replace(r#"["entities","summary"]"#, r#"["report"]"#);
+        assert!(serde_json::from_str::<Chain>(&cyclic).is_err());
+    }
+
+    #[test]
+    fn test_reads_yaml_definitions() {
+        let yaml = r#"
+nodes:
+  - name: summary
+    step:
+      prompt: { Text: { Tera: "Summarize: {{text}}" } }
+      options: { opts: [] }
+  - name: report
+    depends_on: [summary]
+    step:
+      prompt: { Text: { Tera: "Write a report on {{summary}}" } }
+      options: { opts: [] }
+"#;
+        let chain: Chain = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(chain.nodes()[1].dependencies(), ["summary"]);
+    }
+}
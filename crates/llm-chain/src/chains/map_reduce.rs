@@ -6,16 +6,32 @@
 //!
 //! The `Chain` struct is generic over the type of the `Step` and provides a convenient way
 //! to execute map-reduce operations using a provided `Executor`.
+//!
+//! The outputs of the `map` step are combined in the order of the input documents, and each output can be
+//! labelled with the parameters of its document before it is handed to the `reduce` step:
+//!
+//! ```ignore
+//! let chain = Chain::new(map, reduce)
+//!     .with_document_template(StringTemplate::tera("From {{source}}, part {{chunk}}:\n{{text}}"));
+//! let documents = vec![
+//!     parameters!("text" => monday_log, "source" => "monday.log"),
+//!     parameters!("text" => tuesday_log, "source" => "tuesday.log"),
+//! ];
+//! let output = chain.run(documents, parameters!(), &executor).await?;
+//! ```
 
-use crate::traits::ExecutorError;
+use crate::callbacks::{Callbacks, Event};
+use crate::checkpoint::{CheckpointError, Checkpointer};
+use crate::estimate::{placeholder_output, Estimate, StepEstimate};
 use crate::{
-    frame::Frame, output::Output, prompt::Data, serialization::StorableEntity, step::Step, tokens,
-    tokens::PromptTokensError, traits::Executor, Parameters,
+    frame::Frame, output::Output, prompt::Data, prompt::StringTemplate,
+    serialization::StorableEntity, step::Step, tokens, tokens::PromptTokensError, traits::Executor,
+    Parameters,
 };
-use futures::future::join_all;
-use futures::future::FutureExt;
+use futures::future::{join_all, try_join_all};
 use serde::Deserialize;
 use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use thiserror::Error;
 
@@ -32,6 +48,42 @@ pub enum MapReduceChainError {
     InputEmpty,
     #[error("Error templating: {0}")]
     StringTemplate(#[from] crate::prompt::StringTemplateError),
+    #[error("CheckpointError: {0}")]
+    CheckpointError(#[from] CheckpointError),
+    #[error("All {0} chunks failed in the map step")]
+    AllChunksFailed(usize),
+}
+
+/// What a map-reduce chain does when a step fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FailurePolicy {
+    /// Stop the run at the first failed step.
+    #[default]
+    FailFast,
+    /// Leave out the chunks whose `map` step failed, and report them in `MapReduceOutput::skipped`. A failed
+    /// `reduce` step still stops the run.
+    Skip,
+    /// Retry a failed step up to the given number of times before stopping the run.
+    Retry(usize),
+}
+
+/// A chunk that was left out of a map-reduce run because its `map` step failed.
+#[derive(Debug, Clone)]
+pub struct SkippedInput {
+    /// The index of the input document the chunk comes from.
+    pub document: usize,
+    /// The index of the chunk within the document.
+    pub chunk: usize,
+    /// The error the `map` step failed with.
+    pub error: String,
+}
+
+/// The result of a map-reduce run.
+pub struct MapReduceOutput {
+    /// The output of the last `reduce` step.
+    pub output: Output,
+    /// The chunks left out because of `FailurePolicy::Skip`, in input order.
+    pub skipped: Vec<SkippedInput>,
 }
 
 /// The `Chain` struct represents a map-reduce chain, consisting of a `map` step and a `reduce` step.
@@ -42,6 +94,19 @@ pub enum MapReduceChainError {
 pub struct Chain {
     map: Step,
     reduce: Step,
+    #[serde(skip)]
+    callbacks: Callbacks,
+    #[serde(skip)]
+    checkpoints: Option<Checkpointer>,
+    #[serde(default)]
+    failure_policy: FailurePolicy,
+    #[serde(default = "default_document_template")]
+    document_template: StringTemplate,
+}
+
+/// The default template the `map` outputs are formatted with, which is only the output itself.
+fn default_document_template() -> StringTemplate {
+    StringTemplate::tera("{{text}}")
 }
 
 impl Chain {
@@ -49,7 +114,44 @@ impl Chain {
     ///
     /// The `new` function takes two instances of `Step` and returns a new `Chain` instance.
     pub fn new(map: Step, reduce: Step) -> Chain {
-        Chain { map, reduce }
+        Chain {
+            map,
+            reduce,
+            callbacks: Callbacks::default(),
+            checkpoints: None,
+            failure_policy: FailurePolicy::default(),
+            document_template: default_document_template(),
+        }
+    }
+
+    /// Attaches callbacks to the chain, which are notified as every `map` and `reduce` step runs.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Saves the output of every `map` and `reduce` step with the given checkpointer, and reuses the outputs
+    /// saved by an earlier run with the same run id instead of executing those steps again.
+    pub fn with_checkpoints(mut self, checkpoints: Checkpointer) -> Chain {
+        self.checkpoints = Some(checkpoints);
+        self
+    }
+
+    /// Sets what happens when a `map` or `reduce` step fails. Defaults to `FailurePolicy::FailFast`.
+    pub fn with_failure_policy(mut self, failure_policy: FailurePolicy) -> Chain {
+        self.failure_policy = failure_policy;
+        self
+    }
+
+    /// Replaces the template every `map` output is formatted with before the outputs are combined into the input
+    /// of the first `reduce` round, which lets the `reduce` step tell the documents apart.
+    ///
+    /// The template is formatted with the base parameters and the parameters of the document the chunk comes
+    /// from, e.x. `{{source}}`, the output of the `map` step as `text`, and the position of the document and of
+    /// the chunk within it, starting at 1, as `document` and `chunk`.
+    pub fn with_document_template(mut self, document_template: StringTemplate) -> Chain {
+        self.document_template = document_template;
+        self
     }
 
     /// Executes the map-reduce chain using the provided `Executor`.
@@ -65,103 +167,311 @@ impl Chain {
         base_parameters: Parameters,
         executor: &E,
     ) -> Result<Output, MapReduceChainError> {
+        self.run_with_report(documents, base_parameters, executor)
+            .await
+            .map(|output| output.output)
+    }
+
+    /// Executes the map-reduce chain like `run`, also returning the chunks that were skipped because of the
+    /// failure policy.
+    ///
+    /// Progress is reported to the callbacks with `Event::Progress`, for the `map` stage and every reduce round,
+    /// named `reduce 1`, `reduce 2` and so on.
+    pub async fn run_with_report<E: Executor>(
+        &self,
+        documents: Vec<Parameters>,
+        base_parameters: Parameters,
+        executor: &E,
+    ) -> Result<MapReduceOutput, MapReduceChainError> {
+        let output = self.try_run(documents, base_parameters, executor).await;
+        // Errors of the `map` and `reduce` steps themselves have already been reported by their frames.
+        if let Err(err) = &output {
+            if !matches!(err, MapReduceChainError::FormatAndExecuteError(_)) {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+            }
+        }
+        output
+    }
+
+    async fn try_run<E: Executor>(
+        &self,
+        documents: Vec<Parameters>,
+        base_parameters: Parameters,
+        executor: &E,
+    ) -> Result<MapReduceOutput, MapReduceChainError> {
         if documents.is_empty() {
             return Err(MapReduceChainError::InputEmpty);
         }
-        let map_frame = Frame::new(executor, &self.map);
-        let reduce_frame = Frame::new(executor, &self.reduce);
+        let map_frame = Frame::new(executor, &self.map)
+            .with_callbacks(self.callbacks.clone())
+            .with_name("map");
+        let reduce_frame = Frame::new(executor, &self.reduce)
+            .with_callbacks(self.callbacks.clone())
+            .with_name("reduce");
 
-        let chunked_docs = self.chunk_documents(
-            documents.clone(),
-            base_parameters.clone(),
-            executor,
-            &self.map,
-        )?;
+        let chunked_docs =
+            self.chunk_documents(&documents, base_parameters.clone(), executor, &self.map)?;
 
-        // Execute the `map` step for each document, combining the base parameters with each document's parameters.
+        // Execute the `map` step for each chunk, combining the base parameters with the parameters of the chunk's
+        // document, so the `map` step can refer to its metadata as well.
         let chunked_docs_with_base_parameters: Vec<_> = chunked_docs
             .iter()
-            .map(|doc| base_parameters.combine(doc))
+            .map(|(document, _, chunk)| {
+                base_parameters
+                    .combine(&documents[*document])
+                    .combine(chunk)
+            })
             .collect();
-        let mapped_documents: Vec<_> = join_all(
-            chunked_docs_with_base_parameters
-                .iter()
-                .map(|doc| map_frame.format_and_execute(doc))
-                .collect::<Vec<_>>(),
-        )
-        .await;
-        let mapped_documents = mapped_documents
-            .into_iter()
-            .collect::<Result<Vec<Output>, _>>()?;
-        let mapped_documents: Vec<Result<Data<String>, ExecutorError>> = join_all(
-            mapped_documents
-                .into_iter()
-                .map(|x| x.to_immediate().map(|x| x.map(|y| y.as_content())))
-                .collect::<Vec<_>>(),
-        )
-        .await;
-        let mapped_documents: Vec<Data<String>> = mapped_documents
-            .into_iter()
-            .collect::<Result<Vec<Data<String>>, ExecutorError>>()
-            .map_err(|e| {
-                MapReduceChainError::FormatAndExecuteError(
-                    crate::frame::FormatAndExecuteError::Execute(e),
-                )
-            })?;
-
-        let mut documents = self
-            .combine_documents_up_to(executor, mapped_documents, &base_parameters)
+        let mapped = self
+            .execute_all(
+                &map_frame,
+                &self.map,
+                "map",
+                &chunked_docs_with_base_parameters,
+            )
             .await?;
+        let mut mapped_documents = vec![];
+        let mut skipped = vec![];
+        for ((document, chunk, _), result) in chunked_docs.iter().zip(mapped) {
+            match result {
+                Ok(output) => {
+                    let parameters = base_parameters
+                        .combine(&documents[*document])
+                        .with("document", (document + 1).to_string())
+                        .with("chunk", (chunk + 1).to_string())
+                        .with_text(output.extract_last_body().cloned().unwrap_or_default());
+                    mapped_documents.push(self.document_template.format(&parameters)?);
+                }
+                Err(err) => skipped.push(SkippedInput {
+                    document: *document,
+                    chunk: *chunk,
+                    error: err.to_string(),
+                }),
+            }
+        }
+        if mapped_documents.is_empty() {
+            return Err(MapReduceChainError::AllChunksFailed(skipped.len()));
+        }
+
+        let mut documents =
+            self.combine_documents_up_to(executor, mapped_documents, &base_parameters)?;
 
         if documents.is_empty() {
             return Err(MapReduceChainError::InputEmpty);
         }
 
+        let mut round = 0;
         loop {
+            round += 1;
             let tasks: Vec<_> = documents
                 .iter()
                 .map(|doc| base_parameters.with_text(doc))
                 .collect();
-            let futures = tasks.iter().map(|p| reduce_frame.format_and_execute(p));
-            let new_docs = join_all(futures).await;
-            let new_docs = new_docs.into_iter().collect::<Result<Vec<_>, _>>()?;
-            let new_docs = join_all(
-                new_docs
-                    .into_iter()
-                    .map(|x| x.to_immediate().map(|x| x.map(|y| y.as_content()))),
-            )
-            .await;
-            let new_docs = new_docs
+            let stage = format!("reduce {}", round);
+            // Skipping part of a reduce round would silently drop every chunk it covers, so reduce steps
+            // always fail once their retries are used up.
+            let mut new_docs = self
+                .execute_all(&reduce_frame, &self.reduce, &stage, &tasks)
+                .await?
                 .into_iter()
-                .collect::<Result<Vec<Data<String>>, ExecutorError>>()
-                .map_err(|e| {
-                    MapReduceChainError::FormatAndExecuteError(
-                        crate::frame::FormatAndExecuteError::Execute(e),
-                    )
-                })?;
-            let n_new_docs = new_docs.len();
-            if n_new_docs == 1 {
-                return Ok(Output::new_immediate(new_docs[0].clone()));
+                .collect::<Result<Vec<Data<String>>, _>>()?;
+            if new_docs.len() == 1 {
+                return Ok(MapReduceOutput {
+                    output: Output::new_immediate(new_docs.remove(0)),
+                    skipped,
+                });
+            }
+            let new_docs = new_docs
+                .iter()
+                .map(|doc| doc.extract_last_body().cloned().unwrap_or_default())
+                .collect();
+            documents = self.combine_documents_up_to(executor, new_docs, &base_parameters)?;
+        }
+    }
+
+    /// Estimates what running the chain on the given documents would take, without calling the model.
+    ///
+    /// The documents are split into chunks like in a real run, giving the exact `map` calls. The `reduce` rounds
+    /// depend on the length of the outputs, which aren't known, so every output is assumed to be as long as the
+    /// assumed output of its step, see `estimate`. The steps are named like the stages of `Event::Progress`.
+    pub fn dry_run<E: Executor>(
+        &self,
+        documents: &[Parameters],
+        base_parameters: Parameters,
+        executor: &E,
+    ) -> Result<Estimate, MapReduceChainError> {
+        if documents.is_empty() {
+            return Err(MapReduceChainError::InputEmpty);
+        }
+        let mut estimate = Estimate::default();
+        let chunked_docs =
+            self.chunk_documents(documents, base_parameters.clone(), executor, &self.map)?;
+        let mut map = StepEstimate::new("map", executor.max_tokens_allowed(self.map.options()));
+        let map_output = placeholder_output(executor, self.map.options())?;
+        let mut mapped_documents = vec![];
+        for (document, chunk, parameters) in &chunked_docs {
+            let parameters = base_parameters
+                .combine(&documents[*document])
+                .combine(parameters);
+            let prompt = self.map.format(&parameters)?;
+            map.add_call(
+                self.map.options(),
+                executor
+                    .tokens_used(self.map.options(), &prompt)?
+                    .tokens_used(),
+            );
+            let parameters = base_parameters
+                .combine(&documents[*document])
+                .with("document", (document + 1).to_string())
+                .with("chunk", (chunk + 1).to_string())
+                .with_text(map_output.clone());
+            mapped_documents.push(self.document_template.format(&parameters)?);
+        }
+        estimate.steps.push(map);
+
+        let reduce_output = placeholder_output(executor, self.reduce.options())?;
+        let max_tokens_allowed = executor.max_tokens_allowed(self.reduce.options());
+        let mut documents =
+            self.combine_documents_up_to(executor, mapped_documents, &base_parameters)?;
+        let mut round = 0;
+        loop {
+            round += 1;
+            let mut reduce = StepEstimate::new(format!("reduce {}", round), max_tokens_allowed);
+            for document in &documents {
+                let prompt = self.reduce.format(&base_parameters.with_text(document))?;
+                reduce.add_call(
+                    self.reduce.options(),
+                    executor
+                        .tokens_used(self.reduce.options(), &prompt)?
+                        .tokens_used(),
+                );
+            }
+            estimate.steps.push(reduce);
+            let outputs = vec![reduce_output.clone(); documents.len()];
+            let combined = self.combine_documents_up_to(executor, outputs, &base_parameters)?;
+            // Stop once a round has a single output, or if the outputs can't be combined any further, which a
+            // real run would never get out of.
+            if documents.len() == 1 || combined.len() >= documents.len() {
+                return Ok(estimate);
+            }
+            documents = combined;
+        }
+    }
+
+    /// Executes a step for every set of parameters concurrently, reporting progress under `stage`.
+    ///
+    /// Under `FailurePolicy::Skip` the results of all steps are returned, failed or not. Otherwise the first
+    /// failure is returned as the error, without waiting for the other steps.
+    async fn execute_all<E: Executor>(
+        &self,
+        frame: &Frame<'_, E>,
+        step: &Step,
+        stage: &str,
+        tasks: &[Parameters],
+    ) -> Result<Vec<Result<Data<String>, MapReduceChainError>>, MapReduceChainError> {
+        let total = tasks.len();
+        let completed = AtomicUsize::new(0);
+        self.report_progress(stage, 0, total);
+        let name = stage.split(' ').next().unwrap_or(stage);
+        let futures = tasks.iter().map(|task| async {
+            let output = self.execute_with_retries(frame, step, name, task).await;
+            let done = completed.fetch_add(1, Ordering::SeqCst) + 1;
+            self.report_progress(stage, done, total);
+            output
+        });
+        if self.failure_policy == FailurePolicy::Skip {
+            Ok(join_all(futures).await)
+        } else {
+            Ok(try_join_all(futures).await?.into_iter().map(Ok).collect())
+        }
+    }
+
+    fn report_progress(&self, stage: &str, completed: usize, total: usize) {
+        self.callbacks.emit(Event::Progress {
+            stage: stage.to_string(),
+            completed,
+            total,
+        });
+    }
+
+    /// Executes a step, retrying it as many times as the failure policy allows.
+    async fn execute_with_retries<E: Executor>(
+        &self,
+        frame: &Frame<'_, E>,
+        step: &Step,
+        name: &str,
+        parameters: &Parameters,
+    ) -> Result<Data<String>, MapReduceChainError> {
+        let retries = match self.failure_policy {
+            FailurePolicy::Retry(retries) => retries,
+            FailurePolicy::FailFast | FailurePolicy::Skip => 0,
+        };
+        let mut attempt = 0;
+        loop {
+            match self.execute(frame, step, name, parameters).await {
+                Err(err) if attempt < retries => {
+                    attempt += 1;
+                    log::warn!(
+                        "llm-chain map_reduce `{}` step failed, retrying ({}/{}): {}",
+                        name,
+                        attempt,
+                        retries,
+                        err
+                    );
+                }
+                output => return output,
+            }
+        }
+    }
+
+    /// Executes a step, or returns its checkpointed output if an earlier run already executed it.
+    async fn execute<E: Executor>(
+        &self,
+        frame: &Frame<'_, E>,
+        step: &Step,
+        name: &str,
+        parameters: &Parameters,
+    ) -> Result<Data<String>, MapReduceChainError> {
+        let checkpoint = match &self.checkpoints {
+            Some(checkpoints) => {
+                let key = checkpoints.key(name, step.options(), &step.format(parameters)?)?;
+                if let Some(output) = checkpoints.load(&key).await? {
+                    return Ok(output);
+                }
+                Some((checkpoints, key))
             }
-            documents = self
-                .combine_documents_up_to(executor, new_docs, &base_parameters)
-                .await?;
+            None => None,
+        };
+        let output = frame
+            .format_and_execute(parameters)
+            .await?
+            .to_immediate()
+            .await
+            .map_err(|e| {
+                MapReduceChainError::FormatAndExecuteError(
+                    crate::frame::FormatAndExecuteError::Execute(e),
+                )
+            })?
+            .as_content();
+        if let Some((checkpoints, key)) = checkpoint {
+            checkpoints.save(&key, name, output.clone()).await?;
         }
+        Ok(output)
     }
 
-    async fn combine_documents_up_to<E: Executor>(
+    /// Joins consecutive documents for as long as they fit in the prompt of the `reduce` step, keeping their order.
+    fn combine_documents_up_to<E: Executor>(
         &self,
         executor: &E,
-        mut v: Vec<Data<String>>,
+        v: Vec<String>,
         parameters: &Parameters,
     ) -> Result<Vec<String>, MapReduceChainError> {
         let mut new_outputs = Vec::new();
-        while let Some(current) = v.pop() {
-            let mut current_doc = current.extract_last_body().cloned().unwrap_or_default();
-            while let Some(next) = v.last() {
-                let Some(next_doc_content) = next.extract_last_body() else {
-                    continue;
-                };
+        let mut v = v.into_iter().peekable();
+        while let Some(mut current_doc) = v.next() {
+            while let Some(next_doc_content) = v.peek() {
                 let mut new_doc = current_doc.clone();
                 new_doc.push('\n');
                 new_doc.push_str(next_doc_content);
@@ -171,7 +481,7 @@ impl Chain {
                 let count = executor.tokens_used(self.reduce.options(), &prompt)?;
                 if count.has_tokens_remaining() {
                     current_doc = new_doc;
-                    v.pop();
+                    v.next();
                 } else {
                     break;
                 }
@@ -181,30 +491,35 @@ impl Chain {
         Ok(new_outputs)
     }
 
+    /// Splits the documents into chunks fitting the context window of `step`, along with the index of the
+    /// document each chunk comes from and its index within that document.
     fn chunk_documents<'a, E>(
         &self,
-        v: Vec<Parameters>,
+        v: &[Parameters],
         base_parameters: Parameters,
         executor: &E,
         step: &Step,
-    ) -> Result<Vec<Parameters>, PromptTokensError>
+    ) -> Result<Vec<(usize, usize, Parameters)>, PromptTokensError>
     where
         E: Executor + 'a,
     {
-        let data: Result<Vec<_>, _> = v
-            .iter()
-            .map(|x| {
-                <E as tokens::ExecutorTokenCountExt>::split_to_fit(
-                    executor,
-                    step,
-                    x,
-                    &base_parameters,
-                    None,
-                )
-            })
-            .collect();
-        let data = data?.iter().flatten().cloned().collect();
-        Ok(data)
+        let mut chunks = vec![];
+        for (document, x) in v.iter().enumerate() {
+            let split = <E as tokens::ExecutorTokenCountExt>::split_to_fit(
+                executor,
+                step,
+                x,
+                &base_parameters,
+                None,
+            )?;
+            chunks.extend(
+                split
+                    .into_iter()
+                    .enumerate()
+                    .map(|(chunk, parameters)| (document, chunk, parameters)),
+            );
+        }
+        Ok(chunks)
     }
 }
 
@@ -226,3 +541,178 @@ impl StorableEntity for Chain {
         base
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{Chain, FailurePolicy, MapReduceChainError};
+    use crate::callbacks::{Callbacks, Event};
+    use crate::checkpoint::{Checkpointer, InMemoryCheckpointStore};
+    use crate::testing::ScriptedExecutor;
+    use crate::{prompt, prompt::StringTemplate, step::Step, Parameters};
+
+    fn chain() -> Chain {
+        Chain::new(
+            Step::for_prompt_template(prompt!("map {{text}}")),
+            Step::for_prompt_template(prompt!("reduce {{text}}")),
+        )
+    }
+
+    fn documents() -> Vec<Parameters> {
+        ["one", "broken", "three"]
+            .into_iter()
+            .map(Parameters::new_with_text)
+            .collect()
+    }
+
+    fn executor() -> ScriptedExecutor {
+        ScriptedExecutor::fallible(|prompt| {
+            if prompt.contains("broken") {
+                Err("the model is down".to_string())
+            } else {
+                Ok(prompt.replace("map ", ""))
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_fails_fast_by_default() {
+        let result = chain()
+            .run(documents(), Parameters::new(), &executor())
+            .await;
+        assert!(matches!(
+            result,
+            Err(MapReduceChainError::FormatAndExecuteError(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resumes_failed_runs_from_checkpoints() {
+        let store = Arc::new(InMemoryCheckpointStore::new());
+        let chain = chain().with_checkpoints(Checkpointer::new(store.clone(), "run"));
+        let failing = executor();
+        assert!(chain
+            .run(documents(), Parameters::new(), &failing)
+            .await
+            .is_err());
+        // The run stops at the broken chunk, possibly before the chunks after it were mapped.
+        let checkpointed = store.len();
+        assert!(checkpointed >= 1);
+
+        let executor = ScriptedExecutor::new(|prompt| prompt.replace("map ", ""));
+        let output = chain
+            .run(documents(), Parameters::new(), &executor)
+            .await
+            .unwrap();
+        // Only the map steps without a checkpoint run again, followed by the reduce step.
+        assert_eq!(executor.calls(), 3 - checkpointed + 1);
+        let output = output.to_immediate().await.unwrap().as_content().to_text();
+        assert_eq!(output, "reduce one\nbroken\nthree");
+    }
+
+    #[tokio::test]
+    async fn test_skips_failed_chunks_and_reports_progress() {
+        let events = Arc::new(Mutex::new(vec![]));
+        let recorded = events.clone();
+        let callbacks = Callbacks::new().with_handler(move |event: &Event| {
+            if let Event::Progress {
+                stage,
+                completed,
+                total,
+            } = event
+            {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}/{}", stage, completed, total));
+            }
+        });
+        let output = chain()
+            .with_failure_policy(FailurePolicy::Skip)
+            .with_callbacks(callbacks)
+            .run_with_report(documents(), Parameters::new(), &executor())
+            .await
+            .unwrap();
+
+        assert_eq!(output.skipped.len(), 1);
+        assert_eq!(output.skipped[0].document, 1);
+        assert!(output.skipped[0].error.contains("the model is down"));
+        let events = events.lock().unwrap();
+        assert_eq!(events.first().unwrap(), "map 0/3");
+        assert!(events.contains(&"map 3/3".to_string()));
+        assert_eq!(events.last().unwrap(), "reduce 1 1/1");
+    }
+
+    #[tokio::test]
+    async fn test_keeps_order_and_document_parameters() {
+        let executor = ScriptedExecutor::new(|prompt| prompt.replace("map ", ""));
+        let documents = ["monday", "tuesday", "wednesday"]
+            .into_iter()
+            .map(|day| Parameters::new_with_text(format!("{} notes", day)).with("source", day))
+            .collect();
+        let output = chain()
+            .with_document_template(StringTemplate::tera("{{source}} ({{chunk}}): {{text}}"))
+            .run(documents, Parameters::new(), &executor)
+            .await
+            .unwrap();
+        let output = output.to_immediate().await.unwrap().as_content().to_text();
+        assert_eq!(
+            output,
+            "reduce monday (1): monday notes\ntuesday (1): tuesday notes\nwednesday (1): wednesday notes"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_failed_steps() {
+        let failures = Mutex::new(2);
+        let executor = ScriptedExecutor::fallible(move |prompt| {
+            let mut failures = failures.lock().unwrap();
+            if prompt.starts_with("map") && *failures > 0 {
+                *failures -= 1;
+                return Err("rate limited".to_string());
+            }
+            Ok(prompt.to_string())
+        });
+        let documents = vec![Parameters::new_with_text("one")];
+        let output = chain()
+            .with_failure_policy(FailurePolicy::Retry(2))
+            .run_with_report(documents, Parameters::new(), &executor)
+            .await
+            .unwrap();
+        assert!(output.skipped.is_empty());
+        assert_eq!(executor.calls(), 4);
+    }
+
+    #[test]
+    fn test_dry_run_estimates_calls_without_executing() {
+        let executor = ScriptedExecutor::new(|prompt| prompt.to_string());
+        let documents = vec![
+            Parameters::new_with_text("first"),
+            Parameters::new_with_text("second"),
+        ];
+        let estimate = chain()
+            .dry_run(&documents, Parameters::new(), &executor)
+            .unwrap();
+
+        let names: Vec<_> = estimate
+            .steps
+            .iter()
+            .map(|step| step.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["map", "reduce 1"]);
+        assert_eq!(estimate.steps[0].calls, 2);
+        assert_eq!(
+            estimate.steps[0].prompt_tokens,
+            "map first".len() + "map second".len()
+        );
+        // Both outputs, assumed to be 36 placeholder words of 7 tokens, fit in a single reduce prompt.
+        assert_eq!(estimate.steps[1].calls, 1);
+        assert_eq!(
+            estimate.steps[1].prompt_tokens,
+            "reduce ".len() + 252 + 1 + 252
+        );
+        assert!(estimate.fits());
+        assert_eq!(executor.calls(), 0);
+    }
+}
@@ -0,0 +1,324 @@
+//! The `map_rerank` module contains the `Chain` struct, which represents a map-rerank chain.
+//!
+//! A map-rerank chain asks every document separately for an answer to the question, together with a score of
+//! how confident the model is in that answer. The answer with the highest score wins, and the document it came
+//! from is returned with it.
+//!
+//! Unlike a stuff-documents chain it handles any number of documents, and unlike a map-reduce chain the answer
+//! comes from a single document, which makes it easy to tell where it came from. Answers that span several
+//! documents are out of its reach.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let chain = Chain::new();
+//! let result = chain.run(documents, parameters!("question" => "Who wrote it?"), &executor).await?;
+//! println!("{} (score {})", result.answer, result.score);
+//! ```
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::callbacks::{Callbacks, Event};
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::parsing::find_yaml;
+use crate::prompt::{StringTemplate, StringTemplateError};
+use crate::schema::Document;
+use crate::{prompt, serialization::StorableEntity, step::Step, traits::Executor, Parameters};
+
+const MAP_RERANK_PROMPT: &str = "Use the following piece of context to answer the question at the end. If you don't know the answer, just say that you don't know, don't try to make up an answer.
+
+In addition to giving an answer, also return a score of how fully it answered the question, from 0 to 100. A score of 100 means the context fully answers the question, a score of 0 means it doesn't answer it at all.
+
+Respond with YAML in the following format:
+```yaml
+answer: the answer to the question
+score: how fully the answer answers the question
+```
+
+Context:
+{{context}}
+
+Question: {{question}}";
+
+/// The default template a document is formatted with.
+const DOCUMENT_TEMPLATE: &str = "{{page_content}}";
+
+/// The `MapRerankChainError` enum represents errors that can occur when executing a map-rerank chain.
+#[derive(Error, Debug)]
+pub enum MapRerankChainError {
+    /// An error relating to the operation of the Executor.
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    #[error("Error templating: {0}")]
+    StringTemplate(#[from] StringTemplateError),
+    #[error("The vector of input documents was empty")]
+    InputEmpty,
+    #[error("None of the answers contained a score")]
+    NoScoredAnswer,
+}
+
+/// An answer given for a single document, and how confident the model is in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScoredAnswer {
+    pub answer: String,
+    pub score: f64,
+}
+
+/// Parses an answer in the format asked for by the default prompt: a YAML map with `answer` and `score`.
+///
+/// Returns `None` if the text doesn't contain such a map.
+pub fn parse_scored_answer(text: &str) -> Option<ScoredAnswer> {
+    find_yaml::<ScoredAnswer>(text)
+        .ok()
+        .and_then(|answers| answers.into_iter().next())
+}
+
+/// The result of a map-rerank chain.
+pub struct MapRerankOutput<M>
+where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// The answer with the highest score.
+    pub answer: String,
+    /// The score of the answer.
+    pub score: f64,
+    /// The document the answer was given for.
+    pub source_document: Document<M>,
+    /// The answer for every document, in the order of the input. Answers that couldn't be parsed are `None`.
+    pub answers: Vec<Option<ScoredAnswer>>,
+}
+
+/// The `Chain` struct represents a map-rerank chain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chain {
+    step: Step,
+    document_template: StringTemplate,
+    #[serde(skip)]
+    callbacks: Callbacks,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chain {
+    /// Constructs a new `Chain` with the default prompt, which answers `question` from a document.
+    pub fn new() -> Chain {
+        Chain {
+            step: Step::for_prompt_template(prompt!(MAP_RERANK_PROMPT)),
+            document_template: StringTemplate::tera(DOCUMENT_TEMPLATE),
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Replaces the step asked about every document.
+    ///
+    /// The step is formatted with the parameters passed to `run` and the formatted document as `context`. The
+    /// model must answer in the format understood by `parse_scored_answer`.
+    pub fn with_step(mut self, step: Step) -> Chain {
+        self.step = step;
+        self
+    }
+
+    /// Replaces the template every document is formatted with.
+    ///
+    /// The template is formatted with the content of the document as `page_content` and the fields of its
+    /// metadata, see `Document::to_parameters`.
+    pub fn with_document_template(mut self, document_template: StringTemplate) -> Chain {
+        self.document_template = document_template;
+        self
+    }
+
+    /// Attaches callbacks to the chain. The step is reported as `map_rerank` for every document.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Executes the chain using the provided `Executor`.
+    ///
+    /// The documents are asked concurrently. Answers without a score are ignored, and ties go to the earliest
+    /// document.
+    pub async fn run<E: Executor, M>(
+        &self,
+        documents: Vec<Document<M>>,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<MapRerankOutput<M>, MapRerankChainError>
+    where
+        M: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let output = self.try_run(documents, parameters, executor).await;
+        // Errors of the step itself have already been reported by its frames.
+        if let Err(err) = &output {
+            if !matches!(err, MapRerankChainError::FormatAndExecuteError(_)) {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+            }
+        }
+        output
+    }
+
+    async fn try_run<E: Executor, M>(
+        &self,
+        documents: Vec<Document<M>>,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<MapRerankOutput<M>, MapRerankChainError>
+    where
+        M: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        if documents.is_empty() {
+            return Err(MapRerankChainError::InputEmpty);
+        }
+        let inputs = documents
+            .iter()
+            .map(|document| {
+                let context = self.document_template.format(&document.to_parameters())?;
+                Ok(parameters.with("context", context))
+            })
+            .collect::<Result<Vec<_>, MapRerankChainError>>()?;
+        let answers = join_all(inputs.iter().map(|input| self.ask(executor, input))).await;
+        let answers = answers.into_iter().collect::<Result<Vec<_>, _>>()?;
+
+        let mut best: Option<(usize, &ScoredAnswer)> = None;
+        for (index, answer) in answers.iter().enumerate() {
+            if let Some(answer) = answer {
+                if best.is_none_or(|(_, best)| answer.score > best.score) {
+                    best = Some((index, answer));
+                }
+            }
+        }
+        let (index, best) = best.ok_or(MapRerankChainError::NoScoredAnswer)?;
+        let (answer, score) = (best.answer.clone(), best.score);
+        let source_document = documents
+            .into_iter()
+            .nth(index)
+            .expect("the index comes from the documents");
+        Ok(MapRerankOutput {
+            answer,
+            score,
+            source_document,
+            answers,
+        })
+    }
+
+    async fn ask<E: Executor>(
+        &self,
+        executor: &E,
+        parameters: &Parameters,
+    ) -> Result<Option<ScoredAnswer>, MapRerankChainError> {
+        let text = Frame::new(executor, &self.step)
+            .with_callbacks(self.callbacks.clone())
+            .with_name("map_rerank")
+            .format_and_execute(parameters)
+            .await?
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .primary_textual_output()
+            .unwrap_or_default();
+        Ok(parse_scored_answer(&text))
+    }
+}
+
+impl StorableEntity for Chain {
+    fn get_metadata() -> Vec<(String, String)> {
+        let base = vec![(
+            "chain-type".to_string(),
+            "llm-chain::chains::map_rerank::Chain".to_string(),
+        )];
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_scored_answer, Chain, MapRerankChainError, ScoredAnswer};
+    use crate::schema::{Document, EmptyMetadata};
+    use crate::testing::ScriptedExecutor;
+    use crate::{parameters, prompt, step::Step};
+
+    fn documents(texts: &[&str]) -> Vec<Document<EmptyMetadata>> {
+        texts
+            .iter()
+            .map(|text| Document::new(text.to_string()))
+            .collect()
+    }
+
+    /// Answers with the document as the answer, and the number of times it mentions the question as the score.
+    fn executor() -> ScriptedExecutor {
+        ScriptedExecutor::new(|prompt| {
+            let (question, context) = prompt.split_once(" in ").unwrap();
+            if context.contains("nothing") {
+                return "I don't know.".to_string();
+            }
+            format!(
+                "answer: {}\nscore: {}",
+                context,
+                context.matches(question).count()
+            )
+        })
+    }
+
+    fn chain() -> Chain {
+        Chain::new().with_step(Step::for_prompt_template(prompt!(
+            "{{question}} in {{context}}"
+        )))
+    }
+
+    #[tokio::test]
+    async fn test_picks_the_best_scored_answer() {
+        let result = chain()
+            .run(
+                documents(&["whale", "nothing", "whale whale", "whale, whale"]),
+                parameters!("question" => "whale"),
+                &executor(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(result.answer, "whale whale");
+        assert_eq!(result.score, 2.0);
+        assert_eq!(result.source_document.page_content, "whale whale");
+        let scores: Vec<_> = result
+            .answers
+            .iter()
+            .map(|answer| answer.as_ref().map(|answer| answer.score))
+            .collect();
+        assert_eq!(scores, vec![Some(1.0), None, Some(2.0), Some(2.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_fails_without_scored_answers() {
+        let result = chain()
+            .run(
+                documents(&["nothing"]),
+                parameters!("question" => "whale"),
+                &executor(),
+            )
+            .await;
+        assert!(matches!(result, Err(MapRerankChainError::NoScoredAnswer)));
+    }
+
+    #[test]
+    fn test_parses_scored_answers() {
+        let text = "```yaml
+answer: Herman Melville
+score: 90
+```";
+        assert_eq!(
+            parse_scored_answer(text),
+            Some(ScoredAnswer {
+                answer: "Herman Melville".to_string(),
+                score: 90.0,
+            })
+        );
+        assert_eq!(parse_scored_answer("I don't know."), None);
+    }
+}
@@ -7,9 +7,32 @@
 //! Here are the supported chain types:
 //! 1. **Sequential**: This chain type executes the steps one after another in a linear sequence. It's perfect for tasks that need a clear and simple order of execution.
 //! 2. **MapReduce**: This chain type follows the MapReduce paradigm, where the steps are divided into mapping and reducing phases. It's great for tasks that require parallel processing and data aggregation.
-//! 3. **Converstation**: This chain type models a conversation between the LLM and some other entity. It's great for tasks that require a back-and-forth between the LLM and the user.
+//! 3. **Refine**: This chain type processes documents chunk by chunk, refining a running answer with every chunk. It's great for tasks that need context across chunks, like writing one coherent summary.
+//! 4. **Converstation**: This chain type models a conversation between the LLM and some other entity. It's great for tasks that require a back-and-forth between the LLM and the user.
+//! 5. **Graph**: This chain type runs steps as a directed acyclic graph, running independent branches concurrently. It's great for pipelines that fan out and merge their results.
+//! 6. **Router**: This chain type lets the LLM choose which of several sub-chains handles the input. It's great for sending different kinds of requests to differently prompted chains.
+//! 7. **Stuff**: This chain type packs as many documents as fit in the context window into a single prompt. It's great for answering questions over a handful of retrieved documents.
+//! 8. **MapRerank**: This chain type asks every document separately for a scored answer and keeps the best one. It's great for questions whose answer is found in a single document.
+//! 9. **RetrievalQa**: This chain type searches a vector store for documents similar to a question and answers it from them, optionally citing its sources. It's great for retrieval-augmented generation.
+//! 10. **ConversationalRetrieval**: This chain type chats over the documents of a vector store, rewriting follow-up messages into standalone questions. It's great for chatbots answering from a knowledge base.
+//! 11. **SelfCritique**: This chain type re-asks a step until a validator accepts its answer. It's great for getting output that reliably parses.
+//! 12. **SelfConsistency**: This chain type samples several answers to a step and returns the most common one. It's great for classification and math-style prompts, with the share of the votes as a confidence signal.
+//!
+//! Chains stored with `serialization::StorableEntity` can be loaded back without knowing their type in advance
+//! using `registry::ChainRegistry`.
+//!
 //! Stay tuned for more chain types, and feel free to contribute your own! 🎉
 
 pub mod conversation;
+pub mod conversational_retrieval;
+pub mod graph;
 pub mod map_reduce;
+pub mod map_rerank;
+pub mod refine;
+pub mod registry;
+pub mod retrieval_qa;
+pub mod router;
+pub mod self_consistency;
+pub mod self_critique;
 pub mod sequential;
+pub mod stuff;
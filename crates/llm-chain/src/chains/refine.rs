@@ -0,0 +1,218 @@
+//! The `refine` module contains the `Chain` struct, which represents a refine chain.
+//!
+//! A refine chain processes documents one chunk at a time while keeping a running answer. The `initial` step
+//! produces an answer from the first chunk, then the `refine` step is given every following chunk together with
+//! the answer so far, available as `existing_answer`, and improves it.
+//!
+//! Unlike a map-reduce chain, every step sees the context accumulated from the previous chunks, which makes it a
+//! good fit for tasks like writing one coherent summary. The price is that the chunks are processed one after
+//! another rather than in parallel.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::callbacks::{Callbacks, Event};
+use crate::estimate::placeholder_output;
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::tokens::{ExecutorTokenCountExt, PromptTokensError};
+use crate::{serialization::StorableEntity, step::Step, traits::Executor, Parameters};
+
+/// The `RefineChainError` enum represents errors that can occur when executing a refine chain.
+#[derive(Error, Debug)]
+pub enum RefineChainError {
+    /// An error relating to the operation of the Executor.
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    /// An error relating to tokenizing the inputs.
+    #[error("TokenizeError: {0}")]
+    TokenizeError(#[from] PromptTokensError),
+    #[error("The vector of input documents was empty")]
+    InputEmpty,
+}
+
+/// The result of a refine chain.
+#[derive(Debug, Clone)]
+pub struct RefineOutput {
+    /// The final answer, after every chunk has been processed.
+    pub answer: String,
+    /// The answer after each chunk, in order. The last one is the final answer.
+    pub intermediate_answers: Vec<String>,
+}
+
+/// The `Chain` struct represents a refine chain, consisting of an `initial` step and a `refine` step.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chain {
+    initial: Step,
+    refine: Step,
+    #[serde(skip)]
+    callbacks: Callbacks,
+}
+
+impl Chain {
+    /// Constructs a new `Chain` with the given `initial` and `refine` steps.
+    ///
+    /// The `initial` step is formatted with the first chunk as `text`. The `refine` step is formatted with each
+    /// later chunk as `text` and the answer so far as `existing_answer`.
+    pub fn new(initial: Step, refine: Step) -> Chain {
+        Chain {
+            initial,
+            refine,
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Attaches callbacks to the chain, which are notified as the `initial` and every `refine` step runs.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Executes the refine chain using the provided `Executor`.
+    ///
+    /// The documents are split into chunks fitting the context window of the `refine` step, and the chunks are
+    /// processed in order. The other parameters of each document are available to the steps processing its chunks.
+    pub async fn run<E: Executor>(
+        &self,
+        documents: Vec<Parameters>,
+        base_parameters: Parameters,
+        executor: &E,
+    ) -> Result<RefineOutput, RefineChainError> {
+        let output = self.try_run(documents, base_parameters, executor).await;
+        // Errors of the `initial` and `refine` steps themselves have already been reported by their frames.
+        if let Err(err) = &output {
+            if !matches!(err, RefineChainError::FormatAndExecuteError(_)) {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+            }
+        }
+        output
+    }
+
+    async fn try_run<E: Executor>(
+        &self,
+        documents: Vec<Parameters>,
+        base_parameters: Parameters,
+        executor: &E,
+    ) -> Result<RefineOutput, RefineChainError> {
+        // The running answer takes up room in the refine prompt as well, so room for an answer as long as the
+        // refine step may write is left in every chunk.
+        let split_parameters = base_parameters.with(
+            "existing_answer",
+            placeholder_output(executor, self.refine.options())?,
+        );
+        let mut chunks = vec![];
+        for document in &documents {
+            for chunk in executor.split_to_fit(&self.refine, document, &split_parameters, None)? {
+                chunks.push(base_parameters.combine(document).combine(&chunk));
+            }
+        }
+
+        let mut intermediate_answers: Vec<String> = vec![];
+        for chunk in chunks {
+            let answer = match intermediate_answers.last() {
+                None => {
+                    self.execute(executor, &self.initial, "initial", &chunk)
+                        .await?
+                }
+                Some(existing_answer) => {
+                    let parameters = chunk.with("existing_answer", existing_answer.clone());
+                    self.execute(executor, &self.refine, "refine", &parameters)
+                        .await?
+                }
+            };
+            intermediate_answers.push(answer);
+        }
+
+        let answer = intermediate_answers
+            .last()
+            .cloned()
+            .ok_or(RefineChainError::InputEmpty)?;
+        Ok(RefineOutput {
+            answer,
+            intermediate_answers,
+        })
+    }
+
+    async fn execute<E: Executor>(
+        &self,
+        executor: &E,
+        step: &Step,
+        name: &str,
+        parameters: &Parameters,
+    ) -> Result<String, RefineChainError> {
+        Ok(Frame::new(executor, step)
+            .with_callbacks(self.callbacks.clone())
+            .with_name(name)
+            .format_and_execute(parameters)
+            .await?
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .primary_textual_output()
+            .unwrap_or_default())
+    }
+}
+
+impl StorableEntity for Chain {
+    fn get_metadata() -> Vec<(String, String)> {
+        let base = vec![(
+            "chain-type".to_string(),
+            "llm-chain::chains::refine::Chain".to_string(),
+        )];
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chain, RefineChainError};
+    use crate::testing::ScriptedExecutor;
+    use crate::{prompt, step::Step, Parameters};
+
+    fn chain() -> Chain {
+        Chain::new(
+            Step::for_prompt_template(prompt!("summarize {{text}}")),
+            Step::for_prompt_template(prompt!("refine {{existing_answer}} with {{text}}")),
+        )
+    }
+
+    fn executor() -> ScriptedExecutor {
+        ScriptedExecutor::new(|prompt| {
+            if let Some(text) = prompt.strip_prefix("summarize ") {
+                text.to_string()
+            } else {
+                prompt.replace("refine ", "").replace(" with ", "+")
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn test_refines_the_initial_answer_with_every_document() {
+        let documents = ["one", "two", "three"]
+            .into_iter()
+            .map(Parameters::new_with_text)
+            .collect();
+        let executor = executor();
+
+        let output = chain()
+            .run(documents, Parameters::new(), &executor)
+            .await
+            .unwrap();
+
+        assert_eq!(output.answer, "one+two+three");
+        assert_eq!(
+            output.intermediate_answers,
+            vec!["one", "one+two", "one+two+three"]
+        );
+        assert_eq!(executor.calls(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_fails_without_documents() {
+        let executor = executor();
+        let result = chain().run(vec![], Parameters::new(), &executor).await;
+        assert!(matches!(result, Err(RefineChainError::InputEmpty)));
+        assert_eq!(executor.calls(), 0);
+    }
+}
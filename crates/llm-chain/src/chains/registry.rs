@@ -0,0 +1,291 @@
+//! The `registry` module loads chains from serialized envelopes without knowing their type in advance.
+//!
+//! Chains written with `StorableEntity` carry a `chain-type` metadata key. A `ChainRegistry` maps those keys to
+//! chain types, reads any envelope, and returns the chain as a boxed `RunnableChain`, so prompt pipelines can be
+//! shipped as YAML or JSON files and changed without recompiling.
+//!
+//! Sequential, map-reduce and conversation chains are registered out of the box. Other chain types, including
+//! your own, can be added with `ChainRegistry::with_chain_type` once they implement `RunnableChain`.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let registry = ChainRegistry::new();
+//! let mut chain = registry.load_file("pipelines/summarize.yaml")?;
+//! let summary = chain
+//!     .run(ChainInput::new(parameters!()).with_documents(documents), &executor)
+//!     .await?;
+//! ```
+//!
+//! A pipeline file is an envelope, with the chain as `data`:
+//!
+//! ```yaml
+//! metadata:
+//!   chain-type: llm-chain::chains::sequential::Chain
+//! data:
+//!   steps:
+//!     - prompt:
+//!         Text:
+//!           Tera: "Summarize: {{text}}"
+//! ```
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+use super::{conversation, map_reduce, sequential};
+use crate::output::Output;
+use crate::serialization::{Envelope, EnvelopeError, StorableEntity};
+use crate::traits::{Executor, ExecutorError};
+use crate::{prompt, step::Step, Parameters};
+
+/// The `ChainLoadError` enum represents errors that can occur when loading a chain from an envelope.
+#[derive(Error, Debug)]
+pub enum ChainLoadError {
+    #[error("EnvelopeError: {0}")]
+    Envelope(#[from] EnvelopeError),
+    #[error("YAML parsing error: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("The envelope has no `chain-type` metadata")]
+    MissingChainType,
+    #[error("Unknown chain type `{0}`")]
+    UnknownChainType(String),
+    #[error("Invalid `{chain_type}` chain: {source}")]
+    InvalidChain {
+        chain_type: String,
+        source: serde_json::Error,
+    },
+}
+
+/// The error of a chain run through `RunnableChain`, which is the error of the chain itself.
+pub type RunnableChainError = Box<dyn Error + Send + Sync>;
+
+/// The input of a chain run through `RunnableChain`.
+#[derive(Debug, Clone, Default)]
+pub struct ChainInput {
+    /// The parameters of the run. Conversation chains send their `text` as the user message.
+    pub parameters: Parameters,
+    /// The documents processed by document chains, like map-reduce. Other chains ignore them.
+    pub documents: Vec<Parameters>,
+}
+
+impl ChainInput {
+    pub fn new(parameters: Parameters) -> Self {
+        ChainInput {
+            parameters,
+            documents: vec![],
+        }
+    }
+
+    /// Sets the documents processed by document chains.
+    pub fn with_documents(mut self, documents: Vec<Parameters>) -> Self {
+        self.documents = documents;
+        self
+    }
+}
+
+/// A chain that can be run without knowing its type, as returned by `ChainRegistry`.
+#[async_trait]
+pub trait RunnableChain<E: Executor + Sync>: Send + Sync {
+    /// Runs the chain and returns the text of its output.
+    async fn run(&mut self, input: ChainInput, executor: &E) -> Result<String, RunnableChainError>;
+}
+
+async fn text_of(output: Output) -> Result<String, ExecutorError> {
+    Ok(output
+        .to_immediate()
+        .await?
+        .primary_textual_output()
+        .unwrap_or_default())
+}
+
+#[async_trait]
+impl<E: Executor + Sync> RunnableChain<E> for sequential::Chain {
+    async fn run(&mut self, input: ChainInput, executor: &E) -> Result<String, RunnableChainError> {
+        let output = sequential::Chain::run(self, input.parameters, executor).await?;
+        Ok(text_of(output).await?)
+    }
+}
+
+#[async_trait]
+impl<E: Executor + Sync> RunnableChain<E> for map_reduce::Chain {
+    async fn run(&mut self, input: ChainInput, executor: &E) -> Result<String, RunnableChainError> {
+        let output =
+            map_reduce::Chain::run(self, input.documents, input.parameters, executor).await?;
+        Ok(text_of(output).await?)
+    }
+}
+
+#[async_trait]
+impl<E: Executor + Sync> RunnableChain<E> for conversation::Chain {
+    /// Sends the `text` parameter as the next user message, and records it and the answer in the conversation.
+    async fn run(&mut self, input: ChainInput, executor: &E) -> Result<String, RunnableChainError> {
+        let step = Step::for_prompt_template(prompt!(user: "{{text}}"));
+        let output = self.send_message(step, &input.parameters, executor).await?;
+        Ok(text_of(output).await?)
+    }
+}
+
+type Loader<E> = Arc<
+    dyn Fn(serde_json::Value) -> Result<Box<dyn RunnableChain<E>>, serde_json::Error> + Send + Sync,
+>;
+
+/// Maps `chain-type` metadata to chain types, and loads chains of those types from envelopes.
+pub struct ChainRegistry<E: Executor + Sync> {
+    loaders: HashMap<String, Loader<E>>,
+}
+
+impl<E: Executor + Sync + 'static> Default for ChainRegistry<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E: Executor + Sync + 'static> ChainRegistry<E> {
+    /// Creates a registry knowing the sequential, map-reduce and conversation chains.
+    pub fn new() -> Self {
+        let registry = ChainRegistry {
+            loaders: HashMap::new(),
+        };
+        registry
+            .with_storable::<sequential::Chain>()
+            .with_storable::<map_reduce::Chain>()
+            .with_storable::<conversation::Chain>()
+    }
+
+    /// Creates a registry without any chain type.
+    pub fn empty() -> Self {
+        ChainRegistry {
+            loaders: HashMap::new(),
+        }
+    }
+
+    /// Registers a chain type under the given `chain-type`, replacing any type registered under it before.
+    ///
+    /// This is usually the `chain-type` returned by `StorableEntity::get_metadata` for the type.
+    pub fn with_chain_type<C>(mut self, chain_type: impl Into<String>) -> Self
+    where
+        C: RunnableChain<E> + serde::de::DeserializeOwned + 'static,
+    {
+        let loader: Loader<E> = Arc::new(|data| {
+            serde_json::from_value::<C>(data)
+                .map(|chain| Box::new(chain) as Box<dyn RunnableChain<E>>)
+        });
+        self.loaders.insert(chain_type.into(), loader);
+        self
+    }
+
+    fn with_storable<C>(self) -> Self
+    where
+        C: RunnableChain<E> + StorableEntity + 'static,
+    {
+        match chain_type_of::<C>() {
+            Some(chain_type) => self.with_chain_type::<C>(chain_type),
+            None => self,
+        }
+    }
+
+    /// Returns whether a chain type is registered under the given `chain-type`.
+    pub fn contains(&self, chain_type: &str) -> bool {
+        self.loaders.contains_key(chain_type)
+    }
+
+    /// Loads the chain in the envelope, dispatching on its `chain-type` metadata.
+    pub fn load(
+        &self,
+        envelope: Envelope<serde_json::Value>,
+    ) -> Result<Box<dyn RunnableChain<E>>, ChainLoadError> {
+        let chain_type = envelope
+            .metadata
+            .get("chain-type")
+            .ok_or(ChainLoadError::MissingChainType)?;
+        let loader = self
+            .loaders
+            .get(chain_type)
+            .ok_or_else(|| ChainLoadError::UnknownChainType(chain_type.clone()))?;
+        loader(envelope.data).map_err(|source| ChainLoadError::InvalidChain {
+            chain_type: chain_type.clone(),
+            source,
+        })
+    }
+
+    /// Loads a chain from an envelope serialized as YAML or JSON.
+    pub fn load_str(&self, envelope: &str) -> Result<Box<dyn RunnableChain<E>>, ChainLoadError> {
+        self.load(serde_yaml::from_str(envelope)?)
+    }
+
+    /// Loads a chain from a file containing an envelope serialized as YAML or JSON, like the files written by
+    /// `StorableEntity::write_file_sync`.
+    pub fn load_file(&self, path: &str) -> Result<Box<dyn RunnableChain<E>>, ChainLoadError> {
+        let contents = std::fs::read_to_string(path).map_err(EnvelopeError::from)?;
+        self.load_str(&contents)
+    }
+}
+
+fn chain_type_of<C: StorableEntity>() -> Option<String> {
+    C::get_metadata()
+        .into_iter()
+        .find(|(key, _)| key == "chain-type")
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChainInput, ChainLoadError, ChainRegistry};
+    use crate::chains::conversation;
+    use crate::serialization::StorableEntity;
+    use crate::testing::ScriptedExecutor;
+    use crate::Parameters;
+
+    #[tokio::test]
+    async fn test_loads_a_sequential_chain_from_yaml() {
+        let yaml = r#"
+metadata:
+  chain-type: llm-chain::chains::sequential::Chain
+data:
+  steps:
+    - prompt:
+        Text:
+          Tera: "Translate {{text}}"
+    - prompt:
+        Text:
+          Tera: "Summarize {{text}}"
+"#;
+        let registry = ChainRegistry::<ScriptedExecutor>::new();
+        let mut chain = registry.load_str(yaml).unwrap();
+        let executor = ScriptedExecutor::new(|prompt| format!("[{}]", prompt));
+        let output = chain
+            .run(ChainInput::new(Parameters::new_with_text("hej")), &executor)
+            .await
+            .unwrap();
+        assert_eq!(output, "[Summarize [Translate hej]]");
+    }
+
+    #[tokio::test]
+    async fn test_loaded_conversations_keep_their_history() {
+        let json = serde_json::to_string(&conversation::Chain::default().to_envelope()).unwrap();
+        let registry = ChainRegistry::<ScriptedExecutor>::new();
+        let mut chain = registry.load_str(&json).unwrap();
+        let executor = ScriptedExecutor::new(|prompt| prompt.lines().count().to_string());
+        let input = |text: &str| ChainInput::new(Parameters::new_with_text(text));
+        assert_eq!(chain.run(input("Hi"), &executor).await.unwrap(), "1");
+        assert_eq!(chain.run(input("Again"), &executor).await.unwrap(), "3");
+    }
+
+    #[test]
+    fn test_rejects_unknown_chain_types() {
+        let registry = ChainRegistry::<ScriptedExecutor>::empty();
+        let yaml = "metadata:\n  chain-type: my-chain\ndata: {}\n";
+        assert!(matches!(
+            registry.load_str(yaml),
+            Err(ChainLoadError::UnknownChainType(chain_type)) if chain_type == "my-chain"
+        ));
+        assert!(matches!(
+            registry.load_str("data: {}"),
+            Err(ChainLoadError::MissingChainType)
+        ));
+    }
+}
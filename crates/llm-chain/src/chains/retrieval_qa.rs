@@ -0,0 +1,357 @@
+//! The `retrieval_qa` module contains the `Chain` struct, which answers questions over the documents of a vector
+//! store.
+//!
+//! The question is used to search the vector store for similar documents, and as many of them as fit in the
+//! context window are passed to the step, as in a stuff-documents chain. The answer is returned together with the
+//! documents it was given, metadata included, so it can be traced back to its sources.
+//!
+//! In citation mode, the documents are numbered in the prompt and the model is asked to cite the documents it
+//! used, like `[1]`. Once the answer is generated, the citations of documents the model was given are collected,
+//! and other bracketed numbers, such as a year, are ignored. An answer without citations, such as the model saying
+//! that it doesn't know, is returned as is, and `RetrievalQaOutput::is_cited` tells it apart.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let chain = Chain::new(vector_store).with_limit(5).with_citations();
+//! let result = chain.run("Who wrote the manual?", &executor).await?;
+//! for index in result.citations {
+//!     println!("{:?}", result.source_documents[index].metadata);
+//! }
+//! ```
+
+use std::marker::PhantomData;
+
+use thiserror::Error;
+
+use super::stuff::{self, StuffDocumentsChainError};
+use crate::callbacks::{Callbacks, Event};
+use crate::frame::FormatAndExecuteError;
+use crate::prompt::StringTemplate;
+use crate::schema::{Document, EmptyMetadata};
+use crate::traits::{Embeddings, Executor, VectorStore};
+use crate::{prompt, step::Step, Parameters};
+
+const QA_PROMPT: &str = "Use the following pieces of context to answer the question at the end. If you don't know the answer, just say that you don't know, don't try to make up an answer.
+
+{{context}}
+
+Question: {{question}}
+Helpful Answer:";
+
+const CITATION_QA_PROMPT: &str = "Use the following numbered documents to answer the question at the end. If you don't know the answer, just say that you don't know, don't try to make up an answer.
+
+Cite the documents that support your answer by their number in square brackets, like [1] or [1][3]. Only cite documents from the list below.
+
+{{context}}
+
+Question: {{question}}
+Helpful Answer:";
+
+const CITATION_DOCUMENT_TEMPLATE: &str = "[{{index}}] {{page_content}}";
+
+/// The number of documents retrieved from the vector store by default.
+const DEFAULT_LIMIT: u32 = 4;
+
+/// The `RetrievalQaChainError` enum represents errors that can occur when executing a retrieval QA chain.
+#[derive(Error, Debug)]
+pub enum RetrievalQaChainError<V>
+where
+    V: std::fmt::Debug + std::error::Error,
+{
+    #[error("VectorStoreError: {0}")]
+    VectorStoreError(V),
+    #[error("StuffDocumentsChainError: {0}")]
+    StuffDocumentsChainError(#[from] StuffDocumentsChainError),
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    #[error("The vector store returned no documents for the question")]
+    NoDocuments,
+}
+
+/// The result of a retrieval QA chain.
+pub struct RetrievalQaOutput<M>
+where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// The answer of the model.
+    pub answer: String,
+    /// The documents the model was given, in the order they appeared in the prompt.
+    pub source_documents: Vec<Document<M>>,
+    /// In citation mode, the indices into `source_documents` of the cited documents, in the order they were
+    /// first cited. Empty otherwise.
+    pub citations: Vec<usize>,
+}
+
+impl<M> RetrievalQaOutput<M>
+where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Returns whether the answer cites any document. In citation mode, an uncited answer usually means the model
+    /// didn't find the answer in the documents.
+    pub fn is_cited(&self) -> bool {
+        !self.citations.is_empty()
+    }
+}
+
+/// Finds the citations in an answer, like `[1]`, `[1][3]` or `[1, 3]`.
+///
+/// Returns the cited numbers in the order they are first cited, without duplicates.
+pub fn parse_citations(text: &str) -> Vec<usize> {
+    let mut citations = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find('[') {
+        rest = &rest[start + 1..];
+        let Some(end) = rest.find(']') else {
+            break;
+        };
+        let numbers: Option<Vec<usize>> = rest[..end]
+            .split(',')
+            .map(|number| number.trim().parse().ok())
+            .collect();
+        for number in numbers.unwrap_or_default() {
+            if !citations.contains(&number) {
+                citations.push(number);
+            }
+        }
+        rest = &rest[end + 1..];
+    }
+    citations
+}
+
+/// A chain answering questions over the documents of a vector store.
+pub struct Chain<E, V, M = EmptyMetadata>
+where
+    E: Embeddings,
+    V: VectorStore<E, M>,
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    store: V,
+    step: Option<Step>,
+    document_template: Option<StringTemplate>,
+    limit: u32,
+    citations: bool,
+    callbacks: Callbacks,
+    _embeddings: PhantomData<E>,
+    _metadata: PhantomData<M>,
+}
+
+impl<E, V, M> Chain<E, V, M>
+where
+    E: Embeddings,
+    V: VectorStore<E, M>,
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Creates a new retrieval QA chain searching the given vector store, with the default prompt.
+    pub fn new(store: V) -> Self {
+        Chain {
+            store,
+            step: None,
+            document_template: None,
+            limit: DEFAULT_LIMIT,
+            citations: false,
+            callbacks: Callbacks::default(),
+            _embeddings: PhantomData,
+            _metadata: PhantomData,
+        }
+    }
+
+    /// Replaces the step answering the question.
+    ///
+    /// The step is formatted with the question as `question` and the retrieved documents as `context`.
+    pub fn with_step(mut self, step: Step) -> Self {
+        self.step = Some(step);
+        self
+    }
+
+    /// Replaces the template every document is formatted with, see `stuff::Chain::with_document_template`.
+    pub fn with_document_template(mut self, document_template: StringTemplate) -> Self {
+        self.document_template = Some(document_template);
+        self
+    }
+
+    /// Sets the number of documents retrieved from the vector store. Defaults to 4.
+    pub fn with_limit(mut self, limit: u32) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Turns on citation mode: the documents are numbered and the answer must cite the documents it relies on.
+    ///
+    /// The default prompt and document template are replaced by ones numbering the documents. A custom step should
+    /// ask for citations in the format understood by `parse_citations`.
+    pub fn with_citations(mut self) -> Self {
+        self.citations = true;
+        self
+    }
+
+    /// Attaches callbacks to the chain. The step is reported as `stuff`.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Self {
+        self.callbacks = callbacks;
+        self
+    }
+
+    pub fn store(&self) -> &V {
+        &self.store
+    }
+
+    fn stuff_chain(&self) -> stuff::Chain {
+        let step = self.step.clone().unwrap_or_else(|| {
+            let template = if self.citations {
+                CITATION_QA_PROMPT
+            } else {
+                QA_PROMPT
+            };
+            Step::for_prompt_template(prompt!(template))
+        });
+        let mut chain = stuff::Chain::new(step).with_callbacks(self.callbacks.clone());
+        if let Some(document_template) = &self.document_template {
+            chain = chain.with_document_template(document_template.clone());
+        } else if self.citations {
+            chain = chain.with_document_template(StringTemplate::tera(CITATION_DOCUMENT_TEMPLATE));
+        }
+        chain
+    }
+
+    /// Answers the question using the documents of the vector store most similar to it.
+    pub async fn run<X: Executor>(
+        &self,
+        question: &str,
+        executor: &X,
+    ) -> Result<RetrievalQaOutput<M>, RetrievalQaChainError<V::Error>> {
+        let output = self.try_run(question, executor).await;
+        // Errors of the stuff-documents chain have already been reported by it.
+        if let Err(err) = &output {
+            if !matches!(
+                err,
+                RetrievalQaChainError::FormatAndExecuteError(_)
+                    | RetrievalQaChainError::StuffDocumentsChainError(_)
+            ) {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+            }
+        }
+        output
+    }
+
+    async fn try_run<X: Executor>(
+        &self,
+        question: &str,
+        executor: &X,
+    ) -> Result<RetrievalQaOutput<M>, RetrievalQaChainError<V::Error>> {
+        let documents = self
+            .store
+            .similarity_search(question.to_string(), self.limit)
+            .await
+            .map_err(RetrievalQaChainError::VectorStoreError)?;
+        if documents.is_empty() {
+            return Err(RetrievalQaChainError::NoDocuments);
+        }
+
+        let parameters = Parameters::new().with("question", question);
+        let stuffed = self
+            .stuff_chain()
+            .run(documents, parameters, executor)
+            .await?;
+        let answer = stuffed
+            .output
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .primary_textual_output()
+            .unwrap_or_default();
+
+        let citations = if self.citations {
+            cited_documents(&answer, stuffed.source_documents.len())
+        } else {
+            vec![]
+        };
+        Ok(RetrievalQaOutput {
+            answer,
+            source_documents: stuffed.source_documents,
+            citations,
+        })
+    }
+}
+
+/// Returns the indices of the documents cited by an answer given `documents` numbered documents.
+///
+/// Numbers outside of `1..=documents` aren't citations of the given documents, and are ignored.
+fn cited_documents(answer: &str, documents: usize) -> Vec<usize> {
+    parse_citations(answer)
+        .into_iter()
+        .filter(|citation| (1..=documents).contains(citation))
+        .map(|citation| citation - 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cited_documents, parse_citations, Chain, RetrievalQaChainError};
+    use crate::callbacks::{Callbacks, Event};
+    use crate::testing::{ScriptedExecutor, WordStore};
+    use crate::traits::VectorStore;
+
+    async fn store() -> WordStore {
+        let store = WordStore::default();
+        store
+            .add_texts(vec![
+                "Moby Dick was written by Melville".to_string(),
+                "Tea is best served hot".to_string(),
+                "Moby Dick was published in 1851".to_string(),
+            ])
+            .await
+            .unwrap();
+        store
+    }
+
+    #[test]
+    fn test_parses_citations() {
+        assert_eq!(
+            parse_citations("Melville wrote it [2][1], in 1851 [1, 3]. See [note]."),
+            vec![2, 1, 3]
+        );
+        assert!(parse_citations("No sources.").is_empty());
+    }
+
+    #[test]
+    fn test_keeps_citations_of_given_documents() {
+        assert_eq!(cited_documents("It was Melville [2].", 3), vec![1]);
+        assert!(cited_documents("Melville [4], in [1851] [0].", 3).is_empty());
+        assert!(cited_documents("I don't know.", 3).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_answers_with_the_retrieved_documents() {
+        let executor = ScriptedExecutor::new(|prompt| {
+            assert!(prompt.contains("[1] Moby Dick was written by Melville"));
+            assert!(prompt.contains("[2] Moby Dick was published in 1851"));
+            assert!(!prompt.contains("Tea"));
+            "Melville [1], in [1851] [2].".to_string()
+        });
+        let result = Chain::new(store().await)
+            .with_citations()
+            .run("who wrote Moby Dick?", &executor)
+            .await
+            .unwrap();
+
+        assert_eq!(result.answer, "Melville [1], in [1851] [2].");
+        assert_eq!(result.source_documents.len(), 2);
+        assert_eq!(result.citations, vec![0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_reports_questions_without_documents() {
+        let (callbacks, mut events) = Callbacks::channel();
+        let executor = ScriptedExecutor::new(|_| "answer".to_string());
+        let result = Chain::new(store().await)
+            .with_callbacks(callbacks)
+            .run("where lies Zambia?", &executor)
+            .await;
+
+        assert!(matches!(result, Err(RetrievalQaChainError::NoDocuments)));
+        assert!(matches!(events.try_recv(), Ok(Event::Error { .. })));
+        assert_eq!(executor.calls(), 0);
+    }
+}
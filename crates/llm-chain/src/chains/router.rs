@@ -0,0 +1,317 @@
+//! A router chain lets the model pick which of several sub-chains should handle the input.
+//!
+//! The input is first sent to a router step, together with the names and descriptions of the destinations. The
+//! model answers with the destination it chose and optionally a rewritten input, which is then run through the
+//! sub-chain of that destination. Answers that don't name a known destination go to the default chain.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let chain = Chain::new(
+//!     vec![
+//!         Destination::new("billing", "Questions about invoices and payments", billing_chain),
+//!         Destination::new("technical", "Questions about installing and using the product", technical_chain),
+//!     ],
+//!     general_chain,
+//! );
+//!
+//! let routed = chain.run(parameters!("Why was I charged twice?"), &executor).await?;
+//! println!("{:?}: {}", routed.destination, routed.output);
+//! ```
+
+use serde::{Deserialize, Serialize};
+
+use super::sequential::{self, SequentialChainError};
+use crate::callbacks::Callbacks;
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::output::Output;
+use crate::parsing::extract_labeled_text;
+use crate::{prompt, serialization::StorableEntity, step::Step, traits::Executor, Parameters};
+
+const ROUTER_PROMPT: &str = "Given a raw text input to a language model, select the destination best suited for the input. You will be given the names of the available destinations and a description of what each destination is best suited for. You may also revise the original input if you think that revising it will lead to a better response.
+
+Destinations:
+{{destinations}}
+
+Respond in the following format:
+- Destination: the name of the destination to use, or DEFAULT if none of them is suited for the input
+- Next Input: a potentially revised version of the original input
+
+Input: {{text}}";
+
+/// The `RouterChainError` enum represents errors that can occur when executing a router chain.
+#[derive(thiserror::Error, Debug)]
+pub enum RouterChainError {
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    #[error("SequentialChainError: {0}")]
+    SequentialChainError(#[from] SequentialChainError),
+}
+
+/// A sub-chain the router can send the input to.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Destination {
+    name: String,
+    description: String,
+    chain: sequential::Chain,
+}
+
+impl Destination {
+    /// Creates a destination. The description tells the model which inputs the destination is suited for.
+    pub fn new<N: Into<String>, D: Into<String>>(
+        name: N,
+        description: D,
+        chain: sequential::Chain,
+    ) -> Destination {
+        Destination {
+            name: name.into(),
+            description: description.into(),
+            chain,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+}
+
+/// The route chosen by the router step.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Route {
+    /// The destination named by the model, if any.
+    pub destination: Option<String>,
+    /// The rewritten input, if the model provided one.
+    pub next_input: Option<String>,
+}
+
+/// Parses the answer of the router step, which labels the destination with `Destination:` and the rewritten
+/// input with `Next Input:`. Labels are matched case-insensitively.
+pub fn parse_route(text: &str) -> Route {
+    let mut route = Route {
+        destination: None,
+        next_input: None,
+    };
+    for (label, value) in extract_labeled_text(text) {
+        let value = value.trim().to_string();
+        if value.is_empty() {
+            continue;
+        }
+        match label.to_lowercase().as_str() {
+            "destination" => route.destination = route.destination.or(Some(value)),
+            "next input" | "next inputs" => route.next_input = route.next_input.or(Some(value)),
+            _ => {}
+        }
+    }
+    route
+}
+
+/// The result of a router chain: the destination the input was sent to and the output of its chain.
+pub struct RoutedOutput {
+    /// The name of the destination, or `None` if the input went to the default chain.
+    pub destination: Option<String>,
+    pub output: Output,
+}
+
+/// A chain that asks the model to pick a destination and runs the sub-chain of that destination.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chain {
+    router: Step,
+    destinations: Vec<Destination>,
+    default: sequential::Chain,
+    #[serde(skip)]
+    callbacks: Callbacks,
+}
+
+impl Chain {
+    /// Creates a new router chain with the default router prompt.
+    ///
+    /// # Arguments
+    ///
+    /// * `destinations` - The sub-chains the router can choose from.
+    /// * `default` - The chain used when the model doesn't choose one of the destinations.
+    pub fn new(destinations: Vec<Destination>, default: sequential::Chain) -> Chain {
+        Chain {
+            router: Step::for_prompt_template(prompt!(ROUTER_PROMPT)),
+            destinations,
+            default,
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Replaces the router step.
+    ///
+    /// The prompt is formatted with the input parameters and `destinations`, a list of the destinations and their
+    /// descriptions. The model must answer in the format understood by `parse_route`.
+    pub fn with_router(mut self, router: Step) -> Chain {
+        self.router = router;
+        self
+    }
+
+    /// Attaches callbacks to the chain. The router step is reported as `router`.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    fn describe_destinations(&self) -> String {
+        self.destinations
+            .iter()
+            .map(|destination| format!("{}: {}", destination.name, destination.description))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Asks the router step for a route, without running any destination.
+    pub async fn route<E: Executor>(
+        &self,
+        parameters: &Parameters,
+        executor: &E,
+    ) -> Result<Route, RouterChainError> {
+        let parameters = parameters.with("destinations", self.describe_destinations());
+        let answer = Frame::new(executor, &self.router)
+            .with_callbacks(self.callbacks.clone())
+            .with_name("router")
+            .format_and_execute(&parameters)
+            .await?
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .primary_textual_output()
+            .unwrap_or_default();
+        Ok(parse_route(&answer))
+    }
+
+    /// Routes the input and runs the chosen destination.
+    ///
+    /// If the model rewrote the input, the destination is run with the rewritten input as `text`.
+    pub async fn run<E: Executor>(
+        &self,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<RoutedOutput, RouterChainError> {
+        let route = self.route(&parameters, executor).await?;
+        let destination = route.destination.and_then(|name| {
+            self.destinations
+                .iter()
+                .find(|destination| destination.name.eq_ignore_ascii_case(name.trim()))
+        });
+        let chain = destination.map_or(&self.default, |destination| &destination.chain);
+        let parameters = match route.next_input {
+            Some(next_input) => parameters.with_text(next_input),
+            None => parameters,
+        };
+        let output = chain
+            .clone()
+            .with_callbacks(self.callbacks.clone())
+            .run(parameters, executor)
+            .await?;
+        Ok(RoutedOutput {
+            destination: destination.map(|destination| destination.name.clone()),
+            output,
+        })
+    }
+}
+
+impl StorableEntity for Chain {
+    fn get_metadata() -> Vec<(String, String)> {
+        let base = vec![(
+            "chain-type".to_string(),
+            "llm-chain::chains::router::Chain".to_string(),
+        )];
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_route, Chain, Destination, Route, RoutedOutput};
+    use crate::{chains::sequential, prompt, step::Step, testing::ScriptedExecutor, Parameters};
+
+    fn chain() -> Chain {
+        let step = |template: &str| Step::for_prompt_template(prompt!(template));
+        Chain::new(
+            vec![Destination::new(
+                "billing",
+                "Questions about invoices and payments",
+                sequential::Chain::new(vec![step("billing: {{text}}")]),
+            )],
+            sequential::Chain::new(vec![step("general: {{text}}")]),
+        )
+    }
+
+    /// Answers the router prompt with `route` and echoes every other prompt.
+    fn executor(route: &'static str) -> ScriptedExecutor {
+        ScriptedExecutor::new(move |prompt| {
+            if prompt.contains("Destinations:") {
+                route.to_string()
+            } else {
+                prompt.to_string()
+            }
+        })
+    }
+
+    async fn text(routed: RoutedOutput) -> String {
+        routed
+            .output
+            .to_immediate()
+            .await
+            .unwrap()
+            .primary_textual_output()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_runs_the_chosen_destination_with_the_next_input() {
+        let executor = executor("- Destination: Billing\n- Next Input: Why was I charged twice?");
+        let routed = chain()
+            .run(Parameters::new_with_text("charged 2x??"), &executor)
+            .await
+            .unwrap();
+
+        assert_eq!(routed.destination.as_deref(), Some("billing"));
+        assert_eq!(text(routed).await, "billing: Why was I charged twice?");
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_the_default_chain() {
+        for route in ["- Destination: shipping", "DEFAULT", "I am not sure"] {
+            let routed = chain()
+                .run(Parameters::new_with_text("Hello"), &executor(route))
+                .await
+                .unwrap();
+
+            assert_eq!(routed.destination, None);
+            assert_eq!(text(routed).await, "general: Hello");
+        }
+    }
+
+    #[test]
+    fn test_parses_route() {
+        let text = "- Destination: billing
+- Next Input: Why was my card charged twice this month?";
+        assert_eq!(
+            parse_route(text),
+            Route {
+                destination: Some("billing".to_string()),
+                next_input: Some("Why was my card charged twice this month?".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_parses_route_without_next_input() {
+        let text = "**destination**: technical";
+        assert_eq!(
+            parse_route(text),
+            Route {
+                destination: Some("technical".to_string()),
+                next_input: None,
+            }
+        );
+        assert_eq!(parse_route("I don't know").destination, None);
+    }
+}
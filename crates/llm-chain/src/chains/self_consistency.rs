@@ -0,0 +1,254 @@
+//! The `self_consistency` module contains the `Chain` struct, which samples several answers to a step and keeps
+//! the most common one.
+//!
+//! Asking the same question several times with some temperature and taking a vote is a cheap way to get more
+//! accurate answers to classification and math-style prompts, and the share of the votes the winner got is a
+//! useful confidence signal. The answers are normalized by an extractor before they are counted, so "42", "42."
+//! and "The answer is 42" can all count as the same vote.
+//!
+//! The samples are generated with `Executor::execute_n`, which is a single request for executors supporting
+//! several choices per request, like OpenAI, and concurrent calls otherwise.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let chain = Chain::new(step, |answer| {
+//!     answer.split_whitespace().last().map(|word| word.trim_matches('.').to_lowercase())
+//! })
+//! .with_samples(7);
+//! let vote = chain.run(parameters!("Is this review positive or negative? ..."), &executor).await?;
+//! println!("{} ({:.0}% of the votes)", vote.answer, vote.confidence() * 100.0);
+//! ```
+
+use std::sync::Arc;
+
+use futures::future::try_join_all;
+use thiserror::Error;
+
+use crate::callbacks::{Callbacks, Event};
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::options::Opt;
+use crate::{step::Step, traits::Executor, Parameters};
+
+/// The number of answers sampled by default.
+const DEFAULT_SAMPLES: usize = 5;
+
+/// The temperature answers are sampled with, unless the step sets its own.
+const DEFAULT_TEMPERATURE: f32 = 0.7;
+
+/// The `SelfConsistencyChainError` enum represents errors that can occur when executing a self-consistency chain.
+#[derive(Error, Debug)]
+pub enum SelfConsistencyChainError {
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    /// The extractor found no answer in any of the samples. The samples are included for debugging.
+    #[error("No answer could be extracted from any of the {} samples", .samples.len())]
+    NoAnswer { samples: Vec<String> },
+}
+
+/// The result of a vote between sampled answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Vote {
+    /// The most common answer, as returned by the extractor. Ties go to the answer sampled first.
+    pub answer: String,
+    /// The number of samples giving the winning answer.
+    pub votes: usize,
+    /// Every extracted answer with its number of votes, most votes first.
+    pub distribution: Vec<(String, usize)>,
+    /// The raw samples, in the order they were returned, including those no answer was extracted from.
+    pub samples: Vec<String>,
+}
+
+impl Vote {
+    /// The share of the samples giving the winning answer, between 0 and 1.
+    ///
+    /// Samples no answer could be extracted from count as votes against the winner.
+    pub fn confidence(&self) -> f64 {
+        self.votes as f64 / self.samples.len() as f64
+    }
+}
+
+type Extractor = Arc<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// A chain sampling a step several times and voting on the answers.
+#[derive(Clone)]
+pub struct Chain {
+    step: Step,
+    extractor: Extractor,
+    samples: usize,
+    temperature: f32,
+    callbacks: Callbacks,
+}
+
+impl std::fmt::Debug for Chain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Chain")
+            .field("step", &self.step)
+            .field("samples", &self.samples)
+            .field("temperature", &self.temperature)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Chain {
+    /// Creates a new chain sampling `step`, with `extractor` turning every sample into the answer it votes for.
+    ///
+    /// The extractor returns `None` for samples without a usable answer, which then don't vote.
+    pub fn new<F>(step: Step, extractor: F) -> Chain
+    where
+        F: Fn(&str) -> Option<String> + Send + Sync + 'static,
+    {
+        Chain {
+            step,
+            extractor: Arc::new(extractor),
+            samples: DEFAULT_SAMPLES,
+            temperature: DEFAULT_TEMPERATURE,
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Sets the number of answers to sample. Defaults to 5.
+    pub fn with_samples(mut self, samples: usize) -> Chain {
+        self.samples = samples.max(1);
+        self
+    }
+
+    /// Sets the temperature answers are sampled with, unless the step sets its own. Defaults to 0.7, since
+    /// sampling without temperature tends to give the same answer every time.
+    pub fn with_temperature(mut self, temperature: f32) -> Chain {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Attaches callbacks to the chain. The samples are reported as `sample 1`, `sample 2` and so on.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Samples the step and returns the most common answer.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SelfConsistencyChainError::NoAnswer` if no answer could be extracted from any sample.
+    pub async fn run<E: Executor + Sync>(
+        &self,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<Vote, SelfConsistencyChainError> {
+        let output = self.try_run(parameters, executor).await;
+        // Errors of the step itself have already been reported by its frame.
+        if let Err(err) = &output {
+            if !matches!(err, SelfConsistencyChainError::FormatAndExecuteError(_)) {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+            }
+        }
+        output
+    }
+
+    async fn try_run<E: Executor + Sync>(
+        &self,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<Vote, SelfConsistencyChainError> {
+        let options = self
+            .step
+            .options()
+            .with_default(Opt::Temperature(self.temperature));
+        let step = Step::for_prompt_and_options(self.step.prompt().clone(), options);
+        let outputs = Frame::new(executor, &step)
+            .with_callbacks(self.callbacks.clone())
+            .with_name("sample")
+            .format_and_execute_n(&parameters, self.samples)
+            .await?;
+        let samples = try_join_all(outputs.into_iter().map(|output| output.to_immediate()))
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .into_iter()
+            .map(|output| output.primary_textual_output().unwrap_or_default())
+            .collect::<Vec<_>>();
+        self.count(samples)
+    }
+
+    fn count(&self, samples: Vec<String>) -> Result<Vote, SelfConsistencyChainError> {
+        // Kept in the order answers first appeared, so the stable sort below breaks ties in favor of earlier ones.
+        let mut distribution: Vec<(String, usize)> = vec![];
+        for answer in samples.iter().filter_map(|sample| (self.extractor)(sample)) {
+            match distribution.iter_mut().find(|(seen, _)| *seen == answer) {
+                Some((_, votes)) => *votes += 1,
+                None => distribution.push((answer, 1)),
+            }
+        }
+        distribution.sort_by(|(_, a), (_, b)| b.cmp(a));
+        match distribution.first().cloned() {
+            Some((answer, votes)) => Ok(Vote {
+                answer,
+                votes,
+                distribution,
+                samples,
+            }),
+            None => Err(SelfConsistencyChainError::NoAnswer { samples }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::{Chain, SelfConsistencyChainError};
+    use crate::testing::ScriptedExecutor;
+    use crate::{prompt, step::Step, Parameters};
+
+    fn last_number(answer: &str) -> Option<String> {
+        answer
+            .split(|c: char| !c.is_ascii_digit())
+            .rfind(|word| !word.is_empty())
+            .map(str::to_string)
+    }
+
+    #[tokio::test]
+    async fn test_returns_the_majority_answer() {
+        let replies = [
+            "The answer is 4.",
+            "5",
+            "4",
+            "I don't know",
+            "It's 5... no, 4",
+        ];
+        let next = AtomicUsize::new(0);
+        let executor = ScriptedExecutor::new(move |_| {
+            replies[next.fetch_add(1, Ordering::SeqCst) % replies.len()].to_string()
+        });
+        let chain = Chain::new(
+            Step::for_prompt_template(prompt!("What is {{text}}?")),
+            last_number,
+        );
+        let vote = chain
+            .run(Parameters::new_with_text("2 + 2"), &executor)
+            .await
+            .unwrap();
+        assert_eq!(vote.answer, "4");
+        assert_eq!(vote.votes, 3);
+        assert_eq!(
+            vote.distribution,
+            vec![("4".to_string(), 3), ("5".to_string(), 1)]
+        );
+        assert_eq!(vote.confidence(), 0.6);
+        assert_eq!(executor.calls(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_fails_without_any_answer() {
+        let executor = ScriptedExecutor::new(|_| "No idea".to_string());
+        let chain =
+            Chain::new(Step::for_prompt_template(prompt!("Guess")), last_number).with_samples(3);
+        let result = chain.run(Parameters::new(), &executor).await;
+        assert!(matches!(
+            result,
+            Err(SelfConsistencyChainError::NoAnswer { samples }) if samples.len() == 3
+        ));
+    }
+}
@@ -0,0 +1,352 @@
+//! The `self_critique` module contains the `Chain` struct, which retries a step until its answer is accepted.
+//!
+//! The chain runs the step, then checks the answer with a `Validator`: a function, a parser, or a critic step
+//! asking the model itself to review the answer. A rejected answer is sent back to the model together with the
+//! reason it was rejected, and the model is asked to try again, until an answer is accepted or the attempts run
+//! out. This is the usual way to get output that reliably parses.
+//!
+//! # Example
+//!
+//! ```ignore
+//! #[derive(Deserialize)]
+//! struct Recipe {
+//!     name: String,
+//!     ingredients: Vec<String>,
+//! }
+//!
+//! let chain = Chain::new(step, Validator::parses::<Recipe>()).with_max_attempts(3);
+//! let output = chain.run(parameters!("pancakes"), &executor).await?;
+//! let recipe: Recipe = find_yaml(&output.answer)?.remove(0);
+//! ```
+
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+use crate::callbacks::{Callbacks, Event};
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::parsing::find_yaml;
+use crate::prompt::{Data, StringTemplate};
+use crate::{step::Step, traits::Executor, Parameters};
+
+/// The number of attempts made by default.
+const DEFAULT_MAX_ATTEMPTS: usize = 3;
+
+/// The `SelfCritiqueChainError` enum represents errors that can occur when executing a self-critique chain.
+#[derive(Error, Debug)]
+pub enum SelfCritiqueChainError {
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    /// Every attempt was rejected. The attempts are included for debugging.
+    #[error("No answer was accepted after {} attempts, the last one was rejected with: {}", .attempts.len(), last_error(.attempts))]
+    Rejected { attempts: Vec<Attempt> },
+}
+
+fn last_error(attempts: &[Attempt]) -> &str {
+    attempts
+        .last()
+        .and_then(|attempt| attempt.error.as_deref())
+        .unwrap_or_default()
+}
+
+/// An answer produced by the step, and the reason it was rejected, if it was.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attempt {
+    pub answer: String,
+    pub error: Option<String>,
+}
+
+/// The result of a self-critique chain.
+#[derive(Debug, Clone)]
+pub struct SelfCritiqueOutput {
+    /// The accepted answer.
+    pub answer: String,
+    /// Every attempt, in order. The last one is the accepted answer.
+    pub attempts: Vec<Attempt>,
+}
+
+type Check = Arc<dyn Fn(&str) -> Result<(), String> + Send + Sync>;
+
+/// Decides whether an answer is accepted.
+#[derive(Clone)]
+pub enum Validator {
+    /// Accepts the answer if the function returns `Ok`, and otherwise rejects it with the returned reason.
+    Check(Check),
+    /// Asks the model to review the answer with a critic step, see `Validator::critic`.
+    Critic(Step),
+}
+
+impl std::fmt::Debug for Validator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Validator::Check(_) => f.write_str("Check"),
+            Validator::Critic(step) => f.debug_tuple("Critic").field(step).finish(),
+        }
+    }
+}
+
+impl Validator {
+    /// A validator calling the given function on every answer.
+    pub fn check<F>(check: F) -> Self
+    where
+        F: Fn(&str) -> Result<(), String> + Send + Sync + 'static,
+    {
+        Validator::Check(Arc::new(check))
+    }
+
+    /// A validator accepting answers containing YAML or JSON that deserializes to `T`, as found by `find_yaml`.
+    pub fn parses<T: DeserializeOwned>() -> Self {
+        Self::check(|answer| {
+            find_yaml::<T>(answer)
+                .map(|_| ())
+                .map_err(|err| err.to_string())
+        })
+    }
+
+    /// A validator asking the model to review every answer.
+    ///
+    /// The critic step is formatted with the parameters passed to `run` and the answer as `answer`. It accepts
+    /// the answer by responding with `APPROVED`, and rejects it by responding with anything else, which is
+    /// given to the model as the reason.
+    pub fn critic(step: Step) -> Self {
+        Validator::Critic(step)
+    }
+}
+
+/// Reads the response of a critic step: `APPROVED` accepts the answer, anything else is the reason to reject it.
+pub fn parse_verdict(response: &str) -> Result<(), String> {
+    let response = response.trim();
+    if response
+        .get(..8)
+        .is_some_and(|verdict| verdict.eq_ignore_ascii_case("approved"))
+    {
+        Ok(())
+    } else {
+        Err(response.to_string())
+    }
+}
+
+/// A chain re-asking a step until its answer is accepted by a validator.
+#[derive(Clone, Debug)]
+pub struct Chain {
+    step: Step,
+    validator: Validator,
+    max_attempts: usize,
+    callbacks: Callbacks,
+}
+
+impl Chain {
+    /// Creates a new chain checking the answers of `step` with `validator`.
+    pub fn new(step: Step, validator: Validator) -> Chain {
+        Chain {
+            step,
+            validator,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Sets the number of answers generated before giving up, including the first one. Defaults to 3.
+    pub fn with_max_attempts(mut self, max_attempts: usize) -> Chain {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Attaches callbacks to the chain. The attempts are reported as `attempt 1`, `attempt 2` and so on, and the
+    /// reviews of a critic step as `critic 1`, `critic 2` and so on.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Runs the step until an answer is accepted.
+    ///
+    /// # Errors
+    ///
+    /// Returns `SelfCritiqueChainError::Rejected`, with every attempt, if no answer was accepted.
+    pub async fn run<E: Executor>(
+        &self,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<SelfCritiqueOutput, SelfCritiqueChainError> {
+        let output = self.try_run(parameters, executor).await;
+        // Errors of the steps themselves have already been reported by their frames.
+        if let Err(err) = &output {
+            if !matches!(err, SelfCritiqueChainError::FormatAndExecuteError(_)) {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+            }
+        }
+        output
+    }
+
+    async fn try_run<E: Executor>(
+        &self,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<SelfCritiqueOutput, SelfCritiqueChainError> {
+        let mut attempts: Vec<Attempt> = vec![];
+        for number in 1..=self.max_attempts {
+            let step = match attempts.last() {
+                None => self.step.clone(),
+                Some(rejected) => self.reask(rejected),
+            };
+            let answer = self
+                .execute(executor, &step, format!("attempt {}", number), &parameters)
+                .await?;
+            let verdict = self
+                .validate(executor, &answer, number, &parameters)
+                .await?;
+            attempts.push(Attempt {
+                answer,
+                error: verdict.err(),
+            });
+            let last = attempts.last().expect("an attempt was just added");
+            if last.error.is_none() {
+                return Ok(SelfCritiqueOutput {
+                    answer: last.answer.clone(),
+                    attempts,
+                });
+            }
+        }
+        Err(SelfCritiqueChainError::Rejected { attempts })
+    }
+
+    async fn validate<E: Executor>(
+        &self,
+        executor: &E,
+        answer: &str,
+        number: usize,
+        parameters: &Parameters,
+    ) -> Result<Result<(), String>, SelfCritiqueChainError> {
+        match &self.validator {
+            Validator::Check(check) => Ok(check(answer)),
+            Validator::Critic(critic) => {
+                let response = self
+                    .execute(
+                        executor,
+                        critic,
+                        format!("critic {}", number),
+                        &parameters.with("answer", answer),
+                    )
+                    .await?;
+                Ok(parse_verdict(&response))
+            }
+        }
+    }
+
+    /// Returns the step asking again after `rejected`: the original prompt, followed by the rejected answer and
+    /// the reason it was rejected.
+    fn reask(&self, rejected: &Attempt) -> Step {
+        let error = rejected.error.as_deref().unwrap_or_default();
+        // The answer and the error are inserted as they are, so braces in them aren't taken for template syntax.
+        let prompt = match self.step.prompt() {
+            Data::Chat(chat) => Data::Chat(
+                chat.clone()
+                    .with_assistant(StringTemplate::static_string(rejected.answer.clone()))
+                    .with_user(StringTemplate::static_string(format!(
+                        "Your answer was rejected: {}\n\nPlease answer again, fixing the problem.",
+                        error
+                    ))),
+            ),
+            Data::Text(text) => Data::Text(StringTemplate::combine(vec![
+                text.clone(),
+                StringTemplate::static_string(format!(
+                    "\n\nYour previous answer was:\n{}\n\nIt was rejected: {}\n\nPlease answer again, fixing the problem.",
+                    rejected.answer, error
+                )),
+            ])),
+        };
+        Step::for_prompt_and_options(prompt, self.step.options().clone())
+    }
+
+    async fn execute<E: Executor>(
+        &self,
+        executor: &E,
+        step: &Step,
+        name: String,
+        parameters: &Parameters,
+    ) -> Result<String, SelfCritiqueChainError> {
+        Ok(Frame::new(executor, step)
+            .with_callbacks(self.callbacks.clone())
+            .with_name(name)
+            .format_and_execute(parameters)
+            .await?
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::Execute)?
+            .primary_textual_output()
+            .unwrap_or_default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::{parse_verdict, Chain, SelfCritiqueChainError, Validator};
+    use crate::testing::ScriptedExecutor;
+    use crate::{prompt, step::Step, Parameters};
+
+    #[derive(Deserialize)]
+    #[allow(dead_code)]
+    struct Answer {
+        value: u32,
+    }
+
+    #[test]
+    fn test_parses_verdicts() {
+        assert_eq!(parse_verdict("  Approved. Looks good."), Ok(()));
+        assert_eq!(
+            parse_verdict("The total is wrong"),
+            Err("The total is wrong".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reasks_until_the_answer_parses() {
+        let executor = ScriptedExecutor::new(|prompt| {
+            if prompt.contains("rejected") {
+                "value: 42".to_string()
+            } else {
+                "It's 42!".to_string()
+            }
+        });
+        let chain = Chain::new(
+            Step::for_prompt_template(prompt!("What is {{text}}?")),
+            Validator::parses::<Answer>(),
+        );
+        let output = chain
+            .run(Parameters::new_with_text("the answer"), &executor)
+            .await
+            .unwrap();
+        assert_eq!(output.answer, "value: 42");
+        assert_eq!(output.attempts.len(), 2);
+        assert!(output.attempts[0].error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_reports_every_attempt_when_giving_up() {
+        let executor = ScriptedExecutor::new(|prompt| {
+            if prompt.starts_with("Review") {
+                "Too short".to_string()
+            } else {
+                "Hi".to_string()
+            }
+        });
+        let chain = Chain::new(
+            Step::for_prompt_template(prompt!("Write a greeting")),
+            Validator::critic(Step::for_prompt_template(prompt!("Review: {{answer}}"))),
+        )
+        .with_max_attempts(2);
+        let result = chain.run(Parameters::new(), &executor).await;
+        assert!(matches!(
+            result,
+            Err(SelfCritiqueChainError::Rejected { attempts })
+                if attempts.len() == 2 && attempts[1].error.as_deref() == Some("Too short")
+        ));
+        assert_eq!(executor.calls(), 4);
+    }
+}
@@ -24,12 +24,36 @@
 //! let result = chain.run(parameters, &executor).await;
 //! ```
 //!
+//! Steps can also name their outputs with `ChainStep`, so later steps can refer to every earlier result rather
+//! than only the latest `text`, and rename their inputs to match their prompt:
+//!
+//! ```ignore
+//! let chain = Chain::of_steps(vec![
+//!     ChainStep::new(Step::for_prompt_template(prompt!("Summarize: {{text}}"))).with_output_key("summary"),
+//!     ChainStep::new(Step::for_prompt_template(prompt!("List keywords for: {{article}}")))
+//!         .with_input("article", "summary")
+//!         .with_output_key("keywords"),
+//!     ChainStep::new(Step::for_prompt_template(prompt!("Write a tweet about {{summary}} using {{keywords}}")))
+//!         .with_output_key("tweet"),
+//! ]);
+//!
+//! let outputs = chain.run_with_outputs(parameters, &executor).await?;
+//! println!("{}", outputs.get("tweet").unwrap());
+//! ```
+//!
 //! This module also provides serialization and deserialization support for the `Chain` struct, allowing you to store and load chains using formats like JSON, YAML, or others.
 
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
+use crate::callbacks::{Callbacks, Event};
+use crate::checkpoint::{CheckpointError, Checkpointer};
+use crate::estimate::{placeholder_output, Estimate, StepEstimate};
 use crate::frame::FormatAndExecuteError;
 use crate::output::Output;
+use crate::prompt::Data;
+use crate::tokens::PromptTokensError;
 use crate::{
     frame::Frame, serialization::StorableEntity, step::Step, traits::Executor, Parameters,
 };
@@ -42,12 +66,94 @@ pub enum SequentialChainError {
     FormatAndExecuteError(#[from] FormatAndExecuteError),
     #[error("The vector of steps was empty")]
     NoSteps,
+    #[error("The input `{0}` was not produced by any previous step")]
+    MissingInput(String),
+    #[error("CheckpointError: {0}")]
+    CheckpointError(#[from] CheckpointError),
+    #[error("TokenizeError: {0}")]
+    TokenizeError(#[from] PromptTokensError),
+}
+
+/// A step in a sequential chain, optionally naming its output and renaming its inputs.
+///
+/// The output of every step is written to the `text` parameter. When the step declares an output key, the
+/// output is also stored under that key, so it stays available to all later steps.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChainStep {
+    #[serde(flatten)]
+    step: Step,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    output_key: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    input_mapping: BTreeMap<String, String>,
+}
+
+impl ChainStep {
+    pub fn new(step: Step) -> ChainStep {
+        ChainStep {
+            step,
+            output_key: None,
+            input_mapping: BTreeMap::new(),
+        }
+    }
+
+    /// Stores the output of the step under `key`, in addition to `text`.
+    pub fn with_output_key<K: Into<String>>(mut self, key: K) -> ChainStep {
+        self.output_key = Some(key.into());
+        self
+    }
+
+    /// Makes the parameter `source` available to the prompt of the step as `key`.
+    pub fn with_input<K: Into<String>, S: Into<String>>(mut self, key: K, source: S) -> ChainStep {
+        self.input_mapping.insert(key.into(), source.into());
+        self
+    }
+
+    pub fn step(&self) -> &Step {
+        &self.step
+    }
+
+    pub fn output_key(&self) -> Option<&str> {
+        self.output_key.as_deref()
+    }
+
+    /// Returns the parameters the step is formatted with, applying the input renames.
+    fn inputs(&self, parameters: &Parameters) -> Result<Parameters, SequentialChainError> {
+        let mut inputs = parameters.clone();
+        for (key, source) in &self.input_mapping {
+            let value = parameters
+                .get(source)
+                .ok_or_else(|| SequentialChainError::MissingInput(source.clone()))?;
+            inputs = inputs.with(key.clone(), value);
+        }
+        Ok(inputs)
+    }
+
+    /// Returns the parameters with the output of the step added.
+    fn outputs(&self, parameters: Parameters, output: String) -> Parameters {
+        match &self.output_key {
+            Some(key) => parameters
+                .with(key.clone(), output.clone())
+                .with_text(output),
+            None => parameters.with_text(output),
+        }
+    }
+}
+
+impl From<Step> for ChainStep {
+    fn from(step: Step) -> ChainStep {
+        ChainStep::new(step)
+    }
 }
 
 /// A sequential chain is a chain where each step is executed in order, with the output of the previous step being available to the next step.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Chain {
-    steps: Vec<Step>,
+    steps: Vec<ChainStep>,
+    #[serde(skip)]
+    callbacks: Callbacks,
+    #[serde(skip)]
+    checkpoints: Option<Checkpointer>,
 }
 
 impl Chain {
@@ -57,7 +163,20 @@ impl Chain {
     ///
     /// * `steps` - A vector of `Step<E>` objects that define the sequence of steps for the chain.
     pub fn new(steps: Vec<Step>) -> Chain {
-        Chain { steps }
+        Chain::of_steps(steps.into_iter().map(ChainStep::new).collect())
+    }
+
+    /// Creates a new `Chain` instance with the given sequence of steps, which may name their outputs and rename their inputs.
+    ///
+    /// # Arguments
+    ///
+    /// * `steps` - A vector of `ChainStep` objects that define the sequence of steps for the chain.
+    pub fn of_steps(steps: Vec<ChainStep>) -> Chain {
+        Chain {
+            steps,
+            callbacks: Callbacks::default(),
+            checkpoints: None,
+        }
     }
 
     /// Creates a new `Chain` instance with a single step.
@@ -66,7 +185,25 @@ impl Chain {
     ///
     /// * `step` - A `Step<E>` object that defines the single step for the chain.
     pub fn of_one(step: Step) -> Chain {
-        Chain { steps: vec![step] }
+        Chain::new(vec![step])
+    }
+
+    /// Attaches callbacks to the chain, which are notified as every step runs.
+    ///
+    /// The steps are reported under their output key, or as `step 1`, `step 2` and so on.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Saves the output of every step with the given checkpointer, and reuses the outputs saved by an earlier run
+    /// with the same run id instead of executing those steps again.
+    ///
+    /// With checkpoints, `run` waits for the last step to finish rather than streaming its output, as the output
+    /// has to be saved.
+    pub fn with_checkpoints(mut self, checkpoints: Checkpointer) -> Chain {
+        self.checkpoints = Some(checkpoints);
+        self
     }
 
     /// Executes the chain with the given parameters and executor.
@@ -91,30 +228,170 @@ impl Chain {
     where
         E: Executor,
     {
+        let Some((last_step, steps)) = self.steps.split_last() else {
+            return Err(self.report(SequentialChainError::NoSteps));
+        };
+        let parameters = self.run_steps(steps, parameters, executor).await?;
+        let inputs = last_step
+            .inputs(&parameters)
+            .map_err(|err| self.report(err))?;
+        let idx = self.steps.len() - 1;
+        if self.checkpoints.is_some() {
+            let body = self.execute(executor, last_step, idx, &inputs).await?;
+            return Ok(Output::new_immediate(Data::Text(body)));
+        }
+        Ok(self
+            .frame(executor, last_step, idx)
+            .format_and_execute(&inputs)
+            .await?)
+    }
+
+    /// Executes every step of the chain and returns the parameters with all outputs added.
+    ///
+    /// Unlike `run`, which returns the output of the last step, this exposes the outputs of all steps with an
+    /// output key, with the output of the last step also available as `text`.
+    ///
+    /// # Arguments
+    ///
+    /// * `parameters` - A `Parameters` object containing the input parameters for the chain.
+    /// * `executor` - A reference to an executor that implements the `Executor` trait.
+    pub async fn run_with_outputs<E>(
+        &self,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<Parameters, SequentialChainError>
+    where
+        E: Executor,
+    {
+        if self.steps.is_empty() {
+            return Err(self.report(SequentialChainError::NoSteps));
+        }
+        self.run_steps(&self.steps, parameters, executor).await
+    }
+
+    /// Estimates what running the chain with the given parameters would take, without calling the model.
+    ///
+    /// Every prompt is formatted and its tokens counted with the executor. The outputs of the steps aren't known,
+    /// so they are replaced by placeholders as long as the assumed output of the step, see `estimate`.
+    pub fn dry_run<E: Executor>(
+        &self,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<Estimate, SequentialChainError> {
         if self.steps.is_empty() {
             return Err(SequentialChainError::NoSteps);
         }
+        let mut estimate = Estimate::default();
         let mut current_params = parameters;
+        for (idx, step) in self.steps.iter().enumerate() {
+            let options = step.step.options();
+            let prompt = step
+                .step
+                .format(&step.inputs(&current_params)?)
+                .map_err(FormatAndExecuteError::Format)?;
+            let mut step_estimate =
+                StepEstimate::new(step_name(step, idx), executor.max_tokens_allowed(options));
+            step_estimate.add_call(
+                options,
+                executor.tokens_used(options, &prompt)?.tokens_used(),
+            );
+            estimate.steps.push(step_estimate);
+            let placeholder = placeholder_output(executor, options)?;
+            current_params = step.outputs(current_params, placeholder);
+        }
+        Ok(estimate)
+    }
 
-        for step in &self.steps[..self.steps.len() - 1] {
-            let body = Frame::new(executor, step)
-                .format_and_execute(&current_params)
-                .await?
-                .to_immediate()
+    async fn run_steps<E: Executor>(
+        &self,
+        steps: &[ChainStep],
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<Parameters, SequentialChainError> {
+        let mut current_params = parameters;
+        for (idx, step) in steps.iter().enumerate() {
+            let inputs = step
+                .inputs(&current_params)
+                .map_err(|err| self.report(err))?;
+            let body = self.execute(executor, step, idx, &inputs).await?;
+            current_params = step.outputs(current_params, body);
+        }
+        Ok(current_params)
+    }
+
+    /// Executes a step and returns its output, or returns its checkpointed output if an earlier run already
+    /// executed it.
+    async fn execute<E: Executor>(
+        &self,
+        executor: &E,
+        step: &ChainStep,
+        idx: usize,
+        inputs: &Parameters,
+    ) -> Result<String, SequentialChainError> {
+        let checkpoint = match &self.checkpoints {
+            Some(checkpoints) => {
+                let name = step_name(step, idx);
+                let key = step
+                    .step
+                    .format(inputs)
+                    .map_err(|err| SequentialChainError::from(FormatAndExecuteError::Format(err)))
+                    .and_then(|prompt| Ok(checkpoints.key(&name, step.step.options(), &prompt)?))
+                    .map_err(|err| self.report(err))?;
+                let saved = checkpoints
+                    .load(&key)
+                    .await
+                    .map_err(|err| self.report(err.into()))?;
+                if let Some(output) = saved {
+                    return Ok(output.extract_last_body().cloned().unwrap_or_default());
+                }
+                Some((checkpoints, name, key))
+            }
+            None => None,
+        };
+        let body = self
+            .frame(executor, step, idx)
+            .format_and_execute(inputs)
+            .await?
+            .to_immediate()
+            .await
+            .map_err(|err| {
+                SequentialChainError::FormatAndExecuteError(FormatAndExecuteError::Execute(err))
+            })?
+            .as_content();
+        if let Some((checkpoints, name, key)) = checkpoint {
+            checkpoints
+                .save(&key, &name, body.clone())
                 .await
-                .map_err(|err| {
-                    SequentialChainError::FormatAndExecuteError(FormatAndExecuteError::Execute(err))
-                })?
-                .as_content()
-                .extract_last_body()
-                .cloned()
-                .unwrap_or_default();
-            current_params = current_params.with_text(body);
+                .map_err(|err| self.report(err.into()))?;
         }
-        let last_step = self.steps.last().unwrap();
-        Ok(Frame::new(executor, last_step)
-            .format_and_execute(&current_params)
-            .await?)
+        Ok(body.extract_last_body().cloned().unwrap_or_default())
+    }
+
+    fn frame<'l, E: Executor>(
+        &self,
+        executor: &'l E,
+        step: &'l ChainStep,
+        idx: usize,
+    ) -> Frame<'l, E> {
+        Frame::new(executor, &step.step)
+            .with_callbacks(self.callbacks.clone())
+            .with_name(step_name(step, idx))
+    }
+
+    /// Reports an error that didn't occur in one of the steps to the callbacks.
+    fn report(&self, err: SequentialChainError) -> SequentialChainError {
+        self.callbacks.emit(Event::Error {
+            message: err.to_string(),
+        });
+        err
+    }
+}
+
+/// The name a step is reported and checkpointed under: its output key, or its position in the chain.
+fn step_name(step: &ChainStep, idx: usize) -> String {
+    match &step.output_key {
+        Some(key) => key.clone(),
+        None => format!("step {}", idx + 1),
     }
 }
 
@@ -127,3 +404,89 @@ impl StorableEntity for Chain {
         base
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Chain, ChainStep, SequentialChainError};
+    use crate::checkpoint::{Checkpointer, InMemoryCheckpointStore};
+    use crate::testing::ScriptedExecutor;
+    use crate::{parameters, prompt, step::Step, Parameters};
+
+    #[test]
+    fn test_renames_inputs_and_names_outputs() {
+        let step = ChainStep::new(Step::for_prompt_template(prompt!("{{topic}}")))
+            .with_input("topic", "summary")
+            .with_output_key("keywords");
+
+        let inputs = step.inputs(&parameters!("summary" => "Rust")).unwrap();
+        assert_eq!(inputs.get("topic").as_deref(), Some("Rust"));
+        assert!(matches!(
+            step.inputs(&parameters!()),
+            Err(SequentialChainError::MissingInput(source)) if source == "summary"
+        ));
+
+        let outputs = step.outputs(inputs, "ownership".to_string());
+        assert_eq!(outputs.get("keywords").as_deref(), Some("ownership"));
+        assert_eq!(outputs.get_text().as_deref(), Some("ownership"));
+        assert_eq!(outputs.get("summary").as_deref(), Some("Rust"));
+    }
+
+    #[test]
+    fn test_deserializes_plain_steps() {
+        let chain = Chain::new(vec![Step::for_prompt_template(prompt!("{{text}}"))]);
+        let json = serde_json::to_string(&chain).unwrap();
+        assert!(!json.contains("output_key"));
+        let chain: Chain = serde_json::from_str(&json).unwrap();
+        assert_eq!(chain.steps[0].output_key(), None);
+    }
+
+    #[tokio::test]
+    async fn test_resumes_from_checkpoints() {
+        let store = Arc::new(InMemoryCheckpointStore::new());
+        let chain = Chain::new(vec![
+            Step::for_prompt_template(prompt!("Summarize: {{text}}")),
+            Step::for_prompt_template(prompt!("Tweet: {{text}}")),
+        ])
+        .with_checkpoints(Checkpointer::new(store.clone(), "run"));
+
+        let executor = ScriptedExecutor::new(|prompt| prompt.to_uppercase());
+        let output = chain
+            .run(Parameters::new_with_text("rust"), &executor)
+            .await
+            .unwrap();
+        let first = output.to_immediate().await.unwrap().as_content();
+        assert_eq!(executor.calls(), 2);
+        assert_eq!(store.len(), 2);
+
+        let executor = ScriptedExecutor::new(|prompt| prompt.to_uppercase());
+        let output = chain
+            .run(Parameters::new_with_text("rust"), &executor)
+            .await
+            .unwrap();
+        let second = output.to_immediate().await.unwrap().as_content();
+        assert_eq!(executor.calls(), 0);
+        assert_eq!(second.to_text(), first.to_text());
+        assert_eq!(first.to_text(), "TWEET: SUMMARIZE: RUST");
+    }
+
+    #[test]
+    fn test_dry_run_counts_prompt_tokens() {
+        let chain = Chain::new(vec![
+            Step::for_prompt_template(prompt!("Translate {{text}}")),
+            Step::for_prompt_template(prompt!("Summarize {{text}}")),
+        ]);
+        let executor = ScriptedExecutor::new(|prompt| prompt.to_string());
+        let estimate = chain
+            .dry_run(Parameters::new_with_text("hej"), &executor)
+            .unwrap();
+
+        assert_eq!(estimate.calls(), 2);
+        // The output of the first step stands in as 36 placeholder words of 7 tokens.
+        assert_eq!(estimate.steps[0].prompt_tokens, "Translate hej".len());
+        assert_eq!(estimate.steps[1].prompt_tokens, "Summarize ".len() + 252);
+        assert_eq!(estimate.steps[1].name, "step 2");
+        assert_eq!(executor.calls(), 0);
+    }
+}
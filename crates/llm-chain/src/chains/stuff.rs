@@ -0,0 +1,302 @@
+//! The `stuff` module contains the `Chain` struct, which represents a stuff-documents chain.
+//!
+//! A stuff-documents chain packs as many documents as fit in the context window into a single prompt, and runs
+//! the step once. Every document is formatted with the document template, and the formatted documents are joined
+//! and passed to the step as `context`.
+//!
+//! It is the simplest way to answer a question over a handful of documents, such as the results of a similarity
+//! search. Documents that don't fit are left out, in order, so the most relevant documents should come first.
+//! Room is kept for the answer: the `MaxTokens` option of the step, or `ASSUMED_OUTPUT_TOKENS` for steps without it,
+//! unless the budget is set with `with_completion_tokens`.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let chain = Chain::new(Step::for_prompt_template(prompt!(
+//!     "Answer the question using the documents below.\n\n{{context}}\n\nQuestion: {{question}}"
+//! )));
+//!
+//! let result = chain.run(documents, parameters!("question" => "Who wrote it?"), &executor).await?;
+//! println!("{} (from {} documents)", result.output, result.source_documents.len());
+//! ```
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::callbacks::{Callbacks, Event};
+use crate::estimate::assumed_output_tokens;
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::output::Output;
+use crate::prompt::{StringTemplate, StringTemplateError};
+use crate::schema::Document;
+use crate::tokens::PromptTokensError;
+use crate::{serialization::StorableEntity, step::Step, traits::Executor, Parameters};
+
+/// The default template a document is formatted with.
+const DOCUMENT_TEMPLATE: &str = "{{page_content}}";
+
+/// The `StuffDocumentsChainError` enum represents errors that can occur when executing a stuff-documents chain.
+#[derive(Error, Debug)]
+pub enum StuffDocumentsChainError {
+    /// An error relating to the operation of the Executor.
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    /// An error relating to tokenizing the inputs.
+    #[error("TokenizeError: {0}")]
+    TokenizeError(#[from] PromptTokensError),
+    #[error("Error templating: {0}")]
+    StringTemplate(#[from] StringTemplateError),
+    #[error("The vector of input documents was empty")]
+    InputEmpty,
+    #[error("Not even the first document fits in the context window")]
+    DocumentTooLarge,
+}
+
+/// The result of a stuff-documents chain.
+pub struct StuffOutput<M>
+where
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// The output of the step.
+    pub output: Output,
+    /// The documents that were included in the prompt, in order.
+    pub source_documents: Vec<Document<M>>,
+}
+
+/// The `Chain` struct represents a stuff-documents chain.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Chain {
+    step: Step,
+    document_template: StringTemplate,
+    separator: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    completion_tokens: Option<usize>,
+    #[serde(skip)]
+    callbacks: Callbacks,
+}
+
+impl Chain {
+    /// Constructs a new `Chain` with the given step.
+    ///
+    /// The step is formatted with the parameters passed to `run` and the formatted documents as `context`.
+    pub fn new(step: Step) -> Chain {
+        Chain {
+            step,
+            document_template: StringTemplate::tera(DOCUMENT_TEMPLATE),
+            separator: "\n\n".to_string(),
+            completion_tokens: None,
+            callbacks: Callbacks::default(),
+        }
+    }
+
+    /// Replaces the template every document is formatted with.
+    ///
+    /// The template is formatted with the content of the document as `page_content`, the fields of its metadata,
+    /// see `Document::to_parameters`, and the position of the document, starting at 1, as `index`.
+    pub fn with_document_template(mut self, document_template: StringTemplate) -> Chain {
+        self.document_template = document_template;
+        self
+    }
+
+    /// Replaces the separator the formatted documents are joined with, which defaults to a blank line.
+    pub fn with_separator<S: Into<String>>(mut self, separator: S) -> Chain {
+        self.separator = separator.into();
+        self
+    }
+
+    /// Sets the number of tokens kept free for the answer when packing documents, instead of the `MaxTokens` option
+    /// of the step.
+    pub fn with_completion_tokens(mut self, completion_tokens: usize) -> Chain {
+        self.completion_tokens = Some(completion_tokens);
+        self
+    }
+
+    /// Attaches callbacks to the chain. The step is reported as `stuff`.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Chain {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Executes the chain using the provided `Executor`.
+    ///
+    /// Documents are added to the prompt in order until the next one would not leave enough room for the answer
+    /// in the context window of the step. The documents that were left out are dropped.
+    pub async fn run<E: Executor, M>(
+        &self,
+        documents: Vec<Document<M>>,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<StuffOutput<M>, StuffDocumentsChainError>
+    where
+        M: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        let output = self.try_run(documents, parameters, executor).await;
+        // Errors of the step itself have already been reported by its frame.
+        if let Err(err) = &output {
+            if !matches!(err, StuffDocumentsChainError::FormatAndExecuteError(_)) {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+            }
+        }
+        output
+    }
+
+    async fn try_run<E: Executor, M>(
+        &self,
+        mut documents: Vec<Document<M>>,
+        parameters: Parameters,
+        executor: &E,
+    ) -> Result<StuffOutput<M>, StuffDocumentsChainError>
+    where
+        M: serde::Serialize + serde::de::DeserializeOwned,
+    {
+        if documents.is_empty() {
+            return Err(StuffDocumentsChainError::InputEmpty);
+        }
+        let completion_tokens = self
+            .completion_tokens
+            .unwrap_or_else(|| assumed_output_tokens(self.step.options()));
+        let completion_tokens = i32::try_from(completion_tokens).unwrap_or(i32::MAX);
+        let mut context = String::new();
+        let mut used = 0;
+        for (index, document) in documents.iter().enumerate() {
+            let formatted = self.document_template.format(
+                &document
+                    .to_parameters()
+                    .with("index", (index + 1).to_string()),
+            )?;
+            let candidate = if used == 0 {
+                formatted
+            } else {
+                format!("{}{}{}", context, self.separator, formatted)
+            };
+            let prompt = self
+                .step
+                .format(&parameters.with("context", candidate.clone()))?;
+            if !executor
+                .tokens_used(self.step.options(), &prompt)?
+                .has_room_for(completion_tokens)
+            {
+                break;
+            }
+            context = candidate;
+            used += 1;
+        }
+        if used == 0 {
+            return Err(StuffDocumentsChainError::DocumentTooLarge);
+        }
+        documents.truncate(used);
+
+        let output = Frame::new(executor, &self.step)
+            .with_callbacks(self.callbacks.clone())
+            .with_name("stuff")
+            .format_and_execute(&parameters.with("context", context))
+            .await?;
+        Ok(StuffOutput {
+            output,
+            source_documents: documents,
+        })
+    }
+}
+
+impl StorableEntity for Chain {
+    fn get_metadata() -> Vec<(String, String)> {
+        let base = vec![(
+            "chain-type".to_string(),
+            "llm-chain::chains::stuff::Chain".to_string(),
+        )];
+        base
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::{Chain, StuffDocumentsChainError};
+    use crate::schema::{Document, EmptyMetadata};
+    use crate::testing::ScriptedExecutor;
+    use crate::{options, prompt, prompt::StringTemplate, step::Step, Parameters};
+
+    fn documents(texts: &[&str]) -> Vec<Document<EmptyMetadata>> {
+        texts
+            .iter()
+            .map(|text| Document::new(text.to_string()))
+            .collect()
+    }
+
+    fn chain() -> Chain {
+        Chain::new(Step::for_prompt_template(prompt!("docs:\n{{context}}")))
+            .with_document_template(StringTemplate::tera("{{index}}. {{page_content}}"))
+    }
+
+    #[tokio::test]
+    async fn test_packs_the_documents_that_fit() {
+        let prompts = Arc::new(Mutex::new(vec![]));
+        let recorded = prompts.clone();
+        let executor = ScriptedExecutor::new(move |prompt| {
+            recorded.lock().unwrap().push(prompt.to_string());
+            "answer".to_string()
+        })
+        .with_max_tokens(45);
+
+        let result = chain()
+            .with_completion_tokens(10)
+            .run(
+                documents(&["aaaaaaaaaa", "bbbbbbbbbb", "cccccccccc"]),
+                Parameters::new(),
+                &executor,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(
+            *prompts.lock().unwrap(),
+            vec!["docs:\n1. aaaaaaaaaa\n\n2. bbbbbbbbbb"]
+        );
+        let sources: Vec<_> = result
+            .source_documents
+            .iter()
+            .map(|document| document.page_content.as_str())
+            .collect();
+        assert_eq!(sources, vec!["aaaaaaaaaa", "bbbbbbbbbb"]);
+        let output = result.output.to_immediate().await.unwrap();
+        assert_eq!(output.primary_textual_output().unwrap(), "answer");
+    }
+
+    #[tokio::test]
+    async fn test_keeps_room_for_the_answer() {
+        let executor = ScriptedExecutor::new(|_| "answer".to_string()).with_max_tokens(40);
+        let chain = Chain::new(Step::for_prompt_and_options(
+            prompt!("docs:\n{{context}}"),
+            options!(MaxTokens: 20usize),
+        ))
+        .with_document_template(StringTemplate::tera("{{index}}. {{page_content}}"));
+
+        let result = chain
+            .run(
+                documents(&["aaaaaaaaaa", "bbbbbbbbbb"]),
+                Parameters::new(),
+                &executor,
+            )
+            .await
+            .unwrap();
+
+        // The second document would fit, but leave only 6 of the 20 tokens the answer may take.
+        assert_eq!(result.source_documents.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_fails_if_the_first_document_does_not_fit() {
+        let executor = ScriptedExecutor::new(|_| "answer".to_string()).with_max_tokens(10);
+        let result = chain()
+            .run(documents(&["aaaaaaaaaa"]), Parameters::new(), &executor)
+            .await;
+        assert!(matches!(
+            result,
+            Err(StuffDocumentsChainError::DocumentTooLarge)
+        ));
+        assert_eq!(executor.calls(), 0);
+    }
+}
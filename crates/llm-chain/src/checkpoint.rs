@@ -0,0 +1,281 @@
+//! Checkpoints let long chain runs pick up where they left off.
+//!
+//! When a chain is given a `Checkpointer`, the output of every step it runs is saved to a `CheckpointStore`,
+//! keyed by the id of the run and a hash of the step and its formatted prompt. Running the chain again with the
+//! same run id reuses the saved outputs instead of executing the steps again, so a map-reduce job that died
+//! halfway through only pays for the chunks it hadn't finished.
+//!
+//! Checkpoints are stored as `serialization::Envelope`s, with the output as data and the run id, step and input
+//! hash as metadata, so the files written by `FileCheckpointStore` can be inspected by hand. The output is kept as
+//! the model returned it, text or chat messages, so a restored output has the same shape as a fresh one.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let store = Arc::new(FileCheckpointStore::new("checkpoints"));
+//! let chain = map_reduce::Chain::new(map, reduce).with_checkpoints(Checkpointer::new(store, "nightly-report"));
+//! // If this fails halfway through, running it again only executes the remaining steps.
+//! let output = chain.run(documents, parameters!(), &executor).await?;
+//! ```
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+use crate::options::Options;
+use crate::prompt::{Data, Prompt};
+use crate::serialization::{Envelope, EnvelopeError};
+
+/// The `CheckpointError` enum represents errors that can occur when reading or writing checkpoints.
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("EnvelopeError: {0}")]
+    Envelope(#[from] EnvelopeError),
+    #[error("Invalid checkpoint JSON: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("Checkpoint store error: {0}")]
+    Store(String),
+}
+
+impl From<std::io::Error> for CheckpointError {
+    fn from(err: std::io::Error) -> Self {
+        CheckpointError::Envelope(EnvelopeError::IOError(err))
+    }
+}
+
+/// A saved step output, with the run id, the step and the input hash as metadata.
+pub type Checkpoint = Envelope<Data<String>>;
+
+/// A place to keep checkpoints, grouped by run id.
+#[async_trait]
+pub trait CheckpointStore: Send + Sync {
+    /// Returns the checkpoint saved under `key` for the run, if there is one.
+    async fn load(&self, run_id: &str, key: &str) -> Result<Option<Checkpoint>, CheckpointError>;
+
+    /// Saves a checkpoint under `key` for the run, replacing any previous one.
+    async fn save(
+        &self,
+        run_id: &str,
+        key: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), CheckpointError>;
+}
+
+/// A `CheckpointStore` keeping checkpoints in memory, which is mostly useful for tests.
+#[derive(Default)]
+pub struct InMemoryCheckpointStore {
+    checkpoints: Mutex<HashMap<(String, String), Checkpoint>>,
+}
+
+impl InMemoryCheckpointStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of checkpoints in the store.
+    pub fn len(&self) -> usize {
+        self.checkpoints.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for InMemoryCheckpointStore {
+    async fn load(&self, run_id: &str, key: &str) -> Result<Option<Checkpoint>, CheckpointError> {
+        let checkpoints = self.checkpoints.lock().unwrap();
+        Ok(checkpoints
+            .get(&(run_id.to_string(), key.to_string()))
+            .cloned())
+    }
+
+    async fn save(
+        &self,
+        run_id: &str,
+        key: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), CheckpointError> {
+        let mut checkpoints = self.checkpoints.lock().unwrap();
+        checkpoints.insert((run_id.to_string(), key.to_string()), checkpoint.clone());
+        Ok(())
+    }
+}
+
+/// A `CheckpointStore` writing every checkpoint to its own JSON file, at `<directory>/<run id>/<key>.json`.
+pub struct FileCheckpointStore {
+    directory: PathBuf,
+}
+
+impl FileCheckpointStore {
+    /// Creates a store writing to the given directory, which is created when the first checkpoint is saved.
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        FileCheckpointStore {
+            directory: directory.into(),
+        }
+    }
+
+    fn path(&self, run_id: &str, key: &str) -> Result<PathBuf, CheckpointError> {
+        if run_id.is_empty() || run_id.contains(['/', '\\']) || run_id.starts_with('.') {
+            return Err(CheckpointError::Store(format!(
+                "`{}` can't be used as a run id",
+                run_id
+            )));
+        }
+        Ok(self.directory.join(run_id).join(format!("{}.json", key)))
+    }
+}
+
+#[async_trait]
+impl CheckpointStore for FileCheckpointStore {
+    async fn load(&self, run_id: &str, key: &str) -> Result<Option<Checkpoint>, CheckpointError> {
+        match tokio::fs::read(self.path(run_id, key)?).await {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn save(
+        &self,
+        run_id: &str,
+        key: &str,
+        checkpoint: &Checkpoint,
+    ) -> Result<(), CheckpointError> {
+        let path = self.path(run_id, key)?;
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write to a temporary file first, so a run dying mid-write doesn't leave a truncated checkpoint behind.
+        let temporary = path.with_extension("json.tmp");
+        tokio::fs::write(&temporary, serde_json::to_vec_pretty(checkpoint)?).await?;
+        tokio::fs::rename(&temporary, &path).await?;
+        Ok(())
+    }
+}
+
+/// Saves and restores the step outputs of one run of a chain.
+#[derive(Clone)]
+pub struct Checkpointer {
+    store: Arc<dyn CheckpointStore>,
+    run_id: String,
+}
+
+impl std::fmt::Debug for Checkpointer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Checkpointer")
+            .field("run_id", &self.run_id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Checkpointer {
+    /// Creates a checkpointer for the run with the given id. Runs sharing an id share their checkpoints.
+    pub fn new<S: Into<String>>(store: Arc<dyn CheckpointStore>, run_id: S) -> Self {
+        Checkpointer {
+            store,
+            run_id: run_id.into(),
+        }
+    }
+
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+
+    /// Returns the key of the checkpoint of a step, made of the name of the step and a hash of its options and
+    /// formatted prompt.
+    pub(crate) fn key(
+        &self,
+        step: &str,
+        options: &Options,
+        prompt: &Prompt,
+    ) -> Result<String, CheckpointError> {
+        let input = serde_json::to_vec(&(options, prompt))?;
+        let name: String = step
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        Ok(format!("{}-{:016x}", name, fnv1a(&input)))
+    }
+
+    /// Returns the saved output of the step with the given key, if any.
+    pub(crate) async fn load(&self, key: &str) -> Result<Option<Data<String>>, CheckpointError> {
+        Ok(self
+            .store
+            .load(&self.run_id, key)
+            .await?
+            .map(|checkpoint| checkpoint.data))
+    }
+
+    /// Saves the output of the step with the given key.
+    pub(crate) async fn save(
+        &self,
+        key: &str,
+        step: &str,
+        output: Data<String>,
+    ) -> Result<(), CheckpointError> {
+        let mut checkpoint = Envelope::new(output);
+        checkpoint
+            .metadata
+            .insert("run-id".to_string(), self.run_id.clone());
+        checkpoint
+            .metadata
+            .insert("step".to_string(), step.to_string());
+        checkpoint
+            .metadata
+            .insert("input-hash".to_string(), key.to_string());
+        self.store.save(&self.run_id, key, &checkpoint).await
+    }
+}
+
+/// The 64-bit FNV-1a hash, which unlike the hashers of the standard library is stable across Rust versions.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{Checkpointer, FileCheckpointStore, InMemoryCheckpointStore};
+    use crate::options::Options;
+    use crate::prompt::{ChatMessageCollection, Data};
+
+    #[test]
+    fn test_keys_depend_on_the_input() {
+        let checkpointer = Checkpointer::new(Arc::new(InMemoryCheckpointStore::new()), "run");
+        let options = Options::empty().clone();
+        let key = |text: &str| {
+            checkpointer
+                .key("map 1", &options, &Data::Text(text.to_string()))
+                .unwrap()
+        };
+        assert_eq!(key("a"), key("a"));
+        assert_ne!(key("a"), key("b"));
+        assert!(key("a").starts_with("map_1-"));
+    }
+
+    #[tokio::test]
+    async fn test_file_store_roundtrips_checkpoints() {
+        let directory = std::env::temp_dir().join(format!("checkpoints-{}", uuid::Uuid::new_v4()));
+        let checkpointer = Checkpointer::new(Arc::new(FileCheckpointStore::new(&directory)), "run");
+        assert!(checkpointer.load("map-1").await.unwrap().is_none());
+        let output =
+            Data::Chat(ChatMessageCollection::new().with_assistant("A summary".to_string()));
+        checkpointer.save("map-1", "map", output).await.unwrap();
+        let Some(Data::Chat(messages)) = checkpointer.load("map-1").await.unwrap() else {
+            panic!("the checkpoint should be restored as chat messages");
+        };
+        assert_eq!(messages.extract_last_body().unwrap(), "A summary");
+
+        let written = std::fs::read_to_string(directory.join("run").join("map-1.json")).unwrap();
+        assert!(written.contains("\"run-id\": \"run\""));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
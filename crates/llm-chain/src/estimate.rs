@@ -0,0 +1,169 @@
+//! Estimates of what running a chain would take, made without calling the model.
+//!
+//! Chains supporting it have a `dry_run` method formatting every prompt the run would send and counting its
+//! tokens with the executor, so the size of a big job is known before paying for it. The result is an `Estimate`
+//! with the number of calls, the prompt tokens and the maximum completion tokens of every step, and the calls
+//! whose prompt wouldn't fit in the context window of the model.
+//!
+//! The outputs of the model aren't known in a dry run, so prompts containing them are estimated: an output is
+//! assumed to be as long as the `MaxTokens` option of its step, or `ASSUMED_OUTPUT_TOKENS` for steps without it.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let estimate = chain.dry_run(&documents, parameters!(), &executor)?;
+//! println!("{} calls, {} prompt tokens", estimate.calls(), estimate.prompt_tokens());
+//! for step in estimate.overflowing_steps() {
+//!     println!("{} overflows the context window {} times", step.name, step.overflowing_calls);
+//! }
+//! ```
+
+use crate::options::{Opt, OptDiscriminants, Options};
+use crate::tokens::{PromptTokensError, Tokenizer};
+use crate::traits::Executor;
+
+/// The length, in tokens, assumed for the outputs of steps without a `MaxTokens` option.
+pub const ASSUMED_OUTPUT_TOKENS: usize = 256;
+
+/// The estimated calls of one step of a chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StepEstimate {
+    /// The name the step is reported under, like `map` or `reduce 2`.
+    pub name: String,
+    /// The number of times the step would be executed.
+    pub calls: usize,
+    /// The prompt tokens of all calls together.
+    pub prompt_tokens: usize,
+    /// The prompt tokens of the largest call.
+    pub largest_prompt_tokens: usize,
+    /// The most completion tokens all calls together could generate: the `MaxTokens` option of the step, or
+    /// otherwise whatever is left of the context window, for every call.
+    pub max_completion_tokens: usize,
+    /// The context window of the model, as returned by `Executor::max_tokens_allowed`.
+    pub max_tokens_allowed: usize,
+    /// The number of calls whose prompt, plus the `MaxTokens` option if set, doesn't fit in the context window.
+    pub overflowing_calls: usize,
+}
+
+impl StepEstimate {
+    pub(crate) fn new(name: impl Into<String>, max_tokens_allowed: i32) -> Self {
+        StepEstimate {
+            name: name.into(),
+            calls: 0,
+            prompt_tokens: 0,
+            largest_prompt_tokens: 0,
+            max_completion_tokens: 0,
+            max_tokens_allowed: max_tokens_allowed.max(0) as usize,
+            overflowing_calls: 0,
+        }
+    }
+
+    /// Records a call with a prompt of the given number of tokens.
+    pub(crate) fn add_call(&mut self, options: &Options, prompt_tokens: i32) {
+        let prompt_tokens = prompt_tokens.max(0) as usize;
+        let remaining = self.max_tokens_allowed.saturating_sub(prompt_tokens);
+        let completion_tokens = max_output_tokens(options);
+        self.calls += 1;
+        self.prompt_tokens += prompt_tokens;
+        self.largest_prompt_tokens = self.largest_prompt_tokens.max(prompt_tokens);
+        self.max_completion_tokens += completion_tokens.map_or(remaining, |max| max.min(remaining));
+        if prompt_tokens + completion_tokens.unwrap_or(0) > self.max_tokens_allowed {
+            self.overflowing_calls += 1;
+        }
+    }
+
+    /// Returns whether every call of the step fits in the context window.
+    pub fn fits(&self) -> bool {
+        self.overflowing_calls == 0
+    }
+}
+
+/// The estimated cost of a chain run.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Estimate {
+    /// The steps of the run, in the order they would be executed.
+    pub steps: Vec<StepEstimate>,
+}
+
+impl Estimate {
+    /// The number of model calls of the run.
+    pub fn calls(&self) -> usize {
+        self.steps.iter().map(|step| step.calls).sum()
+    }
+
+    /// The prompt tokens of the run.
+    pub fn prompt_tokens(&self) -> usize {
+        self.steps.iter().map(|step| step.prompt_tokens).sum()
+    }
+
+    /// The most completion tokens the run could generate.
+    pub fn max_completion_tokens(&self) -> usize {
+        self.steps
+            .iter()
+            .map(|step| step.max_completion_tokens)
+            .sum()
+    }
+
+    /// The steps with calls that don't fit in the context window.
+    pub fn overflowing_steps(&self) -> impl Iterator<Item = &StepEstimate> {
+        self.steps.iter().filter(|step| !step.fits())
+    }
+
+    /// Returns whether every call of the run fits in the context window.
+    pub fn fits(&self) -> bool {
+        self.overflowing_steps().next().is_none()
+    }
+}
+
+/// Returns the `MaxTokens` option of a step, if it's set.
+fn max_output_tokens(options: &Options) -> Option<usize> {
+    match options.get(OptDiscriminants::MaxTokens) {
+        Some(Opt::MaxTokens(max_tokens)) => Some(*max_tokens),
+        _ => None,
+    }
+}
+
+/// The length, in tokens, assumed for the outputs of a step in a dry run.
+pub(crate) fn assumed_output_tokens(options: &Options) -> usize {
+    max_output_tokens(options).unwrap_or(ASSUMED_OUTPUT_TOKENS)
+}
+
+/// Returns a text standing in for the unknown output of a step, as long as the assumed output.
+pub(crate) fn placeholder_output<E: Executor>(
+    executor: &E,
+    options: &Options,
+) -> Result<String, PromptTokensError> {
+    let tokenizer = executor.get_tokenizer(options)?;
+    let word = tokenizer.tokenize_str(" output")?;
+    let tokens = assumed_output_tokens(options) / word.len().max(1);
+    Ok(" output".repeat(tokens))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Estimate, StepEstimate};
+    use crate::options;
+
+    #[test]
+    fn test_sums_calls_and_flags_overflows() {
+        let options = options!(MaxTokens: 100usize);
+        let mut map = StepEstimate::new("map", 1000);
+        map.add_call(&options, 800);
+        map.add_call(&options, 950);
+        let mut reduce = StepEstimate::new("reduce 1", 1000);
+        reduce.add_call(crate::options::Options::empty(), 300);
+        let estimate = Estimate {
+            steps: vec![map, reduce],
+        };
+
+        assert_eq!(estimate.calls(), 3);
+        assert_eq!(estimate.prompt_tokens(), 2050);
+        // 100 for the first call, the 50 tokens left for the second, and all 700 left for the reduce step.
+        assert_eq!(estimate.max_completion_tokens(), 850);
+        let overflowing: Vec<_> = estimate
+            .overflowing_steps()
+            .map(|step| step.name.as_str())
+            .collect();
+        assert_eq!(overflowing, vec!["map"]);
+    }
+}
@@ -7,6 +7,7 @@
 //! The `Frame` struct is generic over the `Step` and `Executor` types, ensuring that it can work with any
 //! combination of types that implement the required traits.
 
+use crate::callbacks::{Callbacks, Event};
 use crate::output::Output;
 use crate::step::Step;
 use crate::traits;
@@ -23,6 +24,8 @@ where
 {
     executor: &'l E,
     step: &'l Step,
+    callbacks: Callbacks,
+    name: String,
 }
 
 impl<'l, E> Frame<'l, E>
@@ -34,7 +37,24 @@ where
     /// The `new` function takes two references to an `Executor` and a `Step`, respectively, and returns
     /// a new `Frame` instance.
     pub fn new(executor: &'l E, step: &'l Step) -> Self {
-        Self { executor, step }
+        Self {
+            executor,
+            step,
+            callbacks: Callbacks::default(),
+            name: "step".to_string(),
+        }
+    }
+
+    /// Attaches callbacks to the frame, which are notified as the step is formatted and executed.
+    pub fn with_callbacks(mut self, callbacks: Callbacks) -> Self {
+        self.callbacks = callbacks;
+        self
+    }
+
+    /// Sets the name the step is reported under in callback events, defaults to `step`.
+    pub fn with_name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
     }
 
     /// Formats the step with the provided parameters and executes it using the associated executor.
@@ -44,8 +64,86 @@ where
     pub async fn format_and_execute(
         &self,
         parameters: &Parameters,
+    ) -> Result<Output, FormatAndExecuteError> {
+        self.callbacks.emit(Event::StepStarted {
+            name: self.name.clone(),
+        });
+        let output = self.try_format_and_execute(parameters);
+        #[cfg(feature = "tracing")]
+        let output = crate::telemetry::in_span(crate::telemetry::frame_span(&self.name), output);
+        match output.await {
+            Ok(output) => Ok(self.callbacks.observe_output(output, Some(&self.name))),
+            Err(err) => {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+                Err(err)
+            }
+        }
+    }
+
+    /// Formats the step with the provided parameters and asks the executor for `n` answers to it, see
+    /// `Executor::execute_n`.
+    ///
+    /// The answers are reported to the callbacks as `<name> 1`, `<name> 2` and so on.
+    pub async fn format_and_execute_n(
+        &self,
+        parameters: &Parameters,
+        n: usize,
+    ) -> Result<Vec<Output>, FormatAndExecuteError>
+    where
+        E: Sync,
+    {
+        self.callbacks.emit(Event::StepStarted {
+            name: self.name.clone(),
+        });
+        let outputs = self.try_format_and_execute_n(parameters, n);
+        #[cfg(feature = "tracing")]
+        let outputs = crate::telemetry::in_span(crate::telemetry::frame_span(&self.name), outputs);
+        match outputs.await {
+            Ok(outputs) => Ok(outputs
+                .into_iter()
+                .enumerate()
+                .map(|(index, output)| {
+                    let name = format!("{} {}", self.name, index + 1);
+                    self.callbacks.observe_output(output, Some(&name))
+                })
+                .collect()),
+            Err(err) => {
+                self.callbacks.emit(Event::Error {
+                    message: err.to_string(),
+                });
+                Err(err)
+            }
+        }
+    }
+
+    async fn try_format_and_execute_n(
+        &self,
+        parameters: &Parameters,
+        n: usize,
+    ) -> Result<Vec<Output>, FormatAndExecuteError>
+    where
+        E: Sync,
+    {
+        let prompt = self.step.format(parameters)?;
+        self.callbacks.emit(Event::PromptFormatted {
+            prompt: prompt.clone(),
+        });
+        Ok(self
+            .executor
+            .execute_n(self.step.options(), &prompt, n)
+            .await?)
+    }
+
+    async fn try_format_and_execute(
+        &self,
+        parameters: &Parameters,
     ) -> Result<Output, FormatAndExecuteError> {
         let prompt = self.step.format(parameters)?;
+        self.callbacks.emit(Event::PromptFormatted {
+            prompt: prompt.clone(),
+        });
         Ok(self.executor.execute(self.step.options(), &prompt).await?)
     }
 }
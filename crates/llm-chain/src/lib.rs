@@ -23,10 +23,14 @@
 
 // Core components
 pub mod agents;
+pub mod callbacks;
 pub mod chains;
+pub mod checkpoint;
 pub mod document_stores;
+pub mod estimate;
 pub mod executor;
 pub mod frame;
+pub mod memory;
 pub mod options;
 pub mod output;
 pub mod parameters;
@@ -34,13 +38,19 @@ pub mod parsing;
 pub mod prompt;
 pub mod schema;
 pub mod serialization;
+pub mod session;
 pub mod step;
+pub mod structured;
 pub mod tokens;
 pub mod tools;
 pub mod traits;
 
 // Utilities and tools
 pub mod summarization;
+#[cfg(feature = "tracing")]
+pub mod telemetry;
+#[cfg(test)]
+mod testing;
 
 // Re-exports for convenient usage
 pub use parameters::Parameters;
@@ -0,0 +1,591 @@
+//! Memories decide which part of a conversation is sent to the model with every new message.
+//!
+//! A conversation keeps its whole history, but models have a limited context window and long histories are slow
+//! and expensive. A `ConversationMemory` chooses the messages sent along with every new prompt, and is selected
+//! when building a `conversation::Chain` with `with_memory`:
+//!
+//! - `BufferWindowMemory` keeps the last few messages.
+//! - `TokenWindowMemory` keeps as many of the newest messages as fit in the context window. This is the default.
+//! - `SummaryMemory` replaces the history with a running summary of it, written by the model.
+//! - `SummaryBufferMemory` keeps the newest messages up to a number of tokens, and a summary of the older ones.
+//! - `VectorStoreMemory` keeps recent messages like any of the above, and adds the earlier exchanges most relevant
+//!   to the new prompt, which it finds in a vector store.
+//!
+//! All of them pin the system prompt, the system messages the conversation starts with, which is always sent.
+//!
+//! The summary memories keep a summary that can't be recomputed from the history without calling the model.
+//! `ConversationMemory::state` returns it so it can be stored along with the conversation, as sessions do.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let mut chain = Chain::new(prompt!(system: "You are a helpful travel agent."))?
+//!     .with_memory(SummaryBufferMemory::new(1000));
+//! let output = chain.send_message(step, &parameters, &executor).await?;
+//! ```
+
+mod vector_store;
+
+pub use vector_store::VectorStoreMemory;
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::prompt::{ChatMessage, ChatMessageCollection, ChatRole, Prompt};
+use crate::tokens::{Tokenizer, TokenizerError};
+use crate::{prompt, step::Step, Parameters};
+
+const SUMMARY_PROMPT: &str = "Progressively summarize the lines of conversation provided, adding onto the previous summary and returning a new summary.
+
+Current summary:
+{{summary}}
+
+New lines of conversation:
+{{new_lines}}
+
+New summary:";
+
+/// The `MemoryError` enum represents errors that can occur when a memory prepares the context of a conversation.
+#[derive(Error, Debug)]
+pub enum MemoryError {
+    #[error("TokenizerError: {0}")]
+    Tokenizer(#[from] TokenizerError),
+    #[error("StringTemplateError: {0}")]
+    StringTemplate(#[from] crate::prompt::StringTemplateError),
+    #[error("Memory store error: {0}")]
+    Store(String),
+    #[error("Invalid memory state: {0}")]
+    State(#[from] serde_json::Error),
+}
+
+/// Chooses the messages of a conversation sent to the model with the next prompt.
+///
+/// Before every prompt the conversation calls `prepare`, then, for memories keeping a summary, asks for it to be
+/// updated with `summary_request`, executes the returned step, and hands the new summary back with
+/// `update_summary`. It then asks for the context, and once the model answered, hands the exchange to
+/// `save_exchange`.
+#[async_trait]
+pub trait ConversationMemory: Send + Sync {
+    /// Called with the next prompt before anything else, so memories can fetch what they need for it.
+    async fn prepare(
+        &mut self,
+        _history: &ChatMessageCollection<String>,
+        _prompt: &Prompt,
+    ) -> Result<(), MemoryError> {
+        Ok(())
+    }
+
+    /// Called with every exchange once the model answered it, after it was added to the history.
+    async fn save_exchange(
+        &mut self,
+        _input: &ChatMessageCollection<String>,
+        _output: &ChatMessageCollection<String>,
+    ) -> Result<(), MemoryError> {
+        Ok(())
+    }
+
+    /// Returns the messages to send before the next prompt, given the whole history of the conversation and the
+    /// number of tokens left in the context window after the prompt.
+    fn context(
+        &self,
+        history: &ChatMessageCollection<String>,
+        tokenizer: &dyn Tokenizer,
+        max_tokens: usize,
+    ) -> Result<ChatMessageCollection<String>, TokenizerError>;
+
+    /// Returns the step to execute to bring the summary of the memory up to date, if it has one and it's behind.
+    fn summary_request(
+        &self,
+        _history: &ChatMessageCollection<String>,
+        _tokenizer: &dyn Tokenizer,
+    ) -> Result<Option<SummaryRequest>, TokenizerError> {
+        Ok(None)
+    }
+
+    /// Replaces the summary with the output of the step returned by `summary_request`.
+    fn update_summary(&mut self, _summary: String, _summarized: usize) {}
+
+    /// Called when messages were removed from the end of the history, by editing, truncating or regenerating the
+    /// conversation, with what is left of it. Memories keeping a summary drop it if it covers removed messages.
+    fn history_truncated(&mut self, _history: &ChatMessageCollection<String>) {}
+
+    /// Returns the state of the memory that can't be recomputed from the history, like a summary, so it can be
+    /// stored along with the conversation. Memories without such state return `None`.
+    fn state(&self) -> Option<serde_json::Value> {
+        None
+    }
+
+    /// Restores the state returned by `state`, for a conversation restored from storage.
+    fn restore_state(&mut self, _state: serde_json::Value) -> Result<(), MemoryError> {
+        Ok(())
+    }
+}
+
+/// A step asked for by a memory to update its summary.
+#[derive(Debug, Clone)]
+pub struct SummaryRequest {
+    pub step: Step,
+    pub parameters: Parameters,
+    /// The number of messages after the system prompt covered by the summary once updated, to be passed back to
+    /// `update_summary`.
+    pub summarized: usize,
+}
+
+/// Splits a history into its system prompt, the system messages it starts with, and the other messages.
+pub(crate) fn split_system_prompt(
+    history: &ChatMessageCollection<String>,
+) -> (Vec<ChatMessage<String>>, Vec<ChatMessage<String>>) {
+    let messages: Vec<_> = history.iter().cloned().collect();
+    let pinned = messages
+        .iter()
+        .take_while(|message| message.role() == &ChatRole::System)
+        .count();
+    let (system, rest) = messages.split_at(pinned);
+    (system.to_vec(), rest.to_vec())
+}
+
+pub(crate) fn count_tokens(
+    tokenizer: &dyn Tokenizer,
+    messages: &[ChatMessage<String>],
+) -> Result<usize, TokenizerError> {
+    messages.iter().try_fold(0, |total, message| {
+        Ok(total + tokenizer.tokenize_str(message.body())?.len())
+    })
+}
+
+/// Returns the index of the oldest message such that it and every newer one fit in `max_tokens`.
+pub(crate) fn newest_fitting(
+    tokenizer: &dyn Tokenizer,
+    messages: &[ChatMessage<String>],
+    max_tokens: usize,
+) -> Result<usize, TokenizerError> {
+    let mut total = 0;
+    for (index, message) in messages.iter().enumerate().rev() {
+        total += tokenizer.tokenize_str(message.body())?.len();
+        if total > max_tokens {
+            return Ok(index + 1);
+        }
+    }
+    Ok(0)
+}
+
+/// Formats a chat history as a transcript, one message per line, like `User: hello`. System messages are left out.
+pub fn format_chat_history(history: &ChatMessageCollection<String>) -> String {
+    history
+        .iter()
+        .filter(|message| message.role() != &ChatRole::System)
+        .map(|message| format!("{}: {}", message.role(), message.body()))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn collect(messages: Vec<ChatMessage<String>>) -> ChatMessageCollection<String> {
+    ChatMessageCollection::for_vector(messages)
+}
+
+/// A memory keeping the system prompt and the last `k` messages.
+#[derive(Debug, Clone)]
+pub struct BufferWindowMemory {
+    k: usize,
+}
+
+impl BufferWindowMemory {
+    pub fn new(k: usize) -> Self {
+        BufferWindowMemory { k }
+    }
+}
+
+#[async_trait]
+impl ConversationMemory for BufferWindowMemory {
+    fn context(
+        &self,
+        history: &ChatMessageCollection<String>,
+        _tokenizer: &dyn Tokenizer,
+        _max_tokens: usize,
+    ) -> Result<ChatMessageCollection<String>, TokenizerError> {
+        let (mut context, rest) = split_system_prompt(history);
+        context.extend(rest.into_iter().rev().take(self.k).rev());
+        Ok(collect(context))
+    }
+}
+
+/// A memory keeping the system prompt and as many of the newest messages as fit in the context window, dropping
+/// the oldest messages first.
+#[derive(Debug, Clone, Default)]
+pub struct TokenWindowMemory {
+    max_tokens: Option<usize>,
+}
+
+impl TokenWindowMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the history sent to the given number of tokens, even if the context window has more room.
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+}
+
+#[async_trait]
+impl ConversationMemory for TokenWindowMemory {
+    fn context(
+        &self,
+        history: &ChatMessageCollection<String>,
+        tokenizer: &dyn Tokenizer,
+        max_tokens: usize,
+    ) -> Result<ChatMessageCollection<String>, TokenizerError> {
+        let (mut context, rest) = split_system_prompt(history);
+        let max_tokens = self
+            .max_tokens
+            .map_or(max_tokens, |limit| limit.min(max_tokens))
+            .saturating_sub(count_tokens(tokenizer, &context)?);
+        let start = newest_fitting(tokenizer, &rest, max_tokens)?;
+        context.extend_from_slice(&rest[start..]);
+        Ok(collect(context))
+    }
+}
+
+/// The stored state of the memories keeping a summary.
+#[derive(Serialize, Deserialize)]
+struct SummaryState {
+    summary: String,
+    summarized: usize,
+}
+
+/// The state shared by the memories keeping a summary.
+#[derive(Debug, Clone)]
+struct Summary {
+    step: Step,
+    text: String,
+    /// The number of messages after the system prompt the summary covers.
+    summarized: usize,
+}
+
+impl Summary {
+    fn new() -> Self {
+        Summary {
+            step: Step::for_prompt_template(prompt!(SUMMARY_PROMPT)),
+            text: String::new(),
+            summarized: 0,
+        }
+    }
+
+    /// Returns the number of messages covered by the summary, which is none if the history was cut short since.
+    fn summarized(&self, rest: &[ChatMessage<String>]) -> usize {
+        if self.summarized > rest.len() {
+            0
+        } else {
+            self.summarized
+        }
+    }
+
+    /// Returns the request bringing the summary up to the message at `until`.
+    fn request(&self, rest: &[ChatMessage<String>], until: usize) -> Option<SummaryRequest> {
+        let from = self.summarized(rest);
+        if until <= from {
+            return None;
+        }
+        let summary = if from == 0 { "" } else { self.text.as_str() };
+        let new_lines = format_chat_history(&collect(rest[from..until].to_vec()));
+        Some(SummaryRequest {
+            step: self.step.clone(),
+            parameters: Parameters::new()
+                .with("summary", summary)
+                .with("new_lines", new_lines),
+            summarized: until,
+        })
+    }
+
+    fn update(&mut self, summary: String, summarized: usize) {
+        self.text = summary.trim().to_string();
+        self.summarized = summarized;
+    }
+
+    /// Drops the summary if it covers messages that are no longer in the history.
+    fn truncated(&mut self, history: &ChatMessageCollection<String>) {
+        let (_, rest) = split_system_prompt(history);
+        if self.summarized > rest.len() {
+            self.update(String::new(), 0);
+        }
+    }
+
+    fn state(&self) -> Option<serde_json::Value> {
+        let state = SummaryState {
+            summary: self.text.clone(),
+            summarized: self.summarized,
+        };
+        serde_json::to_value(state).ok()
+    }
+
+    fn restore_state(&mut self, state: serde_json::Value) -> Result<(), MemoryError> {
+        let state: SummaryState = serde_json::from_value(state)?;
+        self.update(state.summary, state.summarized);
+        Ok(())
+    }
+
+    /// Returns the system prompt, followed by the summary as a system message if there is one.
+    fn context(
+        &self,
+        system: Vec<ChatMessage<String>>,
+        rest: &[ChatMessage<String>],
+    ) -> Vec<ChatMessage<String>> {
+        let mut context = system;
+        if self.summarized(rest) > 0 && !self.text.is_empty() {
+            context.push(ChatMessage::system(format!(
+                "Summary of the conversation so far:\n{}",
+                self.text
+            )));
+        }
+        context
+    }
+}
+
+/// A memory replacing the history with a running summary, updated by the model before every prompt.
+///
+/// The summary step is formatted with the current summary as `summary` and the messages it doesn't cover yet as
+/// `new_lines`.
+#[derive(Debug, Clone)]
+pub struct SummaryMemory {
+    summary: Summary,
+}
+
+impl Default for SummaryMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SummaryMemory {
+    pub fn new() -> Self {
+        SummaryMemory {
+            summary: Summary::new(),
+        }
+    }
+
+    /// Replaces the step writing the summary.
+    pub fn with_summary_step(mut self, step: Step) -> Self {
+        self.summary.step = step;
+        self
+    }
+
+    /// The summary of the conversation so far.
+    pub fn summary(&self) -> &str {
+        &self.summary.text
+    }
+}
+
+#[async_trait]
+impl ConversationMemory for SummaryMemory {
+    fn context(
+        &self,
+        history: &ChatMessageCollection<String>,
+        _tokenizer: &dyn Tokenizer,
+        _max_tokens: usize,
+    ) -> Result<ChatMessageCollection<String>, TokenizerError> {
+        let (system, rest) = split_system_prompt(history);
+        let mut context = self.summary.context(system, &rest);
+        // Messages added since the summary was last updated are still sent as they are.
+        context.extend_from_slice(&rest[self.summary.summarized(&rest)..]);
+        Ok(collect(context))
+    }
+
+    fn summary_request(
+        &self,
+        history: &ChatMessageCollection<String>,
+        _tokenizer: &dyn Tokenizer,
+    ) -> Result<Option<SummaryRequest>, TokenizerError> {
+        let (_, rest) = split_system_prompt(history);
+        Ok(self.summary.request(&rest, rest.len()))
+    }
+
+    fn update_summary(&mut self, summary: String, summarized: usize) {
+        self.summary.update(summary, summarized);
+    }
+
+    fn history_truncated(&mut self, history: &ChatMessageCollection<String>) {
+        self.summary.truncated(history);
+    }
+
+    fn state(&self) -> Option<serde_json::Value> {
+        self.summary.state()
+    }
+
+    fn restore_state(&mut self, state: serde_json::Value) -> Result<(), MemoryError> {
+        self.summary.restore_state(state)
+    }
+}
+
+/// A memory keeping the newest messages up to a number of tokens, and a running summary of the older ones.
+///
+/// The summary is written like the one of `SummaryMemory`, and only updated once messages fall out of the buffer.
+#[derive(Debug, Clone)]
+pub struct SummaryBufferMemory {
+    summary: Summary,
+    max_buffer_tokens: usize,
+}
+
+impl SummaryBufferMemory {
+    /// Creates a memory keeping up to `max_buffer_tokens` tokens of the newest messages.
+    pub fn new(max_buffer_tokens: usize) -> Self {
+        SummaryBufferMemory {
+            summary: Summary::new(),
+            max_buffer_tokens,
+        }
+    }
+
+    /// Replaces the step writing the summary.
+    pub fn with_summary_step(mut self, step: Step) -> Self {
+        self.summary.step = step;
+        self
+    }
+
+    /// The summary of the messages that fell out of the buffer.
+    pub fn summary(&self) -> &str {
+        &self.summary.text
+    }
+}
+
+#[async_trait]
+impl ConversationMemory for SummaryBufferMemory {
+    fn context(
+        &self,
+        history: &ChatMessageCollection<String>,
+        tokenizer: &dyn Tokenizer,
+        max_tokens: usize,
+    ) -> Result<ChatMessageCollection<String>, TokenizerError> {
+        let (system, rest) = split_system_prompt(history);
+        let mut context = self.summary.context(system, &rest);
+        let unsummarized = &rest[self.summary.summarized(&rest)..];
+        // Should the summary lag behind, the buffer still has to fit in the context window.
+        let max_tokens = max_tokens.saturating_sub(count_tokens(tokenizer, &context)?);
+        let start = newest_fitting(tokenizer, unsummarized, max_tokens)?;
+        context.extend_from_slice(&unsummarized[start..]);
+        Ok(collect(context))
+    }
+
+    fn summary_request(
+        &self,
+        history: &ChatMessageCollection<String>,
+        tokenizer: &dyn Tokenizer,
+    ) -> Result<Option<SummaryRequest>, TokenizerError> {
+        let (_, rest) = split_system_prompt(history);
+        let buffer_start = newest_fitting(tokenizer, &rest, self.max_buffer_tokens)?;
+        Ok(self.summary.request(&rest, buffer_start))
+    }
+
+    fn update_summary(&mut self, summary: String, summarized: usize) {
+        self.summary.update(summary, summarized);
+    }
+
+    fn history_truncated(&mut self, history: &ChatMessageCollection<String>) {
+        self.summary.truncated(history);
+    }
+
+    fn state(&self) -> Option<serde_json::Value> {
+        self.summary.state()
+    }
+
+    fn restore_state(&mut self, state: serde_json::Value) -> Result<(), MemoryError> {
+        self.summary.restore_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        format_chat_history, BufferWindowMemory, ConversationMemory, SummaryBufferMemory,
+        SummaryMemory, TokenWindowMemory,
+    };
+    use crate::prompt::{ChatMessageCollection, ChatRole};
+    use crate::testing::CharTokenizer;
+
+    fn history() -> ChatMessageCollection<String> {
+        ChatMessageCollection::new()
+            .with_system("Be brief".to_string())
+            .with_user("one".to_string())
+            .with_assistant("two".to_string())
+            .with_user("three".to_string())
+            .with_assistant("four".to_string())
+    }
+
+    #[test]
+    fn test_formats_chat_history() {
+        let history = ChatMessageCollection::new()
+            .with_system("You are a helpful assistant".to_string())
+            .with_user("Which are the largest ports in Europe?".to_string())
+            .with_assistant("Rotterdam, then Antwerp.".to_string());
+        assert_eq!(
+            format_chat_history(&history),
+            "User: Which are the largest ports in Europe?\nAssistant: Rotterdam, then Antwerp."
+        );
+    }
+
+    fn bodies(messages: &ChatMessageCollection<String>) -> Vec<&str> {
+        messages
+            .iter()
+            .map(|message| message.body().as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_buffer_window_pins_the_system_prompt() {
+        let context = BufferWindowMemory::new(2)
+            .context(&history(), &CharTokenizer, 4096)
+            .unwrap();
+        assert_eq!(bodies(&context), vec!["Be brief", "three", "four"]);
+    }
+
+    #[test]
+    fn test_token_window_drops_the_oldest_messages() {
+        // The system prompt takes 8 tokens, leaving room for "three" and "four" but not "two".
+        let context = TokenWindowMemory::new()
+            .context(&history(), &CharTokenizer, 17)
+            .unwrap();
+        assert_eq!(bodies(&context), vec!["Be brief", "three", "four"]);
+    }
+
+    #[test]
+    fn test_summary_memory_replaces_summarized_messages() {
+        let mut memory = SummaryMemory::new();
+        let request = memory
+            .summary_request(&history(), &CharTokenizer)
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.summarized, 4);
+        assert_eq!(
+            request.parameters.get("new_lines").unwrap().as_str(),
+            "User: one\nAssistant: two\nUser: three\nAssistant: four"
+        );
+
+        memory.update_summary("Counted to four".to_string(), request.summarized);
+        let history = history().with_user("five".to_string());
+        let context = memory.context(&history, &CharTokenizer, 4096).unwrap();
+        assert_eq!(
+            bodies(&context),
+            vec![
+                "Be brief",
+                "Summary of the conversation so far:\nCounted to four",
+                "five"
+            ]
+        );
+        assert_eq!(context.get_message(1).unwrap().role(), &ChatRole::System);
+    }
+
+    #[test]
+    fn test_summary_buffer_only_summarizes_what_falls_out_of_the_buffer() {
+        let memory = SummaryBufferMemory::new(9);
+        let request = memory
+            .summary_request(&history(), &CharTokenizer)
+            .unwrap()
+            .unwrap();
+        // "three" and "four" fit in the buffer, "one" and "two" are summarized.
+        assert_eq!(request.summarized, 2);
+        assert!(SummaryBufferMemory::new(100)
+            .summary_request(&history(), &CharTokenizer)
+            .unwrap()
+            .is_none());
+    }
+}
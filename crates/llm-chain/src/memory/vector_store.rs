@@ -0,0 +1,386 @@
+use std::collections::HashSet;
+use std::marker::PhantomData;
+
+use async_trait::async_trait;
+
+use super::{
+    count_tokens, split_system_prompt, ConversationMemory, MemoryError, SummaryRequest,
+    TokenWindowMemory,
+};
+use crate::prompt::{ChatMessage, ChatMessageCollection, ChatRole, Prompt, StringTemplate};
+use crate::schema::EmptyMetadata;
+use crate::tokens::{Tokenizer, TokenizerError};
+use crate::traits::{Embeddings, VectorStore};
+use crate::Parameters;
+
+/// The number of earlier exchanges retrieved by default.
+const DEFAULT_LIMIT: u32 = 4;
+
+/// A long-term memory storing every exchange of the conversation in a vector store, and adding the earlier
+/// exchanges most relevant to each new prompt to its context.
+///
+/// Recent messages are chosen by another memory, a `TokenWindowMemory` by default, and the retrieved exchanges
+/// are added after the system prompt as a system message. Any `VectorStore` can be used, and since the store
+/// outlives the conversation, an assistant can remember what a user told it weeks ago. Retrieved exchanges that
+/// are still among the recent messages are left out, so the newest exchanges aren't sent twice.
+///
+/// Exchanges are only ever added to the store. Editing or regenerating messages of a conversation leaves the
+/// exchanges saved before in the store, where they can still be retrieved, so remove them from the store if the
+/// old answers must be forgotten.
+///
+/// # Example
+///
+/// ```ignore
+/// let memory = VectorStoreMemory::new(qdrant)
+///     .with_limit(3)
+///     .with_max_tokens(500)
+///     .with_recent(BufferWindowMemory::new(6));
+/// let chain = Chain::new(prompt!(system: "You are a personal assistant."))?.with_memory(memory);
+/// ```
+pub struct VectorStoreMemory<E, V, M = EmptyMetadata>
+where
+    E: Embeddings,
+    V: VectorStore<E, M>,
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    store: V,
+    recent: Box<dyn ConversationMemory>,
+    limit: u32,
+    max_tokens: Option<usize>,
+    exchange_template: StringTemplate,
+    context_template: StringTemplate,
+    retrieved: Vec<String>,
+    _marker: PhantomData<fn() -> (E, M)>,
+}
+
+impl<E, V, M> VectorStoreMemory<E, V, M>
+where
+    E: Embeddings,
+    V: VectorStore<E, M>,
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Creates a memory storing exchanges in the given store.
+    pub fn new(store: V) -> Self {
+        VectorStoreMemory {
+            store,
+            recent: Box::new(TokenWindowMemory::new()),
+            limit: DEFAULT_LIMIT,
+            max_tokens: None,
+            exchange_template: StringTemplate::tera("User: {{input}}\nAssistant: {{output}}"),
+            context_template: StringTemplate::tera(
+                "Relevant parts of earlier conversations:\n\n{{memories}}",
+            ),
+            retrieved: vec![],
+            _marker: PhantomData,
+        }
+    }
+
+    /// Sets the memory choosing the recent messages sent along with the retrieved exchanges.
+    pub fn with_recent<R: ConversationMemory + 'static>(mut self, recent: R) -> Self {
+        self.recent = Box::new(recent);
+        self
+    }
+
+    /// Sets the number of earlier exchanges retrieved for every prompt. Defaults to 4.
+    pub fn with_limit(mut self, limit: u32) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Limits the retrieved exchanges to the given number of tokens, leaving out the least relevant ones first.
+    pub fn with_max_tokens(mut self, max_tokens: usize) -> Self {
+        self.max_tokens = Some(max_tokens);
+        self
+    }
+
+    /// Sets the template an exchange is stored as, formatted with the prompt as `input` and the answer as
+    /// `output`. Defaults to `User: {{input}}\nAssistant: {{output}}`.
+    pub fn with_exchange_template(mut self, exchange_template: StringTemplate) -> Self {
+        self.exchange_template = exchange_template;
+        self
+    }
+
+    /// Sets the template of the system message the retrieved exchanges are added as, formatted with the exchanges,
+    /// most relevant first and separated by blank lines, as `memories`.
+    pub fn with_context_template(mut self, context_template: StringTemplate) -> Self {
+        self.context_template = context_template;
+        self
+    }
+
+    /// Returns the store the exchanges are saved in.
+    pub fn store(&self) -> &V {
+        &self.store
+    }
+
+    /// Returns the message with the most relevant of the given exchanges fitting in `max_tokens`, if any do.
+    fn memories_message(
+        &self,
+        exchanges: &[&str],
+        tokenizer: &dyn Tokenizer,
+        max_tokens: usize,
+    ) -> Result<Option<ChatMessage<String>>, TokenizerError> {
+        let max_tokens = self
+            .max_tokens
+            .map_or(max_tokens, |limit| limit.min(max_tokens));
+        let mut memories = vec![];
+        let mut tokens = 0;
+        for exchange in exchanges {
+            tokens += tokenizer.tokenize_str(exchange)?.len();
+            if tokens > max_tokens {
+                break;
+            }
+            memories.push(*exchange);
+        }
+        if memories.is_empty() {
+            return Ok(None);
+        }
+        let parameters = Parameters::new().with("memories", memories.join("\n\n"));
+        // A template that can't be formatted is a programming error, so fall back to the bare exchanges.
+        let body = self
+            .context_template
+            .format(&parameters)
+            .unwrap_or_else(|_| memories.join("\n\n"));
+        Ok(Some(ChatMessage::system(body)))
+    }
+
+    /// Returns the exchanges among the messages, formatted the way they are stored.
+    ///
+    /// Every run of user messages followed by a run of assistant messages is taken as one exchange.
+    fn exchanges(&self, messages: &[ChatMessage<String>]) -> HashSet<String> {
+        let mut exchanges = HashSet::new();
+        let mut input = ChatMessageCollection::new();
+        let mut output = ChatMessageCollection::new();
+        for message in messages {
+            let is_answer = message.role() == &ChatRole::Assistant;
+            if !is_answer && !output.is_empty() {
+                exchanges.extend(self.format_exchange(&input, &output).ok());
+                input = ChatMessageCollection::new();
+                output = ChatMessageCollection::new();
+            }
+            if is_answer {
+                output.add_message(message.clone());
+            } else {
+                input.add_message(message.clone());
+            }
+        }
+        if !output.is_empty() {
+            exchanges.extend(self.format_exchange(&input, &output).ok());
+        }
+        exchanges
+    }
+
+    fn format_exchange(
+        &self,
+        input: &ChatMessageCollection<String>,
+        output: &ChatMessageCollection<String>,
+    ) -> Result<String, MemoryError> {
+        let parameters = Parameters::new()
+            .with("input", bodies(input))
+            .with("output", bodies(output));
+        Ok(self.exchange_template.format(&parameters)?)
+    }
+}
+
+/// Joins the bodies of the messages, one per line.
+fn bodies(messages: &ChatMessageCollection<String>) -> String {
+    messages
+        .iter()
+        .map(|message| message.body().as_str())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[async_trait]
+impl<E, V, M> ConversationMemory for VectorStoreMemory<E, V, M>
+where
+    E: Embeddings,
+    V: VectorStore<E, M> + Send + Sync,
+    M: serde::Serialize + serde::de::DeserializeOwned,
+{
+    /// Retrieves the earlier exchanges most similar to the last message of the prompt.
+    async fn prepare(
+        &mut self,
+        history: &ChatMessageCollection<String>,
+        prompt: &Prompt,
+    ) -> Result<(), MemoryError> {
+        self.recent.prepare(history, prompt).await?;
+        let query = prompt
+            .to_chat()
+            .iter()
+            .last()
+            .map(|message| message.body().clone())
+            .unwrap_or_default();
+        self.retrieved = if query.trim().is_empty() {
+            vec![]
+        } else {
+            self.store
+                .similarity_search(query, self.limit)
+                .await
+                .map_err(|err| MemoryError::Store(err.to_string()))?
+                .into_iter()
+                .map(|document| document.page_content)
+                .collect()
+        };
+        Ok(())
+    }
+
+    async fn save_exchange(
+        &mut self,
+        input: &ChatMessageCollection<String>,
+        output: &ChatMessageCollection<String>,
+    ) -> Result<(), MemoryError> {
+        self.recent.save_exchange(input, output).await?;
+        let exchange = self.format_exchange(input, output)?;
+        self.store
+            .add_texts(vec![exchange])
+            .await
+            .map_err(|err| MemoryError::Store(err.to_string()))?;
+        Ok(())
+    }
+
+    fn context(
+        &self,
+        history: &ChatMessageCollection<String>,
+        tokenizer: &dyn Tokenizer,
+        max_tokens: usize,
+    ) -> Result<ChatMessageCollection<String>, TokenizerError> {
+        let retrieved: Vec<&str> = self.retrieved.iter().map(String::as_str).collect();
+        let mut memories = self.memories_message(&retrieved, tokenizer, max_tokens)?;
+        let memory_tokens = count_tokens(tokenizer, memories.as_slice())?;
+        let recent =
+            self.recent
+                .context(history, tokenizer, max_tokens.saturating_sub(memory_tokens))?;
+        let (mut context, rest) = split_system_prompt(&recent);
+        let sent = self.exchanges(&rest);
+        if retrieved.iter().any(|exchange| sent.contains(*exchange)) {
+            // Leaving out the exchanges that are sent anyway never takes more room than what was set aside.
+            let unsent: Vec<&str> = retrieved
+                .into_iter()
+                .filter(|exchange| !sent.contains(*exchange))
+                .collect();
+            memories = self.memories_message(&unsent, tokenizer, memory_tokens)?;
+        }
+        context.extend(memories);
+        context.extend(rest);
+        Ok(ChatMessageCollection::for_vector(context))
+    }
+
+    fn summary_request(
+        &self,
+        history: &ChatMessageCollection<String>,
+        tokenizer: &dyn Tokenizer,
+    ) -> Result<Option<SummaryRequest>, TokenizerError> {
+        self.recent.summary_request(history, tokenizer)
+    }
+
+    fn update_summary(&mut self, summary: String, summarized: usize) {
+        self.recent.update_summary(summary, summarized);
+    }
+
+    /// Forwards to the memory of recent messages. Exchanges already in the vector store are kept.
+    fn history_truncated(&mut self, history: &ChatMessageCollection<String>) {
+        self.recent.history_truncated(history);
+    }
+
+    fn state(&self) -> Option<serde_json::Value> {
+        self.recent.state()
+    }
+
+    fn restore_state(&mut self, state: serde_json::Value) -> Result<(), MemoryError> {
+        self.recent.restore_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VectorStoreMemory;
+    use crate::memory::{BufferWindowMemory, ConversationMemory};
+    use crate::prompt::{ChatMessageCollection, Data};
+    use crate::testing::{CharTokenizer, WordStore};
+
+    fn exchange(
+        input: &str,
+        output: &str,
+    ) -> (ChatMessageCollection<String>, ChatMessageCollection<String>) {
+        (
+            ChatMessageCollection::new().with_user(input.to_string()),
+            ChatMessageCollection::new().with_assistant(output.to_string()),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_adds_relevant_exchanges_after_the_system_prompt() {
+        let mut memory = VectorStoreMemory::new(WordStore::default())
+            .with_limit(1)
+            .with_recent(BufferWindowMemory::new(1));
+        for (input, output) in [("my dog is Rex", "Nice dog!"), ("I like tea", "Me too")] {
+            let (input, output) = exchange(input, output);
+            memory.save_exchange(&input, &output).await.unwrap();
+        }
+
+        let history = ChatMessageCollection::new()
+            .with_system("Be brief".to_string())
+            .with_user("I like tea".to_string())
+            .with_assistant("Me too".to_string());
+        let prompt = Data::Chat(
+            ChatMessageCollection::new().with_user("what is my dog called?".to_string()),
+        );
+        memory.prepare(&history, &prompt).await.unwrap();
+        let context = memory.context(&history, &CharTokenizer, 4096).unwrap();
+
+        let bodies: Vec<_> = context.iter().map(|m| m.body().as_str()).collect();
+        assert_eq!(
+            bodies,
+            vec![
+                "Be brief",
+                "Relevant parts of earlier conversations:\n\nUser: my dog is Rex\nAssistant: Nice dog!",
+                "Me too"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_leaves_out_exchanges_over_the_token_cap() {
+        let mut memory = VectorStoreMemory::new(WordStore::default()).with_max_tokens(10);
+        let (input, output) = exchange("my dog is Rex", "Nice dog!");
+        memory.save_exchange(&input, &output).await.unwrap();
+        let prompt = Data::Text("my dog".to_string());
+        memory
+            .prepare(&ChatMessageCollection::new(), &prompt)
+            .await
+            .unwrap();
+        let context = memory
+            .context(&ChatMessageCollection::new(), &CharTokenizer, 4096)
+            .unwrap();
+        assert!(context.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_leaves_out_exchanges_among_the_recent_messages() {
+        let mut memory = VectorStoreMemory::new(WordStore::default());
+        for (input, output) in [
+            ("my dog is Rex", "Nice dog!"),
+            ("my cat is Tom", "Nice cat!"),
+        ] {
+            let (input, output) = exchange(input, output);
+            memory.save_exchange(&input, &output).await.unwrap();
+        }
+
+        let history = ChatMessageCollection::new()
+            .with_user("my cat is Tom".to_string())
+            .with_assistant("Nice cat!".to_string());
+        let prompt = Data::Text("what are my dog and cat called?".to_string());
+        memory.prepare(&history, &prompt).await.unwrap();
+        let context = memory.context(&history, &CharTokenizer, 4096).unwrap();
+
+        let bodies: Vec<_> = context.iter().map(|m| m.body().as_str()).collect();
+        assert_eq!(
+            bodies,
+            vec![
+                "Relevant parts of earlier conversations:\n\nUser: my dog is Rex\nAssistant: Nice dog!",
+                "my cat is Tom",
+                "Nice cat!"
+            ]
+        );
+    }
+}
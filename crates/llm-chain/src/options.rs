@@ -92,6 +92,15 @@ impl Options {
             .iter()
             .find(|opt| OptDiscriminants::from(*opt) == opt_discriminant)
     }
+
+    /// Returns a copy of these options with `opt` added, unless an option of the same kind is already set.
+    pub(crate) fn with_default(&self, opt: Opt) -> Options {
+        let mut options = self.clone();
+        if self.get(OptDiscriminants::from(&opt)).is_none() {
+            options.opts.push(opt);
+        }
+        options
+    }
 }
 
 /// `options!` is a declarative macro that facilitates the creation of an `Options` instance.
@@ -35,7 +35,7 @@ impl OutputStream {
         (sender, Self { receiver })
     }
 
-    pub(super) fn from_stream<S>(stream: S) -> Self
+    pub(crate) fn from_stream<S>(stream: S) -> Self
     where
         S: Stream<Item = StreamSegment> + Send + 'static,
     {
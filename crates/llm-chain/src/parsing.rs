@@ -125,6 +125,15 @@ fn extract_yaml<T: DeserializeOwned>(code_block: &str) -> Result<T, ExtractionEr
 /// ";
 /// find_yaml::<serde_yaml::Value>(data).unwrap();
 /// ```
+///
+/// It finds JSON objects and arrays surrounded by prose, as chatty models like to answer.
+///
+/// ```
+/// use llm_chain::parsing::find_yaml;
+/// let data = r#"Sure! Here is the person: {"name": "Ada", "born": 1815}. Anything else?"#;
+/// let found = find_yaml::<serde_yaml::Value>(data).unwrap();
+/// assert_eq!(found[0]["born"], 1815);
+/// ```
 pub fn find_yaml<T: DeserializeOwned>(text: &str) -> Result<Vec<T>, ExtractionError> {
     let mut current_error = ExtractionErrorImpl::NoneFound;
     if text.is_empty() {
@@ -170,6 +179,17 @@ pub fn find_yaml<T: DeserializeOwned>(text: &str) -> Result<Vec<T>, ExtractionEr
             }
         }
     }
+    if found.is_empty() {
+        // Fall back to JSON embedded in prose.
+        for candidate in embedded_json(text) {
+            match extract_yaml(candidate) {
+                Ok(o) => found.push_back(o),
+                Err(e) => {
+                    current_error = ExtractionErrorImpl::most_representative(current_error, e)
+                }
+            }
+        }
+    }
     if !found.is_empty() {
         Ok(found.into())
     } else {
@@ -177,6 +197,43 @@ pub fn find_yaml<T: DeserializeOwned>(text: &str) -> Result<Vec<T>, ExtractionEr
     }
 }
 
+/// Finds the outermost JSON objects and arrays in a text, by matching brackets outside of strings.
+pub(crate) fn embedded_json(text: &str) -> Vec<&str> {
+    let mut candidates = vec![];
+    let mut depth = 0;
+    let mut start = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for (index, c) in text.char_indices() {
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match c {
+            '"' if depth > 0 => in_string = true,
+            '{' | '[' => {
+                if depth == 0 {
+                    start = index;
+                }
+                depth += 1;
+            }
+            '}' | ']' if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    candidates.push(&text[start..=index]);
+                }
+            }
+            _ => {}
+        }
+    }
+    candidates
+}
+
 /// Extracts labeled text from markdown
 ///
 /// LLMs often generate text that looks something like this
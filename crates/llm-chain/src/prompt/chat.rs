@@ -244,6 +244,11 @@ impl<Body> ChatMessageCollection<Body> {
         self.messages.pop_front()
     }
 
+    /// Keeps the first `len` messages of the collection and removes the rest.
+    pub fn truncate(&mut self, len: usize) {
+        self.messages.truncate(len);
+    }
+
     /// Returns the number of messages in the collection.
     pub fn len(&self) -> usize {
         self.messages.len()
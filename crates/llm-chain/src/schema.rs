@@ -2,6 +2,8 @@
 //!
 //! This schema is used to store documents in vector stores. It is used to store the document's content and metadata.
 
+use crate::Parameters;
+
 #[derive(Debug, Clone)]
 pub struct Document<M = EmptyMetadata>
 where
@@ -21,6 +23,34 @@ where
             metadata: None,
         }
     }
+
+    /// Attaches metadata to the document.
+    pub fn with_metadata(mut self, metadata: M) -> Self {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    /// Turns the document into `Parameters` for formatting prompts.
+    ///
+    /// The content is available as `page_content`. If the metadata serializes to a map, each of its fields is
+    /// available under its own name; strings are inserted as they are and other values as JSON.
+    pub fn to_parameters(&self) -> Parameters {
+        let mut parameters = Parameters::new().with("page_content", self.page_content.clone());
+        let metadata = self
+            .metadata
+            .as_ref()
+            .and_then(|metadata| serde_json::to_value(metadata).ok());
+        if let Some(serde_json::Value::Object(fields)) = metadata {
+            for (key, value) in fields {
+                parameters = match value {
+                    serde_json::Value::Null => parameters,
+                    serde_json::Value::String(value) => parameters.with(key, value),
+                    value => parameters.with(key, value.to_string()),
+                };
+            }
+        }
+        parameters
+    }
 }
 
 #[derive(Debug)]
@@ -61,3 +91,22 @@ impl<'de> Deserialize<'de> for EmptyMetadata {
         deserializer.deserialize_unit(EmptyMetadataVisitor)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Document;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_to_parameters_includes_metadata_fields() {
+        let metadata = HashMap::from([("source".to_string(), "manual.pdf".to_string())]);
+        let parameters = Document::new("The answer is 42.".to_string())
+            .with_metadata(metadata)
+            .to_parameters();
+        assert_eq!(
+            parameters.get("page_content").as_deref(),
+            Some("The answer is 42.")
+        );
+        assert_eq!(parameters.get("source").as_deref(), Some("manual.pdf"));
+    }
+}
@@ -190,6 +190,6 @@ pub trait StorableEntity: Serialize + DeserializeOwned {
         Envelope::<Self>::read_file_sync(path).map(|envelope| Self::from_envelope(envelope))
     }
     fn write_file_sync(self, path: &str) -> Result<(), EnvelopeError> {
-        Envelope::new(self).write_file_sync(path)
+        self.to_envelope().write_file_sync(path)
     }
 }
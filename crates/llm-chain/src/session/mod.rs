@@ -0,0 +1,747 @@
+//! Sessions keep conversations between requests.
+//!
+//! A `conversation::Chain` holds its whole history in memory, which doesn't suit stateless services handling
+//! every message of a conversation in a new request. A `SessionStore` keeps the messages of conversations keyed
+//! by a conversation id, and a `Session` restores a chain from it and appends the messages added to the chain
+//! once the request is done. The state of the memory of the chain, like the summary of a `SummaryMemory`, is
+//! stored with the messages, so it doesn't have to be written again by every request.
+//!
+//! Three stores are provided:
+//!
+//! - `InMemorySessionStore` keeps sessions in memory, which is mostly useful for tests.
+//! - `FileSessionStore` keeps every session in a JSON Lines file.
+//! - `SqliteSessionStore` keeps sessions in a SQLite database. It requires the `sqlite` feature.
+//!
+//! Stores append to sessions, so two requests of the same conversation never overwrite each other's messages.
+//! Only when a request changes messages that were already stored, by editing or regenerating them, is the
+//! session rewritten as a whole with `SessionStore::replace`. The memory state is replaced as a whole, so the last request to save it wins. Sessions that haven't been updated for a while can be removed with `SessionStore::delete_expired`.
+//!
+//! # Example
+//!
+//! ```ignore
+//! let store: Arc<dyn SessionStore> = Arc::new(FileSessionStore::new("sessions"));
+//! // In the handler of every request:
+//! let chain = Chain::new(prompt!(system: "You are a helpful assistant."))?;
+//! let mut session = Session::open(store.clone(), conversation_id, chain).await?;
+//! let answer = session.chain_mut().send_message(step, &parameters!(), &executor).await?;
+//! session.save().await?;
+//! ```
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::chains::conversation::Chain;
+use crate::memory::MemoryError;
+use crate::prompt::{ChatMessage, ChatMessageCollection};
+
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteSessionStore;
+
+/// The `SessionError` enum represents errors that can occur when reading or writing sessions.
+#[derive(Error, Debug)]
+pub enum SessionError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+    #[cfg(feature = "sqlite")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("Session store error: {0}")]
+    Store(String),
+    #[error("Memory error: {0}")]
+    Memory(#[from] MemoryError),
+}
+
+/// A stored session, as returned by `SessionStore::list`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionInfo {
+    pub id: String,
+    /// The number of messages in the session.
+    pub messages: usize,
+    /// When messages were last appended to the session.
+    pub updated_at: SystemTime,
+}
+
+/// A place to keep the messages of conversations, keyed by conversation id.
+#[async_trait]
+pub trait SessionStore: Send + Sync {
+    /// Returns the messages of the session, or `None` if there is no session with that id.
+    async fn load(&self, id: &str) -> Result<Option<ChatMessageCollection<String>>, SessionError>;
+
+    /// Appends messages to the session, creating it if it doesn't exist. Either all messages are appended or none
+    /// are, and concurrent appends to the same session don't overwrite each other.
+    async fn append(&self, id: &str, messages: &[ChatMessage<String>]) -> Result<(), SessionError>;
+
+    /// Replaces all messages of the session, creating it if it doesn't exist. Messages appended by others in the
+    /// meantime are lost.
+    async fn replace(&self, id: &str, messages: &[ChatMessage<String>])
+        -> Result<(), SessionError>;
+
+    /// Returns the memory state stored with the session, if any.
+    async fn load_memory_state(&self, id: &str) -> Result<Option<serde_json::Value>, SessionError>;
+
+    /// Replaces the memory state stored with the session, creating the session if it doesn't exist.
+    async fn save_memory_state(
+        &self,
+        id: &str,
+        state: &serde_json::Value,
+    ) -> Result<(), SessionError>;
+
+    /// Returns every stored session.
+    async fn list(&self) -> Result<Vec<SessionInfo>, SessionError>;
+
+    /// Deletes the session, returning whether it existed.
+    async fn delete(&self, id: &str) -> Result<bool, SessionError>;
+
+    /// Deletes the sessions that haven't been updated for longer than `ttl`, returning how many were deleted.
+    async fn delete_expired(&self, ttl: Duration) -> Result<usize, SessionError> {
+        let now = SystemTime::now();
+        let mut deleted = 0;
+        for session in self.list().await? {
+            let idle = now.duration_since(session.updated_at).unwrap_or_default();
+            if idle > ttl && self.delete(&session.id).await? {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+}
+
+/// A session kept by an `InMemorySessionStore`.
+struct StoredSession {
+    messages: Vec<ChatMessage<String>>,
+    memory_state: Option<serde_json::Value>,
+    updated_at: SystemTime,
+}
+
+impl StoredSession {
+    fn new() -> Self {
+        StoredSession {
+            messages: vec![],
+            memory_state: None,
+            updated_at: SystemTime::now(),
+        }
+    }
+}
+
+/// A `SessionStore` keeping sessions in memory, which is mostly useful for tests.
+#[derive(Default)]
+pub struct InMemorySessionStore {
+    sessions: Mutex<HashMap<String, StoredSession>>,
+}
+
+impl InMemorySessionStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl SessionStore for InMemorySessionStore {
+    async fn load(&self, id: &str) -> Result<Option<ChatMessageCollection<String>>, SessionError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .get(id)
+            .map(|session| ChatMessageCollection::for_vector(session.messages.clone())))
+    }
+
+    async fn append(&self, id: &str, messages: &[ChatMessage<String>]) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .entry(id.to_string())
+            .or_insert_with(StoredSession::new);
+        session.messages.extend_from_slice(messages);
+        session.updated_at = SystemTime::now();
+        Ok(())
+    }
+
+    async fn replace(
+        &self,
+        id: &str,
+        messages: &[ChatMessage<String>],
+    ) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .entry(id.to_string())
+            .or_insert_with(StoredSession::new);
+        session.messages = messages.to_vec();
+        session.updated_at = SystemTime::now();
+        Ok(())
+    }
+
+    async fn load_memory_state(&self, id: &str) -> Result<Option<serde_json::Value>, SessionError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .get(id)
+            .and_then(|session| session.memory_state.clone()))
+    }
+
+    async fn save_memory_state(
+        &self,
+        id: &str,
+        state: &serde_json::Value,
+    ) -> Result<(), SessionError> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let session = sessions
+            .entry(id.to_string())
+            .or_insert_with(StoredSession::new);
+        session.memory_state = Some(state.clone());
+        session.updated_at = SystemTime::now();
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<SessionInfo>, SessionError> {
+        let sessions = self.sessions.lock().unwrap();
+        Ok(sessions
+            .iter()
+            .map(|(id, session)| SessionInfo {
+                id: id.clone(),
+                messages: session.messages.len(),
+                updated_at: session.updated_at,
+            })
+            .collect())
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, SessionError> {
+        Ok(self.sessions.lock().unwrap().remove(id).is_some())
+    }
+}
+
+/// A `SessionStore` keeping every session in its own JSON Lines file, at `<directory>/<id>.jsonl`, with one
+/// message per line. The memory state is kept next to it, in `<directory>/<id>.memory.json`.
+///
+/// The messages of an append are written to the end of the file with a single write, so concurrent appends,
+/// even from several processes, don't overwrite each other. Should a process crash in the middle of an append,
+/// the incomplete line it leaves is ignored when loading, and the next append starts on a new line. The file is
+/// never truncated, as other processes may be appending to it. The modification time of the file is used as the
+/// time the session was updated, and saving the memory state updates it too.
+pub struct FileSessionStore {
+    directory: PathBuf,
+}
+
+impl FileSessionStore {
+    /// Creates a store writing to the given directory, which is created when the first session is saved.
+    pub fn new<P: Into<PathBuf>>(directory: P) -> Self {
+        FileSessionStore {
+            directory: directory.into(),
+        }
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf, SessionError> {
+        Ok(self.directory.join(format!("{}.jsonl", check_id(id)?)))
+    }
+
+    fn memory_state_path(&self, id: &str) -> Result<PathBuf, SessionError> {
+        Ok(self
+            .directory
+            .join(format!("{}.memory.json", check_id(id)?)))
+    }
+}
+
+/// Returns the id if it's safe to use as a file name.
+fn check_id(id: &str) -> Result<&str, SessionError> {
+    if id.is_empty() || id.contains(['/', '\\']) || id.starts_with('.') {
+        return Err(SessionError::Store(format!(
+            "`{}` can't be used as a session id",
+            id
+        )));
+    }
+    Ok(id)
+}
+
+/// Parses the lines of a session file, ignoring the incomplete lines left by appends that were cut short: a last
+/// line without a line break, which may still be being written, and lines ending in the middle of a message.
+fn parse_lines(contents: &str) -> Result<Vec<ChatMessage<String>>, SessionError> {
+    let complete = contents.rfind('\n').map_or("", |end| &contents[..end]);
+    let mut messages = vec![];
+    for line in complete.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str(line) {
+            Ok(message) => messages.push(message),
+            Err(err) if err.is_eof() => {
+                log::warn!("llm-chain skipped an incomplete line of a session file");
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(messages)
+}
+
+#[async_trait]
+impl SessionStore for FileSessionStore {
+    async fn load(&self, id: &str) -> Result<Option<ChatMessageCollection<String>>, SessionError> {
+        match tokio::fs::read_to_string(self.path(id)?).await {
+            Ok(contents) => Ok(Some(ChatMessageCollection::for_vector(parse_lines(
+                &contents,
+            )?))),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn append(&self, id: &str, messages: &[ChatMessage<String>]) -> Result<(), SessionError> {
+        let path = self.path(id)?;
+        let mut lines = to_lines(messages)?;
+        tokio::fs::create_dir_all(&self.directory).await?;
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .await?;
+        if lines.is_empty() {
+            return Ok(());
+        }
+        if !ends_with_line_break(&mut file).await? {
+            lines.insert(0, b'\n');
+        }
+        file.write_all(&lines).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    async fn replace(
+        &self,
+        id: &str,
+        messages: &[ChatMessage<String>],
+    ) -> Result<(), SessionError> {
+        let path = self.path(id)?;
+        tokio::fs::create_dir_all(&self.directory).await?;
+        write_atomically(&path, &to_lines(messages)?).await
+    }
+
+    async fn load_memory_state(&self, id: &str) -> Result<Option<serde_json::Value>, SessionError> {
+        match tokio::fs::read(self.memory_state_path(id)?).await {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn save_memory_state(
+        &self,
+        id: &str,
+        state: &serde_json::Value,
+    ) -> Result<(), SessionError> {
+        let path = self.memory_state_path(id)?;
+        tokio::fs::create_dir_all(&self.directory).await?;
+        write_atomically(&path, &serde_json::to_vec(state)?).await?;
+        // The session file marks the session as existing and when it was last updated, so it's created empty if
+        // no messages were appended, and touched otherwise.
+        self.append(id, &[]).await?;
+        let file = tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(self.path(id)?)
+            .await?;
+        file.into_std().await.set_modified(SystemTime::now())?;
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<Vec<SessionInfo>, SessionError> {
+        let mut entries = match tokio::fs::read_dir(&self.directory).await {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut sessions = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_none_or(|extension| extension != "jsonl")
+            {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let contents = tokio::fs::read_to_string(&path).await?;
+            sessions.push(SessionInfo {
+                id: id.to_string(),
+                messages: parse_lines(&contents)?.len(),
+                updated_at: entry.metadata().await?.modified()?,
+            });
+        }
+        Ok(sessions)
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, SessionError> {
+        match tokio::fs::remove_file(self.memory_state_path(id)?).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        match tokio::fs::remove_file(self.path(id)?).await {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// Serializes messages as JSON Lines.
+fn to_lines(messages: &[ChatMessage<String>]) -> Result<Vec<u8>, SessionError> {
+    let mut lines = vec![];
+    for message in messages {
+        serde_json::to_writer(&mut lines, message)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+/// Writes a file through a temporary file, so a crash never leaves it half-written.
+async fn write_atomically(path: &std::path::Path, contents: &[u8]) -> Result<(), SessionError> {
+    let temporary = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+    tokio::fs::write(&temporary, contents).await?;
+    tokio::fs::rename(&temporary, path).await?;
+    Ok(())
+}
+
+/// Returns whether a session file is empty or ends with a line break, so the next messages start on a line of
+/// their own.
+async fn ends_with_line_break(file: &mut tokio::fs::File) -> Result<bool, SessionError> {
+    if file.metadata().await?.len() == 0 {
+        return Ok(true);
+    }
+    let mut last = [0];
+    file.seek(std::io::SeekFrom::End(-1)).await?;
+    file.read_exact(&mut last).await?;
+    Ok(last[0] == b'\n')
+}
+
+/// A conversation restored from a `SessionStore`.
+pub struct Session {
+    store: Arc<dyn SessionStore>,
+    id: String,
+    chain: Chain,
+    /// The messages known to be stored.
+    saved: Vec<ChatMessage<String>>,
+}
+
+impl Session {
+    /// Opens the session with the given id, restoring its messages and the state of its memory into `chain`. If
+    /// the session isn't stored yet, the messages `chain` starts with, like its system prompt, become the first
+    /// messages of the session.
+    pub async fn open(
+        store: Arc<dyn SessionStore>,
+        id: impl Into<String>,
+        chain: Chain,
+    ) -> Result<Session, SessionError> {
+        let id = id.into();
+        let (mut chain, saved) = match store.load(&id).await? {
+            Some(history) => {
+                let saved = history.iter().cloned().collect();
+                (chain.with_history(history), saved)
+            }
+            None => (chain, vec![]),
+        };
+        if let Some(state) = store.load_memory_state(&id).await? {
+            chain.restore_memory_state(state)?;
+        }
+        Ok(Session {
+            store,
+            id,
+            chain,
+            saved,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn chain(&self) -> &Chain {
+        &self.chain
+    }
+
+    /// Returns the chain, to send messages in the conversation.
+    pub fn chain_mut(&mut self) -> &mut Chain {
+        &mut self.chain
+    }
+
+    /// Appends the messages added to the chain since the session was opened or last saved to the store, and
+    /// stores the state of its memory once it has seen every exchange, see `Chain::flush_memory`.
+    ///
+    /// If stored messages were changed in the meantime, with `Chain::edit_message` or `Chain::regenerate` for
+    /// example, the stored session is replaced with the history of the chain instead.
+    pub async fn save(&mut self) -> Result<(), SessionError> {
+        self.chain.flush_memory().await?;
+        let history: Vec<_> = self.chain.history().iter().cloned().collect();
+        let unchanged = history.len() >= self.saved.len()
+            && self.saved.iter().zip(&history).all(|(saved, message)| {
+                saved.role() == message.role() && saved.body() == message.body()
+            });
+        if !unchanged {
+            self.store.replace(&self.id, &history).await?;
+        } else if history.len() > self.saved.len() {
+            self.store
+                .append(&self.id, &history[self.saved.len()..])
+                .await?;
+        }
+        self.saved = history;
+        if let Some(state) = self.chain.memory_state() {
+            self.store.save_memory_state(&self.id, &state).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
+
+    use super::{FileSessionStore, InMemorySessionStore, Session, SessionStore};
+    use crate::chains::conversation::Chain;
+    use crate::memory::SummaryMemory;
+    use crate::prompt::{ChatMessage, ChatMessageCollection};
+    use crate::testing::{RecordingMemory, ScriptedExecutor};
+    use crate::{options, prompt, step::Step, Parameters};
+
+    fn bodies(messages: &ChatMessageCollection<String>) -> Vec<String> {
+        messages.iter().map(|m| m.body().clone()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_sessions_are_restored_between_requests() {
+        let store: Arc<dyn SessionStore> = Arc::new(InMemorySessionStore::new());
+        let executor = ScriptedExecutor::new(|prompt| prompt.lines().count().to_string());
+        let step = Step::for_prompt_template(prompt!(user: "{{text}}"));
+        for text in ["Hi", "Again"] {
+            let chain = Chain::new(prompt!(system: "Be brief")).unwrap();
+            let mut session = Session::open(store.clone(), "conversation", chain)
+                .await
+                .unwrap();
+            session
+                .chain_mut()
+                .send_message(step.clone(), &Parameters::new_with_text(text), &executor)
+                .await
+                .unwrap();
+            session.save().await.unwrap();
+        }
+
+        let history = store.load("conversation").await.unwrap().unwrap();
+        assert_eq!(bodies(&history), vec!["Be brief", "Hi", "2", "Again", "4"]);
+        assert_eq!(store.list().await.unwrap()[0].messages, 5);
+    }
+
+    #[tokio::test]
+    async fn test_file_store_appends_replaces_lists_and_expires_sessions() {
+        let directory = std::env::temp_dir().join(format!("sessions-{}", uuid::Uuid::new_v4()));
+        let store = FileSessionStore::new(&directory);
+        assert!(store.load("chat").await.unwrap().is_none());
+        store
+            .append("chat", &[ChatMessage::user("Hi".to_string())])
+            .await
+            .unwrap();
+        store
+            .append("chat", &[ChatMessage::assistant("Hello".to_string())])
+            .await
+            .unwrap();
+
+        let history = store.load("chat").await.unwrap().unwrap();
+        assert_eq!(bodies(&history), vec!["Hi", "Hello"]);
+        let sessions = store.list().await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!((sessions[0].id.as_str(), sessions[0].messages), ("chat", 2));
+        assert!(store.append("../chat", &[]).await.is_err());
+        store
+            .replace("chat", &[ChatMessage::user("Hey".to_string())])
+            .await
+            .unwrap();
+        let history = store.load("chat").await.unwrap().unwrap();
+        assert_eq!(bodies(&history), vec!["Hey"]);
+
+        assert_eq!(
+            store
+                .delete_expired(Duration::from_secs(3600))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(store.delete_expired(Duration::ZERO).await.unwrap(), 1);
+        assert!(!store.delete("chat").await.unwrap());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_memory_state_is_restored_between_requests() {
+        let store: Arc<dyn SessionStore> = Arc::new(InMemorySessionStore::new());
+        let summary_prompts = Arc::new(Mutex::new(vec![]));
+        let recorded = summary_prompts.clone();
+        let executor = ScriptedExecutor::new(move |prompt| {
+            if !prompt.contains("Progressively summarize") {
+                return "ok".to_string();
+            }
+            let mut prompts = recorded.lock().unwrap();
+            prompts.push(prompt.to_string());
+            format!("Summary {}", prompts.len())
+        });
+        let step = Step::for_prompt_template(prompt!(user: "{{text}}"));
+        for text in ["Hi", "Again", "More"] {
+            let chain = Chain::new(prompt!(system: "Be brief"))
+                .unwrap()
+                .with_memory(SummaryMemory::new());
+            let mut session = Session::open(store.clone(), "conversation", chain)
+                .await
+                .unwrap();
+            session
+                .chain_mut()
+                .send_message(step.clone(), &Parameters::new_with_text(text), &executor)
+                .await
+                .unwrap();
+            session.save().await.unwrap();
+        }
+
+        let prompts = summary_prompts.lock().unwrap();
+        assert_eq!(prompts.len(), 2);
+        // The last request only summarizes the new messages, on top of the summary stored by the one before.
+        assert!(prompts[1].contains("Summary 1"));
+        assert!(prompts[1].contains("Again") && !prompts[1].contains("Hi"));
+    }
+
+    #[tokio::test]
+    async fn test_file_store_recovers_from_an_incomplete_line() {
+        let directory = std::env::temp_dir().join(format!("sessions-{}", uuid::Uuid::new_v4()));
+        let store = FileSessionStore::new(&directory);
+        store
+            .append("chat", &[ChatMessage::user("Hi".to_string())])
+            .await
+            .unwrap();
+        // A process crashing in the middle of an append leaves half a line behind.
+        let path = directory.join("chat.jsonl");
+        let mut contents = std::fs::read_to_string(&path).unwrap();
+        contents.push_str("{\"role\":\"Assis");
+        std::fs::write(&path, contents).unwrap();
+
+        let history = store.load("chat").await.unwrap().unwrap();
+        assert_eq!(bodies(&history), vec!["Hi"]);
+        store
+            .append("chat", &[ChatMessage::assistant("Hello".to_string())])
+            .await
+            .unwrap();
+        let history = store.load("chat").await.unwrap().unwrap();
+        assert_eq!(bodies(&history), vec!["Hi", "Hello"]);
+        // The incomplete line is left in place rather than cut off under the feet of other writers.
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.contains("{\"role\":\"Assis\n"));
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_file_store_dates_memory_state_updates() {
+        let directory = std::env::temp_dir().join(format!("sessions-{}", uuid::Uuid::new_v4()));
+        let store = FileSessionStore::new(&directory);
+        store
+            .append("chat", &[ChatMessage::user("Hi".to_string())])
+            .await
+            .unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(7200);
+        std::fs::File::options()
+            .append(true)
+            .open(directory.join("chat.jsonl"))
+            .unwrap()
+            .set_modified(long_ago)
+            .unwrap();
+
+        let state = serde_json::json!({"summary": "Greetings"});
+        store.save_memory_state("chat", &state).await.unwrap();
+        assert!(store.list().await.unwrap()[0].updated_at > long_ago);
+        assert_eq!(
+            store
+                .delete_expired(Duration::from_secs(3600))
+                .await
+                .unwrap(),
+            0
+        );
+        assert_eq!(store.list().await.unwrap()[0].messages, 1);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_edited_and_regenerated_sessions_are_rewritten() {
+        let store: Arc<dyn SessionStore> = Arc::new(InMemorySessionStore::new());
+        let answers = Mutex::new(0);
+        let executor = ScriptedExecutor::new(move |_| {
+            let mut answers = answers.lock().unwrap();
+            *answers += 1;
+            format!("answer {}", answers)
+        });
+        let step = Step::for_prompt_template(prompt!(user: "{{text}}"));
+        let open = || async {
+            let chain = Chain::new(prompt!(system: "Be brief")).unwrap();
+            Session::open(store.clone(), "conversation", chain)
+                .await
+                .unwrap()
+        };
+
+        let mut session = open().await;
+        for text in ["Hi", "Again"] {
+            session
+                .chain_mut()
+                .send_message(step.clone(), &Parameters::new_with_text(text), &executor)
+                .await
+                .unwrap();
+        }
+        session.save().await.unwrap();
+
+        let mut session = open().await;
+        session
+            .chain_mut()
+            .edit_message(1, "Hello".to_string())
+            .unwrap();
+        session
+            .chain_mut()
+            .regenerate(step.options(), &executor)
+            .await
+            .unwrap();
+        session.save().await.unwrap();
+        let history = store.load("conversation").await.unwrap().unwrap();
+        assert_eq!(bodies(&history), vec!["Be brief", "Hello", "answer 3"]);
+
+        let mut session = open().await;
+        session
+            .chain_mut()
+            .regenerate(step.options(), &executor)
+            .await
+            .unwrap();
+        session.save().await.unwrap();
+        let history = store.load("conversation").await.unwrap().unwrap();
+        assert_eq!(bodies(&history), vec!["Be brief", "Hello", "answer 4"]);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_exchanges_reach_the_memory_on_save() {
+        let store: Arc<dyn SessionStore> = Arc::new(InMemorySessionStore::new());
+        let executor = ScriptedExecutor::new(|_| "Hello there".to_string());
+        let memory = RecordingMemory::default();
+        let chain = Chain::new(prompt!(system: "Be brief"))
+            .unwrap()
+            .with_memory(memory.clone());
+        let mut session = Session::open(store.clone(), "conversation", chain)
+            .await
+            .unwrap();
+        let step = Step::for_prompt_and_options(prompt!(user: "{{text}}"), options!(Stream: true));
+        session
+            .chain_mut()
+            .stream_message(step, &Parameters::new_with_text("Hi"), &executor)
+            .await
+            .unwrap()
+            .finish()
+            .await
+            .unwrap();
+        session.save().await.unwrap();
+
+        assert_eq!(memory.exchanges(), vec!["Hi -> Hello there"]);
+        let history = store.load("conversation").await.unwrap().unwrap();
+        assert_eq!(bodies(&history), vec!["Be brief", "Hi", "Hello there"]);
+    }
+}
@@ -0,0 +1,282 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{SessionError, SessionInfo, SessionStore};
+use crate::prompt::{ChatMessage, ChatMessageCollection};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS llm_chain_sessions (
+    id TEXT PRIMARY KEY,
+    updated_at INTEGER NOT NULL,
+    memory_state TEXT
+);
+CREATE TABLE IF NOT EXISTS llm_chain_session_messages (
+    session_id TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (session_id, position)
+);
+";
+
+/// A `SessionStore` keeping sessions in a SQLite database, in the `llm_chain_sessions` and
+/// `llm_chain_session_messages` tables, which are created if they don't exist.
+///
+/// Every append is a transaction. The connection is used from a blocking thread, so the store can be shared by
+/// the tasks of a server.
+pub struct SqliteSessionStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteSessionStore {
+    /// Opens the database at the given path, creating it if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, SessionError> {
+        Self::new(Connection::open(path)?)
+    }
+
+    /// Opens a database living in memory, which is lost when the store is dropped.
+    pub fn open_in_memory() -> Result<Self, SessionError> {
+        Self::new(Connection::open_in_memory()?)
+    }
+
+    /// Creates a store using the given connection.
+    pub fn new(connection: Connection) -> Result<Self, SessionError> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteSessionStore {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    async fn with_connection<T, F>(&self, f: F) -> Result<T, SessionError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> Result<T, SessionError> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || f(&mut connection.lock().unwrap()))
+            .await
+            .map_err(|err| SessionError::Store(err.to_string()))?
+    }
+}
+
+fn to_millis(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as i64
+}
+
+fn from_millis(millis: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis.max(0) as u64)
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn load(&self, id: &str) -> Result<Option<ChatMessageCollection<String>>, SessionError> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            let exists = connection
+                .query_row(
+                    "SELECT 1 FROM llm_chain_sessions WHERE id = ?1",
+                    params![id],
+                    |_| Ok(()),
+                )
+                .optional()?;
+            if exists.is_none() {
+                return Ok(None);
+            }
+            let mut statement = connection.prepare(
+                "SELECT message FROM llm_chain_session_messages WHERE session_id = ?1 ORDER BY position",
+            )?;
+            let rows = statement.query_map(params![id], |row| row.get::<_, String>(0))?;
+            let mut messages = vec![];
+            for row in rows {
+                messages.push(serde_json::from_str(&row?)?);
+            }
+            Ok(Some(ChatMessageCollection::for_vector(messages)))
+        })
+        .await
+    }
+
+    async fn append(&self, id: &str, messages: &[ChatMessage<String>]) -> Result<(), SessionError> {
+        let id = id.to_string();
+        let messages = messages
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO llm_chain_sessions (id, updated_at) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET updated_at = excluded.updated_at",
+                params![id, to_millis(SystemTime::now())],
+            )?;
+            let next: i64 = transaction.query_row(
+                "SELECT COALESCE(MAX(position) + 1, 0) FROM llm_chain_session_messages WHERE session_id = ?1",
+                params![id],
+                |row| row.get(0),
+            )?;
+            for (offset, message) in messages.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO llm_chain_session_messages (session_id, position, message) VALUES (?1, ?2, ?3)",
+                    params![id, next + offset as i64, message],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn replace(
+        &self,
+        id: &str,
+        messages: &[ChatMessage<String>],
+    ) -> Result<(), SessionError> {
+        let id = id.to_string();
+        let messages = messages
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "INSERT INTO llm_chain_sessions (id, updated_at) VALUES (?1, ?2)
+                 ON CONFLICT (id) DO UPDATE SET updated_at = excluded.updated_at",
+                params![id, to_millis(SystemTime::now())],
+            )?;
+            transaction.execute(
+                "DELETE FROM llm_chain_session_messages WHERE session_id = ?1",
+                params![id],
+            )?;
+            for (position, message) in messages.iter().enumerate() {
+                transaction.execute(
+                    "INSERT INTO llm_chain_session_messages (session_id, position, message) VALUES (?1, ?2, ?3)",
+                    params![id, position as i64, message],
+                )?;
+            }
+            transaction.commit()?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn load_memory_state(&self, id: &str) -> Result<Option<serde_json::Value>, SessionError> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            let state = connection
+                .query_row(
+                    "SELECT memory_state FROM llm_chain_sessions WHERE id = ?1",
+                    params![id],
+                    |row| row.get::<_, Option<String>>(0),
+                )
+                .optional()?
+                .flatten();
+            Ok(state
+                .map(|state| serde_json::from_str(&state))
+                .transpose()?)
+        })
+        .await
+    }
+
+    async fn save_memory_state(
+        &self,
+        id: &str,
+        state: &serde_json::Value,
+    ) -> Result<(), SessionError> {
+        let id = id.to_string();
+        let state = serde_json::to_string(state)?;
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO llm_chain_sessions (id, updated_at, memory_state) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (id) DO UPDATE SET updated_at = excluded.updated_at, memory_state = excluded.memory_state",
+                params![id, to_millis(SystemTime::now()), state],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<Vec<SessionInfo>, SessionError> {
+        self.with_connection(|connection| {
+            let mut statement = connection.prepare(
+                "SELECT s.id, s.updated_at, COUNT(m.position)
+                 FROM llm_chain_sessions s
+                 LEFT JOIN llm_chain_session_messages m ON m.session_id = s.id
+                 GROUP BY s.id
+                 ORDER BY s.id",
+            )?;
+            let rows = statement.query_map([], |row| {
+                Ok(SessionInfo {
+                    id: row.get(0)?,
+                    updated_at: from_millis(row.get(1)?),
+                    messages: row.get::<_, i64>(2)? as usize,
+                })
+            })?;
+            Ok(rows.collect::<Result<Vec<_>, _>>()?)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, SessionError> {
+        let id = id.to_string();
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute(
+                "DELETE FROM llm_chain_session_messages WHERE session_id = ?1",
+                params![id],
+            )?;
+            let deleted =
+                transaction.execute("DELETE FROM llm_chain_sessions WHERE id = ?1", params![id])?;
+            transaction.commit()?;
+            Ok(deleted > 0)
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::SqliteSessionStore;
+    use crate::prompt::ChatMessage;
+    use crate::session::SessionStore;
+
+    #[tokio::test]
+    async fn test_appends_and_deletes_sessions() {
+        let store = SqliteSessionStore::open_in_memory().unwrap();
+        let messages = [
+            ChatMessage::user("Hi".to_string()),
+            ChatMessage::assistant("Hello".to_string()),
+        ];
+        store.append("chat", &messages).await.unwrap();
+        store.append("chat", &messages[..1]).await.unwrap();
+
+        let history = store.load("chat").await.unwrap().unwrap();
+        let bodies: Vec<_> = history.iter().map(|m| m.body().as_str()).collect();
+        assert_eq!(bodies, vec!["Hi", "Hello", "Hi"]);
+        assert_eq!(store.list().await.unwrap()[0].messages, 3);
+        store.replace("chat", &messages[1..]).await.unwrap();
+        let history = store.load("chat").await.unwrap().unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!(history.get_message(0).unwrap().body(), "Hello");
+        assert!(store.load("other").await.unwrap().is_none());
+        assert!(store.load_memory_state("chat").await.unwrap().is_none());
+        let state = serde_json::json!({"summary": "Greetings"});
+        store.save_memory_state("chat", &state).await.unwrap();
+        assert_eq!(store.load_memory_state("chat").await.unwrap(), Some(state));
+
+        assert_eq!(
+            store
+                .delete_expired(Duration::from_secs(3600))
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(store.delete("chat").await.unwrap());
+        assert!(store.list().await.unwrap().is_empty());
+    }
+}
@@ -6,15 +6,19 @@ use crate::options::Opt;
 use crate::options::Options;
 use crate::output::Output;
 use crate::prompt::{Prompt, StringTemplateError};
+use crate::structured::{self, StructuredOutputError};
 use crate::traits::Executor;
 use crate::{chains::sequential, prompt, Parameters};
 
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 #[derive(derive_builder::Builder, Debug, Clone, Serialize, Deserialize)]
 /// A step in a chain of LLM invocations. It is a combination of a prompt and a configuration.
 pub struct Step {
     pub(crate) prompt: prompt::PromptTemplate,
+    // Optional, so steps written by hand don't need an empty set of options.
+    #[serde(default)]
     pub(crate) options: Options,
 }
 
@@ -77,4 +81,35 @@ impl Step {
             .format_and_execute(parameters)
             .await
     }
+
+    /// Executes the step and parses the output into a value of type `T`, see the `structured` module.
+    ///
+    /// The JSON schema of `T` is appended to the prompt, and the model is asked again with the errors it made
+    /// when its answer doesn't match the schema, up to `structured::DEFAULT_RETRIES` times.
+    pub async fn run_typed<T, E>(
+        &self,
+        parameters: &Parameters,
+        executor: &E,
+    ) -> Result<T, StructuredOutputError>
+    where
+        T: DeserializeOwned + schemars::JsonSchema,
+        E: Executor,
+    {
+        self.run_typed_with_retries(parameters, executor, structured::DEFAULT_RETRIES)
+            .await
+    }
+
+    /// Like `run_typed`, asking the model again up to `retries` times after an invalid answer.
+    pub async fn run_typed_with_retries<T, E>(
+        &self,
+        parameters: &Parameters,
+        executor: &E,
+        retries: usize,
+    ) -> Result<T, StructuredOutputError>
+    where
+        T: DeserializeOwned + schemars::JsonSchema,
+        E: Executor,
+    {
+        structured::run_typed(self, parameters, executor, retries).await
+    }
 }
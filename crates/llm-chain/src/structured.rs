@@ -0,0 +1,491 @@
+//! Typed outputs, parsed from the answers of the model into your own types.
+//!
+//! `Step::run_typed` asks the model for a value of any type implementing `serde::Deserialize` and
+//! `schemars::JsonSchema`. The JSON schema of the type is appended to the prompt as format instructions, and the
+//! answer is parsed leniently with `parsing::find_yaml`, so JSON or YAML, in a code block or surrounded by prose,
+//! are all accepted. The parsed value is checked against the schema, and when it doesn't match, the model is asked
+//! again with the errors it made, up to a number of retries.
+//!
+//! # Example
+//!
+//! ```ignore
+//! // `schemars` is re-exported here, so `JsonSchema` can be derived without depending on it.
+//! use llm_chain::structured::schemars::{self, JsonSchema};
+//!
+//! #[derive(Deserialize, JsonSchema)]
+//! struct Invoice {
+//!     number: String,
+//!     total: f64,
+//!     lines: Vec<String>,
+//! }
+//!
+//! let step = Step::for_prompt_template(prompt!("Extract the invoice from this email:\n{{text}}"));
+//! let invoice: Invoice = step.run_typed(&parameters!(email), &executor).await?;
+//! ```
+
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use thiserror::Error;
+
+use crate::frame::{FormatAndExecuteError, Frame};
+use crate::parsing::{embedded_json, find_yaml};
+use crate::prompt::{ChatMessage, Data, Prompt, StringTemplate};
+use crate::{step::Step, traits::Executor, Parameters};
+
+pub use schemars;
+
+/// The number of times the model is asked again after an invalid answer, by default.
+pub const DEFAULT_RETRIES: usize = 2;
+
+/// The `StructuredOutputError` enum represents errors that can occur when asking the model for a typed value.
+#[derive(Error, Debug)]
+pub enum StructuredOutputError {
+    #[error("FormatAndExecuteError: {0}")]
+    FormatAndExecuteError(#[from] FormatAndExecuteError),
+    /// Every answer was invalid. The errors and the output are those of the last answer.
+    #[error("No valid output after {attempts} attempts: {}", .errors.join("; "))]
+    Invalid {
+        attempts: usize,
+        errors: Vec<String>,
+        output: String,
+    },
+}
+
+/// Returns the JSON schema of a type.
+pub fn schema_for<T: JsonSchema>() -> Value {
+    let mut schema = serde_json::to_value(schemars::schema_for!(T)).unwrap_or(Value::Bool(true));
+    if let Some(schema) = schema.as_object_mut() {
+        // It only tells the model which draft the schema follows.
+        schema.remove("$schema");
+    }
+    schema
+}
+
+/// Returns the instructions appended to prompts asking for a value matching the schema.
+pub fn format_instructions(schema: &Value) -> String {
+    format!(
+        "Answer with a JSON value matching the following JSON schema, in a ```json code block:\n\n```json\n{}\n```",
+        serde_json::to_string_pretty(schema).unwrap_or_default()
+    )
+}
+
+/// Parses a value matching the schema out of the output of a model, returning the errors found otherwise.
+///
+/// When the output contains several JSON or YAML values, the first one that is valid is returned. JSON embedded in
+/// prose is tried as well, since prose like `Answer: {...}` is valid YAML itself and would otherwise hide it.
+pub fn parse<T: DeserializeOwned>(output: &str, schema: &Value) -> Result<T, Vec<String>> {
+    let (mut candidates, not_found) = match find_yaml::<Value>(output) {
+        Ok(found) => (found, None),
+        Err(err) => (vec![], Some(err.to_string())),
+    };
+    for embedded in embedded_json(output) {
+        if let Ok(candidate) = serde_yaml::from_str::<Value>(embedded) {
+            if !candidates.contains(&candidate) {
+                candidates.push(candidate);
+            }
+        }
+    }
+    if candidates.is_empty() {
+        return Err(not_found.into_iter().collect());
+    }
+    let mut errors = vec![];
+    for candidate in candidates {
+        if let Err(invalid) = validate(schema, &candidate) {
+            errors.extend(invalid);
+            continue;
+        }
+        match serde_json::from_value(candidate) {
+            Ok(value) => return Ok(value),
+            Err(err) => errors.push(err.to_string()),
+        }
+    }
+    Err(errors)
+}
+
+/// Checks a value against a JSON schema, returning every violation found.
+///
+/// This supports the parts of JSON schema generated by `schemars`: types, properties, required and additional
+/// properties, items, enums, constants, references to definitions, combinations with `allOf`, `anyOf` and
+/// `oneOf`, and bounds on numbers, strings and arrays. Other keywords are ignored.
+pub fn validate(schema: &Value, value: &Value) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    Validator { root: schema }.check(schema, value, "$", &mut errors);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+struct Validator<'a> {
+    root: &'a Value,
+}
+
+impl Validator<'_> {
+    fn check(&self, schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => {
+                return errors.push(format!("{}: no value is allowed here", path))
+            }
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match reference
+                .strip_prefix('#')
+                .and_then(|pointer| self.root.pointer(pointer))
+            {
+                Some(target) => self.check(target, value, path, errors),
+                None => errors.push(format!(
+                    "{}: unknown schema reference `{}`",
+                    path, reference
+                )),
+            }
+        }
+        if let Some(types) = schema.get("type") {
+            let allowed: Vec<&str> = match types {
+                Value::String(name) => vec![name.as_str()],
+                Value::Array(names) => names.iter().filter_map(Value::as_str).collect(),
+                _ => vec![],
+            };
+            if !allowed.is_empty() && !allowed.iter().any(|name| has_type(value, name)) {
+                errors.push(format!(
+                    "{}: expected {}, found {}",
+                    path,
+                    allowed.join(" or "),
+                    type_name(value)
+                ));
+                return;
+            }
+        }
+        if let Some(Value::Array(options)) = schema.get("enum") {
+            if !options.contains(value) {
+                errors.push(format!(
+                    "{}: {} isn't one of {}",
+                    path,
+                    value,
+                    Value::Array(options.clone())
+                ));
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                errors.push(format!("{}: expected {}, found {}", path, constant, value));
+            }
+        }
+        if let Some(Value::Array(all)) = schema.get("allOf") {
+            for schema in all {
+                self.check(schema, value, path, errors);
+            }
+        }
+        for keyword in ["anyOf", "oneOf"] {
+            if let Some(Value::Array(options)) = schema.get(keyword) {
+                let matching = options
+                    .iter()
+                    .filter(|schema| {
+                        let mut option_errors = vec![];
+                        self.check(schema, value, path, &mut option_errors);
+                        option_errors.is_empty()
+                    })
+                    .count();
+                if matching == 0 || (keyword == "oneOf" && matching > 1) {
+                    errors.push(format!(
+                        "{}: {} matches {} of the allowed alternatives instead of {}",
+                        path,
+                        value,
+                        matching,
+                        if keyword == "oneOf" { "one" } else { "any" }
+                    ));
+                }
+            }
+        }
+        match value {
+            Value::Object(object) => {
+                if let Some(Value::Array(required)) = schema.get("required") {
+                    for name in required.iter().filter_map(Value::as_str) {
+                        if !object.contains_key(name) {
+                            errors.push(format!("{}: missing required property `{}`", path, name));
+                        }
+                    }
+                }
+                let properties = schema.get("properties").and_then(Value::as_object);
+                for (name, property) in object {
+                    let property_path = format!("{}.{}", path, name);
+                    match properties.and_then(|properties| properties.get(name)) {
+                        Some(property_schema) => {
+                            self.check(property_schema, property, &property_path, errors)
+                        }
+                        None => match schema.get("additionalProperties") {
+                            Some(Value::Bool(false)) => {
+                                errors.push(format!("{}: unknown property `{}`", path, name))
+                            }
+                            Some(additional) => {
+                                self.check(additional, property, &property_path, errors)
+                            }
+                            None => {}
+                        },
+                    }
+                }
+            }
+            Value::Array(items) => {
+                check_bound(
+                    schema,
+                    "minItems",
+                    items.len(),
+                    |len, min| len >= min,
+                    path,
+                    errors,
+                );
+                check_bound(
+                    schema,
+                    "maxItems",
+                    items.len(),
+                    |len, max| len <= max,
+                    path,
+                    errors,
+                );
+                match schema.get("items") {
+                    Some(Value::Array(positional)) => {
+                        for (index, (item, schema)) in items.iter().zip(positional).enumerate() {
+                            self.check(schema, item, &format!("{}[{}]", path, index), errors);
+                        }
+                    }
+                    Some(item_schema) => {
+                        for (index, item) in items.iter().enumerate() {
+                            self.check(item_schema, item, &format!("{}[{}]", path, index), errors);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            Value::String(string) => {
+                let len = string.chars().count();
+                check_bound(
+                    schema,
+                    "minLength",
+                    len,
+                    |len, min| len >= min,
+                    path,
+                    errors,
+                );
+                check_bound(
+                    schema,
+                    "maxLength",
+                    len,
+                    |len, max| len <= max,
+                    path,
+                    errors,
+                );
+            }
+            Value::Number(number) => {
+                let number = number.as_f64().unwrap_or_default();
+                if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+                    if number < minimum {
+                        errors.push(format!("{}: {} is less than {}", path, number, minimum));
+                    }
+                }
+                if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+                    if number > maximum {
+                        errors.push(format!("{}: {} is more than {}", path, number, maximum));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn check_bound(
+    schema: &serde_json::Map<String, Value>,
+    keyword: &str,
+    len: usize,
+    within: fn(usize, usize) -> bool,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    if let Some(bound) = schema.get(keyword).and_then(Value::as_u64) {
+        if !within(len, bound as usize) {
+            errors.push(format!(
+                "{}: has length {}, which violates {} {}",
+                path, len, keyword, bound
+            ));
+        }
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => {
+            value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0)
+        }
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Runs the step, asking for a value of type `T`, and asks again up to `retries` times when the answer is invalid.
+///
+/// Every attempt runs in a `Frame`, like `Step::run`, so it's traced like any other step.
+pub(crate) async fn run_typed<T, E>(
+    step: &Step,
+    parameters: &Parameters,
+    executor: &E,
+    retries: usize,
+) -> Result<T, StructuredOutputError>
+where
+    T: DeserializeOwned + JsonSchema,
+    E: Executor,
+{
+    let schema = schema_for::<T>();
+    let mut prompt = step
+        .format(parameters)
+        .map_err(FormatAndExecuteError::from)?
+        .combine(&Data::Text(format_instructions(&schema)));
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        // The prompt is complete already, so the step of the attempt sends it as it is.
+        let attempt = Step::for_prompt_and_options(
+            prompt.map(|body| StringTemplate::static_string(body.clone())),
+            step.options().clone(),
+        );
+        let output = Frame::new(executor, &attempt)
+            .format_and_execute(&Parameters::new())
+            .await?
+            .to_immediate()
+            .await
+            .map_err(FormatAndExecuteError::from)?
+            .primary_textual_output()
+            .unwrap_or_default();
+        match parse(&output, &schema) {
+            Ok(value) => return Ok(value),
+            Err(errors) if attempts > retries => {
+                return Err(StructuredOutputError::Invalid {
+                    attempts,
+                    errors,
+                    output,
+                })
+            }
+            Err(errors) => prompt = reask(&prompt, output, &errors),
+        }
+    }
+}
+
+/// Adds an invalid answer and the errors it contains to the prompt, asking for a corrected answer.
+fn reask(prompt: &Prompt, output: String, errors: &[String]) -> Prompt {
+    let mut chat = prompt.to_chat();
+    chat.add_message(ChatMessage::assistant(output));
+    chat.add_message(ChatMessage::user(format!(
+        "Your answer isn't valid:\n{}\n\nAnswer again with the corrected JSON value, in a ```json code block.",
+        errors
+            .iter()
+            .map(|error| format!("- {}", error))
+            .collect::<Vec<_>>()
+            .join("\n")
+    )));
+    Data::Chat(chat)
+}
+
+#[cfg(test)]
+mod tests {
+    use schemars::JsonSchema;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use super::{parse, schema_for, validate, StructuredOutputError};
+    use crate::testing::ScriptedExecutor;
+    use crate::{prompt, step::Step, Parameters};
+
+    #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+    enum Mood {
+        Happy,
+        Sad,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize, JsonSchema)]
+    struct Review {
+        stars: u8,
+        mood: Mood,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn test_validates_against_the_schema() {
+        let schema = schema_for::<Review>();
+        assert!(validate(&schema, &json!({"stars": 4, "mood": "Happy", "tags": []})).is_ok());
+        let errors = validate(&schema, &json!({"stars": "many", "mood": "Angry"})).unwrap_err();
+        assert_eq!(errors.len(), 3, "{:?}", errors);
+        assert!(errors
+            .iter()
+            .any(|e| e == "$: missing required property `tags`"));
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("$.stars: expected integer")));
+        assert!(errors.iter().any(|e| e.starts_with("$.mood:")));
+    }
+
+    #[test]
+    fn test_parses_yaml_and_json_in_prose() {
+        let schema = schema_for::<Review>();
+        let yaml = "```yaml\nstars: 2\nmood: Sad\ntags: [slow]\n```";
+        assert_eq!(
+            parse::<Review>(yaml, &schema).unwrap(),
+            Review {
+                stars: 2,
+                mood: Mood::Sad,
+                tags: vec!["slow".to_string()]
+            }
+        );
+        let prose = r#"Here you go: {"stars": 5, "mood": "Happy", "tags": ["fast"]} Enjoy!"#;
+        assert_eq!(parse::<Review>(prose, &schema).unwrap().stars, 5);
+    }
+
+    #[test]
+    fn test_parses_json_after_a_label() {
+        let schema = schema_for::<Review>();
+        let labeled = r#"Review: {"stars": 4, "mood": "Happy", "tags": []}"#;
+        assert_eq!(parse::<Review>(labeled, &schema).unwrap().stars, 4);
+    }
+
+    #[tokio::test]
+    async fn test_reasks_with_the_errors() {
+        let executor = ScriptedExecutor::new(|prompt| {
+            if prompt.contains("missing required property `tags`") {
+                r#"{"stars": 3, "mood": "Happy", "tags": []}"#.to_string()
+            } else {
+                r#"{"stars": 3, "mood": "Happy"}"#.to_string()
+            }
+        });
+        let step = Step::for_prompt_template(prompt!("Review: {{text}}"));
+        let parameters = Parameters::new_with_text("Decent");
+        let review: Review = step.run_typed(&parameters, &executor).await.unwrap();
+        assert_eq!(review.stars, 3);
+        assert_eq!(executor.calls(), 2);
+
+        let result = step
+            .run_typed_with_retries::<Review, _>(&Parameters::new_with_text("Bad"), &executor, 0)
+            .await;
+        assert!(matches!(
+            result,
+            Err(StructuredOutputError::Invalid { attempts: 1, .. })
+        ));
+    }
+}
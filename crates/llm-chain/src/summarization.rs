@@ -3,34 +3,71 @@
 //! This module contains the `TextSummarizer` struct, that provides an easy way to summarize text.
 
 use crate::{
-    chains::map_reduce::{self, MapReduceChainError},
+    chains::{
+        map_reduce::{self, MapReduceChainError},
+        refine::{self, RefineChainError},
+    },
     frame::FormatAndExecuteError,
     parameters, prompt,
     step::Step,
     traits,
 };
 
+/// The strategy a `TextSummarizer` uses to summarize texts that don't fit in the context window.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SummarizationStrategy {
+    /// Summarizes every chunk in parallel, then combines the summaries. Fast, but every chunk is summarized
+    /// without knowing about the others.
+    #[default]
+    MapReduce,
+    /// Summarizes the first chunk, then refines the summary with every following chunk. Slower, but the summary
+    /// stays coherent across chunks.
+    Refine,
+}
+
+enum SummarizerChain {
+    MapReduce(map_reduce::Chain),
+    Refine(refine::Chain),
+}
+
 /// A `TextSummarizer` takes a given text and summarizes it using an `Executor`.
 ///
-/// The summarizer is built on top of a `map_reduce::Chain`, which takes care of the summarization process.
+/// The summarizer is built on top of a `map_reduce::Chain` or a `refine::Chain`, depending on the
+/// `SummarizationStrategy`, which takes care of the summarization process.
 pub struct TextSummarizer {
-    chain: map_reduce::Chain,
+    chain: SummarizerChain,
 }
 
 impl Default for TextSummarizer {
     fn default() -> Self {
+        Self::new(SummarizationStrategy::default())
+    }
+}
+
+impl TextSummarizer {
+    /// Creates a summarizer using the given strategy.
+    pub fn new(strategy: SummarizationStrategy) -> Self {
         let map_prompt = Step::for_prompt_template(prompt!(
             "You are a text summarizer. You will be given a text and you will have to summarize it",
             "Text:\n\n{{text}}\n\nPlease write a summary of the text above. Respond only with the summary."
         ));
-        let reduce_prompt = Step::for_prompt_template(prompt!(
-            "You are a text summarizer. You will be given a text and you will have to summarize it",
-            "Text:\n\n{{text}}\n\nPlease write a combined summary of the segment summaries above. Respond only with the summary."
-        ));
-
-        TextSummarizer {
-            chain: map_reduce::Chain::new(map_prompt, reduce_prompt),
-        }
+        let chain = match strategy {
+            SummarizationStrategy::MapReduce => {
+                let reduce_prompt = Step::for_prompt_template(prompt!(
+                    "You are a text summarizer. You will be given a text and you will have to summarize it",
+                    "Text:\n\n{{text}}\n\nPlease write a combined summary of the segment summaries above. Respond only with the summary."
+                ));
+                SummarizerChain::MapReduce(map_reduce::Chain::new(map_prompt, reduce_prompt))
+            }
+            SummarizationStrategy::Refine => {
+                let refine_prompt = Step::for_prompt_template(prompt!(
+                    "You are a text summarizer. You will be given an existing summary and more text, and you will have to refine the summary",
+                    "Existing summary:\n\n{{existing_answer}}\n\nMore text:\n\n{{text}}\n\nPlease refine the existing summary with the text above. If the text isn't useful, repeat the existing summary. Respond only with the summary."
+                ));
+                SummarizerChain::Refine(refine::Chain::new(map_prompt, refine_prompt))
+            }
+        };
+        TextSummarizer { chain }
     }
 }
 
@@ -39,6 +76,8 @@ impl Default for TextSummarizer {
 pub enum TextSummarizerError {
     #[error("MapReduceChainError: {0}")]
     MapReduceChainError(#[from] MapReduceChainError),
+    #[error("RefineChainError: {0}")]
+    RefineChainError(#[from] RefineChainError),
     #[error("No output was produced")]
     NoOutput,
 }
@@ -55,17 +94,26 @@ impl TextSummarizer {
         let params = parameters! {
             "text" => text,
         };
-        let chain_output = self.chain.run(vec![params], parameters!(), exec).await?;
-        chain_output
-            .to_immediate()
-            .await
-            .map_err(|err| {
-                TextSummarizerError::MapReduceChainError(
-                    MapReduceChainError::FormatAndExecuteError(FormatAndExecuteError::Execute(err)),
-                )
-            })?
-            .primary_textual_output()
-            .ok_or(TextSummarizerError::NoOutput)
+        match &self.chain {
+            SummarizerChain::MapReduce(chain) => {
+                let chain_output = chain.run(vec![params], parameters!(), exec).await?;
+                chain_output
+                    .to_immediate()
+                    .await
+                    .map_err(|err| {
+                        TextSummarizerError::MapReduceChainError(
+                            MapReduceChainError::FormatAndExecuteError(
+                                FormatAndExecuteError::Execute(err),
+                            ),
+                        )
+                    })?
+                    .primary_textual_output()
+                    .ok_or(TextSummarizerError::NoOutput)
+            }
+            SummarizerChain::Refine(chain) => {
+                Ok(chain.run(vec![params], parameters!(), exec).await?.answer)
+            }
+        }
     }
 }
 
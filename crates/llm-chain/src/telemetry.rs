@@ -0,0 +1,481 @@
+//! Optional `tracing` instrumentation, enabled with the `tracing` cargo feature.
+//!
+//! With the feature enabled, every `Frame::format_and_execute` and `ToolCollection::invoke` runs inside a span.
+//! Executors, embeddings and vector stores are implemented outside of this crate, so their calls are traced by
+//! wrapping them in `Instrumented`, which implements the same trait as the value it wraps:
+//!
+//! ```ignore
+//! let exec = Instrumented::new(executor!()?).with_system("openai");
+//! ```
+//!
+//! The span attributes follow the OpenTelemetry semantic conventions for generative AI, e.x.
+//! `gen_ai.operation.name`, `gen_ai.request.model` and `gen_ai.usage.input_tokens`, and failed calls record
+//! `error.type`. The `otel.*` fields are understood by `tracing-opentelemetry`, so the spans can be exported to
+//! any OTLP collector as they are. The latency of a call is the duration of its span.
+use std::error::Error;
+use std::future::Future;
+
+use async_trait::async_trait;
+use tracing::{field::Empty, Instrument, Span};
+
+use crate::{
+    options::{Opt, OptDiscriminants, Options},
+    output::Output,
+    prompt::Prompt,
+    schema::Document,
+    tokens::{PromptTokensError, TokenCount, Tokenizer, TokenizerError},
+    traits::{Embeddings, Executor, ExecutorCreationError, ExecutorError, VectorStore},
+};
+
+/// Runs the future inside the span, recording the error on the span if it fails.
+pub(crate) async fn in_span<T, E, F>(span: Span, future: F) -> Result<T, E>
+where
+    F: Future<Output = Result<T, E>>,
+    E: Error,
+{
+    let result = future.instrument(span.clone()).await;
+    if let Err(err) = &result {
+        span.record("error.type", error_type(err).as_str());
+        span.record("otel.status_code", "ERROR");
+        span.record("otel.status_message", err.to_string().as_str());
+    }
+    result
+}
+
+/// Returns a low-cardinality name for the error, made up of its type and variant, e.x. `ExecutorError::InvalidOptions`.
+fn error_type<E: Error>(err: &E) -> String {
+    let type_name = std::any::type_name::<E>();
+    let type_name = type_name.split('<').next().unwrap_or(type_name);
+    let type_name = type_name.rsplit("::").next().unwrap_or(type_name);
+    let variant: String = format!("{:?}", err)
+        .chars()
+        .take_while(|c| c.is_alphanumeric() || *c == '_')
+        .collect();
+    if variant.is_empty() || variant == type_name {
+        type_name.to_string()
+    } else {
+        format!("{}::{}", type_name, variant)
+    }
+}
+
+pub(crate) fn frame_span(name: &str) -> Span {
+    tracing::info_span!(
+        "llm_chain.frame",
+        otel.name = %format!("frame {}", name),
+        llm_chain.step.name = %name,
+        "error.type" = Empty,
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+    )
+}
+
+pub(crate) fn tool_span(name: &str) -> Span {
+    tracing::info_span!(
+        "llm_chain.tool.invoke",
+        otel.name = %format!("execute_tool {}", name),
+        gen_ai.operation.name = "execute_tool",
+        gen_ai.tool.name = %name,
+        "error.type" = Empty,
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+    )
+}
+
+fn embeddings_span(system: Option<&str>, texts: usize) -> Span {
+    let span = tracing::info_span!(
+        "llm_chain.embeddings",
+        otel.name = "embeddings",
+        otel.kind = "client",
+        gen_ai.operation.name = "embeddings",
+        gen_ai.system = Empty,
+        llm_chain.embeddings.texts = texts,
+        "error.type" = Empty,
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+    );
+    if let Some(system) = system {
+        span.record("gen_ai.system", system);
+    }
+    span
+}
+
+/// Wraps an `Executor`, `Embeddings` or `VectorStore`, tracing every call made to it.
+#[derive(Clone, Debug)]
+pub struct Instrumented<T> {
+    inner: T,
+    system: Option<String>,
+}
+
+impl<T> Instrumented<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            system: None,
+        }
+    }
+
+    /// Sets the `gen_ai.system` attribute, e.x. `openai`, recorded on the spans of executors and embeddings.
+    pub fn with_system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<E: Executor> Instrumented<E> {
+    /// Records the operation, the model, the system and the prompt tokens of a request on its span.
+    fn record_request(&self, span: &Span, options: &Options, prompt: &Prompt) {
+        let operation = match prompt {
+            Prompt::Chat(_) => "chat",
+            Prompt::Text(_) => "text_completion",
+        };
+        let model = match options.get(OptDiscriminants::Model) {
+            Some(Opt::Model(model)) => Some(model.to_name()),
+            _ => None,
+        };
+        match &model {
+            Some(model) => span.record("otel.name", format!("{} {}", operation, model).as_str()),
+            None => span.record("otel.name", operation),
+        };
+        span.record("gen_ai.operation.name", operation);
+        if let Some(system) = &self.system {
+            span.record("gen_ai.system", system.as_str());
+        }
+        if let Some(model) = &model {
+            span.record("gen_ai.request.model", model.as_str());
+        }
+        if let Ok(count) = self.inner.tokens_used(options, prompt) {
+            span.record("gen_ai.usage.input_tokens", count.tokens_used());
+        }
+    }
+}
+
+#[async_trait]
+impl<E> Executor for Instrumented<E>
+where
+    E: Executor + Send + Sync,
+{
+    type StepTokenizer<'a>
+        = E::StepTokenizer<'a>
+    where
+        Self: 'a;
+
+    fn new_with_options(options: Options) -> Result<Self, ExecutorCreationError> {
+        E::new_with_options(options).map(Self::new)
+    }
+
+    /// Executes the prompt inside a span, recording the model and the number of tokens used.
+    ///
+    /// Output tokens are only counted for immediate outputs, a streamed output is still being produced when the
+    /// span ends.
+    async fn execute(&self, options: &Options, prompt: &Prompt) -> Result<Output, ExecutorError> {
+        let span = tracing::info_span!(
+            "llm_chain.executor.execute",
+            otel.name = Empty,
+            otel.kind = "client",
+            gen_ai.operation.name = Empty,
+            gen_ai.system = Empty,
+            gen_ai.request.model = Empty,
+            gen_ai.usage.input_tokens = Empty,
+            gen_ai.usage.output_tokens = Empty,
+            "error.type" = Empty,
+            otel.status_code = Empty,
+            otel.status_message = Empty,
+        );
+        self.record_request(&span, options, prompt);
+
+        let output = in_span(span.clone(), self.inner.execute(options, prompt)).await?;
+        if let Output::Immediate(immediate) = &output {
+            let tokens = immediate.primary_textual_output().and_then(|text| {
+                self.inner
+                    .get_tokenizer(options)
+                    .and_then(|tokenizer| tokenizer.tokenize_str(&text))
+                    .ok()
+            });
+            if let Some(tokens) = tokens {
+                span.record("gen_ai.usage.output_tokens", tokens.len());
+            }
+        }
+        Ok(output)
+    }
+
+    /// Forwards to the wrapped executor, so executors generating several answers in one request keep doing so.
+    async fn execute_n(
+        &self,
+        options: &Options,
+        prompt: &Prompt,
+        n: usize,
+    ) -> Result<Vec<Output>, ExecutorError> {
+        let span = tracing::info_span!(
+            "llm_chain.executor.execute_n",
+            otel.name = Empty,
+            otel.kind = "client",
+            gen_ai.operation.name = Empty,
+            gen_ai.system = Empty,
+            gen_ai.request.model = Empty,
+            gen_ai.request.choice.count = n,
+            gen_ai.usage.input_tokens = Empty,
+            "error.type" = Empty,
+            otel.status_code = Empty,
+            otel.status_message = Empty,
+        );
+        self.record_request(&span, options, prompt);
+        in_span(span, self.inner.execute_n(options, prompt, n)).await
+    }
+
+    fn tokens_used(
+        &self,
+        options: &Options,
+        prompt: &Prompt,
+    ) -> Result<TokenCount, PromptTokensError> {
+        self.inner.tokens_used(options, prompt)
+    }
+
+    fn max_tokens_allowed(&self, options: &Options) -> i32 {
+        self.inner.max_tokens_allowed(options)
+    }
+
+    fn answer_prefix(&self, prompt: &Prompt) -> Option<String> {
+        self.inner.answer_prefix(prompt)
+    }
+
+    fn get_tokenizer(&self, options: &Options) -> Result<Self::StepTokenizer<'_>, TokenizerError> {
+        self.inner.get_tokenizer(options)
+    }
+}
+
+#[async_trait]
+impl<E> Embeddings for Instrumented<E>
+where
+    E: Embeddings + Send + Sync,
+{
+    type Error = E::Error;
+
+    async fn embed_texts(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, Self::Error> {
+        let span = embeddings_span(self.system.as_deref(), texts.len());
+        in_span(span, self.inner.embed_texts(texts)).await
+    }
+
+    async fn embed_query(&self, query: String) -> Result<Vec<f32>, Self::Error> {
+        let span = embeddings_span(self.system.as_deref(), 1);
+        in_span(span, self.inner.embed_query(query)).await
+    }
+}
+
+#[async_trait]
+impl<V, E, M> VectorStore<E, M> for Instrumented<V>
+where
+    V: VectorStore<E, M> + Send + Sync,
+    E: Embeddings,
+    M: serde::Serialize + serde::de::DeserializeOwned + Send + Sync + 'static,
+{
+    type Error = V::Error;
+
+    async fn add_texts(&self, texts: Vec<String>) -> Result<Vec<String>, Self::Error> {
+        self.inner.add_texts(texts).await
+    }
+
+    async fn add_documents(&self, documents: Vec<Document<M>>) -> Result<Vec<String>, Self::Error> {
+        self.inner.add_documents(documents).await
+    }
+
+    async fn similarity_search(
+        &self,
+        query: String,
+        limit: u32,
+    ) -> Result<Vec<Document<M>>, Self::Error> {
+        let span = tracing::info_span!(
+            "llm_chain.vector_store.similarity_search",
+            otel.name = "similarity_search",
+            otel.kind = "client",
+            db.operation.name = "similarity_search",
+            llm_chain.vector_store.limit = limit,
+            llm_chain.vector_store.results = Empty,
+            "error.type" = Empty,
+            otel.status_code = Empty,
+            otel.status_message = Empty,
+        );
+        let documents = in_span(span.clone(), self.inner.similarity_search(query, limit)).await?;
+        span.record("llm_chain.vector_store.results", documents.len());
+        Ok(documents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use async_trait::async_trait;
+    use thiserror::Error;
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::Subscriber;
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::{registry::LookupSpan, Layer};
+
+    use super::Instrumented;
+    use crate::options::Options;
+    use crate::prompt::Data;
+    use crate::testing::ScriptedExecutor;
+    use crate::tools::{tools::BashTool, ToolCollection};
+    use crate::traits::{Embeddings, EmbeddingsError, Executor};
+
+    type ExportedSpan = (Id, &'static str, HashMap<String, String>);
+
+    /// An in-memory exporter, collecting the name and fields of every span.
+    #[derive(Clone, Default)]
+    struct SpanExporter(Arc<Mutex<Vec<ExportedSpan>>>);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.0.insert(field.name().to_string(), value.to_string());
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0
+                .insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanExporter {
+        fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, _: Context<'_, S>) {
+            let mut fields = HashMap::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            self.0
+                .lock()
+                .unwrap()
+                .push((id.clone(), attrs.metadata().name(), fields));
+        }
+
+        fn on_record(&self, id: &Id, values: &Record<'_>, _: Context<'_, S>) {
+            let mut spans = self.0.lock().unwrap();
+            if let Some((_, _, fields)) = spans.iter_mut().find(|(span, _, _)| span == id) {
+                values.record(&mut FieldVisitor(fields));
+            }
+        }
+    }
+
+    impl SpanExporter {
+        fn span(&self, name: &str) -> HashMap<String, String> {
+            let spans = self.0.lock().unwrap();
+            spans
+                .iter()
+                .find(|(_, span, _)| *span == name)
+                .map(|(_, _, fields)| fields.clone())
+                .unwrap_or_else(|| panic!("no span named {}", name))
+        }
+    }
+
+    #[derive(Debug, Error)]
+    #[error("embedding failed")]
+    struct FakeEmbeddingsError;
+
+    impl EmbeddingsError for FakeEmbeddingsError {}
+
+    struct FakeEmbeddings;
+
+    #[async_trait]
+    impl Embeddings for FakeEmbeddings {
+        type Error = FakeEmbeddingsError;
+
+        async fn embed_texts(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, Self::Error> {
+            Ok(texts.iter().map(|_| vec![0.0]).collect())
+        }
+
+        async fn embed_query(&self, _: String) -> Result<Vec<f32>, Self::Error> {
+            Err(FakeEmbeddingsError)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_traces_tool_invocations() {
+        let exporter = SpanExporter::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(exporter.clone()));
+
+        let tools = ToolCollection::<BashTool>::new();
+        assert!(tools.invoke("Missing", &"input".into()).await.is_err());
+
+        let span = exporter.span("llm_chain.tool.invoke");
+        assert_eq!(span["gen_ai.operation.name"], "execute_tool");
+        assert_eq!(span["gen_ai.tool.name"], "Missing");
+        assert_eq!(span["error.type"], "ToolUseError::ToolNotFound");
+        assert_eq!(span["otel.status_code"], "ERROR");
+    }
+
+    #[tokio::test]
+    async fn test_traces_executor_calls() {
+        let exporter = SpanExporter::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(exporter.clone()));
+
+        let executor = Instrumented::new(ScriptedExecutor::fallible(|prompt| {
+            if prompt == "fail" {
+                Err("the model is down".to_string())
+            } else {
+                Ok("an answer".to_string())
+            }
+        }))
+        .with_system("fake");
+        let options = Options::empty();
+        executor
+            .execute(options, &Data::Text("hello".to_string()))
+            .await
+            .unwrap();
+        assert!(executor
+            .execute_n(options, &Data::Text("fail".to_string()), 2)
+            .await
+            .is_err());
+
+        let span = exporter.span("llm_chain.executor.execute");
+        assert_eq!(span["otel.name"], "text_completion");
+        assert_eq!(span["gen_ai.system"], "fake");
+        assert_eq!(span["gen_ai.usage.input_tokens"], "5");
+        assert_eq!(span["gen_ai.usage.output_tokens"], "9");
+        assert!(!span.contains_key("error.type"));
+
+        let span = exporter.span("llm_chain.executor.execute_n");
+        assert_eq!(span["otel.name"], "text_completion");
+        assert_eq!(span["otel.kind"], "client");
+        assert_eq!(span["gen_ai.operation.name"], "text_completion");
+        assert_eq!(span["gen_ai.system"], "fake");
+        assert_eq!(span["gen_ai.request.choice.count"], "2");
+        assert_eq!(span["error.type"], "ExecutorError::InnerError");
+        assert_eq!(span["otel.status_code"], "ERROR");
+        assert!(span["otel.status_message"].contains("the model is down"));
+    }
+
+    #[tokio::test]
+    async fn test_traces_embeddings() {
+        let exporter = SpanExporter::default();
+        let _guard =
+            tracing::subscriber::set_default(tracing_subscriber::registry().with(exporter.clone()));
+
+        let embeddings = Instrumented::new(FakeEmbeddings).with_system("fake");
+        embeddings
+            .embed_texts(vec!["a".to_string(), "b".to_string()])
+            .await
+            .unwrap();
+
+        let span = exporter.span("llm_chain.embeddings");
+        assert_eq!(span["gen_ai.operation.name"], "embeddings");
+        assert_eq!(span["gen_ai.system"], "fake");
+        assert_eq!(span["llm_chain.embeddings.texts"], "2");
+        assert!(!span.contains_key("error.type"));
+
+        assert!(embeddings.embed_query("a".to_string()).await.is_err());
+        let spans = exporter.0.lock().unwrap();
+        let (_, _, failed) = spans.last().unwrap();
+        assert_eq!(failed["error.type"], "FakeEmbeddingsError");
+    }
+}
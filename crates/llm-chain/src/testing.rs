@@ -0,0 +1,227 @@
+//! Helpers shared by the unit tests of the crate.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use thiserror::Error;
+
+use crate::memory::{ConversationMemory, MemoryError};
+use crate::options::{Opt, OptDiscriminants, Options};
+use crate::output::{Output, StreamSegment};
+use crate::prompt::{ChatMessageCollection, Data, Prompt};
+use crate::schema::{Document, EmptyMetadata};
+use crate::tokens::{PromptTokensError, TokenCollection, TokenCount, Tokenizer, TokenizerError};
+use crate::traits::{
+    Embeddings, EmbeddingsError, Executor, ExecutorCreationError, ExecutorError, VectorStore,
+    VectorStoreError,
+};
+
+/// A tokenizer with one token per character.
+pub(crate) struct CharTokenizer;
+
+impl Tokenizer for CharTokenizer {
+    fn tokenize_str(&self, doc: &str) -> Result<TokenCollection, TokenizerError> {
+        Ok(doc.chars().map(|c| c as i32).collect::<Vec<_>>().into())
+    }
+
+    fn to_string(&self, tokens: TokenCollection) -> Result<String, TokenizerError> {
+        Ok(tokens
+            .as_i32()?
+            .into_iter()
+            .filter_map(|token| char::from_u32(token as u32))
+            .collect())
+    }
+}
+
+type Reply = Box<dyn Fn(&str) -> Result<String, String> + Send + Sync>;
+
+/// An executor answering every prompt with a function of its text, and counting how often it was called.
+///
+/// With the `Stream` option, the reply is streamed word by word.
+pub(crate) struct ScriptedExecutor {
+    reply: Reply,
+    max_tokens: i32,
+    calls: AtomicUsize,
+}
+
+impl ScriptedExecutor {
+    pub(crate) fn new<F: Fn(&str) -> String + Send + Sync + 'static>(reply: F) -> Self {
+        Self::fallible(move |prompt| Ok(reply(prompt)))
+    }
+
+    /// Creates an executor failing with the given message whenever `reply` returns an error.
+    pub(crate) fn fallible<F>(reply: F) -> Self
+    where
+        F: Fn(&str) -> Result<String, String> + Send + Sync + 'static,
+    {
+        ScriptedExecutor {
+            reply: Box::new(reply),
+            max_tokens: 4096,
+            calls: AtomicUsize::new(0),
+        }
+    }
+
+    /// Sets the size of the context window, in characters. Defaults to 4096.
+    pub(crate) fn with_max_tokens(mut self, max_tokens: i32) -> Self {
+        self.max_tokens = max_tokens;
+        self
+    }
+
+    pub(crate) fn calls(&self) -> usize {
+        self.calls.load(Ordering::SeqCst)
+    }
+}
+
+#[async_trait]
+impl Executor for ScriptedExecutor {
+    type StepTokenizer<'a> = CharTokenizer;
+
+    fn new_with_options(_: Options) -> Result<Self, ExecutorCreationError> {
+        Ok(ScriptedExecutor::new(|prompt| prompt.to_string()))
+    }
+
+    async fn execute(&self, options: &Options, prompt: &Prompt) -> Result<Output, ExecutorError> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let reply = (self.reply)(&prompt.to_text()).map_err(|message| {
+            ExecutorError::InnerError(Box::<dyn std::error::Error + Send + Sync>::from(message))
+        })?;
+        if let Some(Opt::Stream(true)) = options.get(OptDiscriminants::Stream) {
+            let words: Vec<_> = reply
+                .split_inclusive(' ')
+                .map(|word| StreamSegment::Content(word.to_string()))
+                .collect();
+            return Ok(Output::from_stream(futures::stream::iter(words)));
+        }
+        Ok(Output::new_immediate(Data::Text(reply)))
+    }
+
+    fn tokens_used(&self, _: &Options, prompt: &Prompt) -> Result<TokenCount, PromptTokensError> {
+        Ok(TokenCount::new(
+            self.max_tokens,
+            prompt.to_text().chars().count() as i32,
+        ))
+    }
+
+    fn answer_prefix(&self, _: &Prompt) -> Option<String> {
+        None
+    }
+
+    fn max_tokens_allowed(&self, _: &Options) -> i32 {
+        self.max_tokens
+    }
+
+    fn get_tokenizer(&self, _: &Options) -> Result<CharTokenizer, TokenizerError> {
+        Ok(CharTokenizer)
+    }
+}
+
+/// A memory sending the whole history, and recording the exchanges it's given as `input -> output`.
+#[derive(Clone, Default)]
+pub(crate) struct RecordingMemory(Arc<Mutex<Vec<String>>>);
+
+impl RecordingMemory {
+    pub(crate) fn exchanges(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+fn joined(messages: &ChatMessageCollection<String>) -> String {
+    messages
+        .iter()
+        .map(|message| message.body().as_str())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[async_trait]
+impl ConversationMemory for RecordingMemory {
+    async fn save_exchange(
+        &mut self,
+        input: &ChatMessageCollection<String>,
+        output: &ChatMessageCollection<String>,
+    ) -> Result<(), MemoryError> {
+        let exchange = format!("{} -> {}", joined(input), joined(output));
+        self.0.lock().unwrap().push(exchange);
+        Ok(())
+    }
+
+    fn context(
+        &self,
+        history: &ChatMessageCollection<String>,
+        _: &dyn Tokenizer,
+        _: usize,
+    ) -> Result<ChatMessageCollection<String>, TokenizerError> {
+        Ok(history.clone())
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("unreachable")]
+pub(crate) struct NoError;
+
+impl EmbeddingsError for NoError {}
+impl VectorStoreError for NoError {}
+
+pub(crate) struct NoEmbeddings;
+
+#[async_trait]
+impl Embeddings for NoEmbeddings {
+    type Error = NoError;
+
+    async fn embed_texts(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>, Self::Error> {
+        Ok(texts.iter().map(|_| vec![]).collect())
+    }
+
+    async fn embed_query(&self, _: String) -> Result<Vec<f32>, Self::Error> {
+        Ok(vec![])
+    }
+}
+
+/// A vector store ranking texts by the number of words they share with the query.
+#[derive(Default)]
+pub(crate) struct WordStore(Mutex<Vec<String>>);
+
+#[async_trait]
+impl VectorStore<NoEmbeddings> for WordStore {
+    type Error = NoError;
+
+    async fn add_texts(&self, texts: Vec<String>) -> Result<Vec<String>, Self::Error> {
+        self.0.lock().unwrap().extend(texts);
+        Ok(vec![])
+    }
+
+    async fn add_documents(
+        &self,
+        documents: Vec<Document<EmptyMetadata>>,
+    ) -> Result<Vec<String>, Self::Error> {
+        self.add_texts(documents.into_iter().map(|d| d.page_content).collect())
+            .await
+    }
+
+    async fn similarity_search(
+        &self,
+        query: String,
+        limit: u32,
+    ) -> Result<Vec<Document<EmptyMetadata>>, Self::Error> {
+        let shared = |text: &String| {
+            text.split_whitespace()
+                .filter(|word| query.split_whitespace().any(|q| q == *word))
+                .count()
+        };
+        let mut texts: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|text| shared(text) > 0)
+            .cloned()
+            .collect();
+        texts.sort_by_key(|text| std::cmp::Reverse(shared(text)));
+        Ok(texts
+            .into_iter()
+            .take(limit as usize)
+            .map(Document::new)
+            .collect())
+    }
+}
@@ -121,6 +121,11 @@ impl TokenCount {
         }
     }
 
+    /// Returns the number of tokens used.
+    pub fn tokens_used(&self) -> i32 {
+        self.tokens_used
+    }
+
     /// Returns the number of tokens that could be added to the context window.
     pub fn tokens_remaining(&self) -> i32 {
         self.max_tokens - self.tokens_used
@@ -44,12 +44,17 @@ where
         name: &str,
         input: &serde_yaml::Value,
     ) -> Result<serde_yaml::Value, ToolUseError<<T as Tool>::Error>> {
-        let tool = self
-            .tools
-            .iter()
-            .find(|t| t.matches(name))
-            .ok_or(ToolUseError::ToolNotFound)?;
-        tool.invoke(input.clone()).await.map_err(|e| e.into())
+        let output = async {
+            let tool = self
+                .tools
+                .iter()
+                .find(|t| t.matches(name))
+                .ok_or(ToolUseError::ToolNotFound)?;
+            tool.invoke(input.clone()).await.map_err(|e| e.into())
+        };
+        #[cfg(feature = "tracing")]
+        let output = crate::telemetry::in_span(crate::telemetry::tool_span(name), output);
+        output.await
     }
 
     pub fn get_tool_invocation(
@@ -86,6 +91,11 @@ where
         serde_yaml::to_string(&output).map_err(|e| e.into())
     }
 
+    /// Returns the names of the tools in the collection, in the order they were added.
+    pub fn tool_names(&self) -> Vec<String> {
+        self.tools.iter().map(|t| t.description().name).collect()
+    }
+
     /// Generate a YAML-formatted string describing the available tools.
     pub fn describe(&self) -> Result<String, ToolUseError<<T as Tool>::Error>> {
         let des: Vec<_> = self.tools.iter().map(|t| t.description()).collect();
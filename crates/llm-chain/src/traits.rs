@@ -66,6 +66,22 @@ pub trait Executor: Sized {
 
     async fn execute(&self, options: &Options, prompt: &Prompt) -> Result<Output, ExecutorError>;
 
+    /// Generates `n` independent answers to the same prompt, e.x. to vote on them.
+    ///
+    /// By default the prompt is executed `n` times concurrently. Executors for backends able to return several
+    /// choices from one request, like OpenAI, override this to make a single call.
+    async fn execute_n(
+        &self,
+        options: &Options,
+        prompt: &Prompt,
+        n: usize,
+    ) -> Result<Vec<Output>, ExecutorError>
+    where
+        Self: Sync,
+    {
+        futures::future::try_join_all((0..n).map(|_| self.execute(options, prompt))).await
+    }
+
     /// Calculates the number of tokens used by the step given a set of parameters.
     ///
     /// The step and the parameters together are used to form full prompt, which is then tokenized